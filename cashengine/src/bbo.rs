@@ -0,0 +1,106 @@
+use crate::shm_chunk;
+use crate::shm_chunk::ChunkPayload;
+
+// Best bid/offer as written into SHM chunks with `shm_chunk::KIND_BBO`.
+// Consumers get numeric prices without parsing the exchange JSON again.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Bbo {
+    // Global chunk index of the market, see `SharedMemoryReader::market`
+    pub symbol_index: u32,
+    // Padding, keeps the following fields 8-byte aligned
+    pub reserved: u32,
+    pub seq_id: u64,
+    pub bid: f64,
+    pub bid_size: f64,
+    pub ask: f64,
+    pub ask_size: f64,
+    // Exchange timestamp of the message in milliseconds since UNIX epoch
+    pub exchange_ts: u64,
+    // Exchange quote time in milliseconds since UNIX epoch
+    pub quote_time: u64,
+}
+
+static TS: &[u8] = b"\"ts\":";
+static TICK: &[u8] = b"\"tick\":";
+static SEQ_ID: &[u8] = b"\"seqId\":";
+static ASK: &[u8] = b"\"ask\":";
+static ASK_SIZE: &[u8] = b"\"askSize\":";
+static BID: &[u8] = b"\"bid\":";
+static BID_SIZE: &[u8] = b"\"bidSize\":";
+static QUOTE_TIME: &[u8] = b"\"quoteTime\":";
+static DATA: &[u8] = b"\"data\":";
+static UPDATE_ID: &[u8] = b"\"u\":";
+static BEST_BID: &[u8] = b"\"b\":";
+static BEST_BID_QTY: &[u8] = b"\"B\":";
+static BEST_ASK: &[u8] = b"\"a\":";
+static BEST_ASK_QTY: &[u8] = b"\"A\":";
+
+impl Bbo {
+    // Parses an HTX `market.$symbol.bbo` message without allocating:
+    // {"ch":"market.btcusdt.bbo","ts":1630000000000,"tick":{"seqId":1,"ask":1.1,"askSize":2.0,"bid":1.0,"bidSize":3.0,"quoteTime":1630000000000,"symbol":"btcusdt"}}
+    pub fn parse_htx(message: &[u8], symbol_index: u32) -> Option<Bbo> {
+        let tick_start = find(message, TICK)? + TICK.len();
+        let (head, tick) = message.split_at(tick_start);
+        Some(Bbo {
+            symbol_index,
+            reserved: 0,
+            seq_id: number_after(tick, SEQ_ID)?,
+            bid: number_after(tick, BID)?,
+            bid_size: number_after(tick, BID_SIZE)?,
+            ask: number_after(tick, ASK)?,
+            ask_size: number_after(tick, ASK_SIZE)?,
+            exchange_ts: number_after(head, TS)?,
+            quote_time: number_after(tick, QUOTE_TIME).unwrap_or(0),
+        })
+    }
+
+    // Parses a Binance `<symbol>@bookTicker` message of a combined stream, numbers are quoted:
+    // {"stream":"btcusdt@bookTicker","data":{"u":400900217,"s":"BTCUSDT","b":"25.35","B":"31.21","a":"25.36","A":"40.66"}}
+    // Spot book tickers carry no timestamps, both are 0.
+    pub fn parse_binance(message: &[u8], symbol_index: u32) -> Option<Bbo> {
+        let data = &message[find(message, DATA)? + DATA.len()..];
+        Some(Bbo {
+            symbol_index,
+            reserved: 0,
+            seq_id: number_after(data, UPDATE_ID)?,
+            bid: quoted_number_after(data, BEST_BID)?,
+            bid_size: quoted_number_after(data, BEST_BID_QTY)?,
+            ask: quoted_number_after(data, BEST_ASK)?,
+            ask_size: quoted_number_after(data, BEST_ASK_QTY)?,
+            exchange_ts: 0,
+            quote_time: 0,
+        })
+    }
+
+}
+
+// SAFETY: Bbo is repr(C) plain old data without padding.
+unsafe impl ChunkPayload for Bbo {
+    const KIND: u32 = shm_chunk::KIND_BBO;
+}
+
+pub(crate) fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+// Parses the JSON number following `key`, e.g. `"bid":` in `"bid":1.5,`
+pub(crate) fn number_after<T: std::str::FromStr>(json: &[u8], key: &[u8]) -> Option<T> {
+    let start = find(json, key)? + key.len();
+    let value = &json[start..];
+    let end = value
+        .iter()
+        .position(|&c| c == b',' || c == b'}' || c == b']')
+        .unwrap_or(value.len());
+    std::str::from_utf8(&value[..end]).ok()?.trim().parse().ok()
+}
+
+// Parses the JSON string holding a number following `key`, e.g. `"b":` in `"b":"1.5",`
+pub(crate) fn quoted_number_after<T: std::str::FromStr>(json: &[u8], key: &[u8]) -> Option<T> {
+    let start = find(json, key)? + key.len();
+    let value = json[start..].trim_ascii_start().strip_prefix(b"\"")?;
+    let end = value.iter().position(|&c| c == b'"')?;
+    std::str::from_utf8(&value[..end]).ok()?.parse().ok()
+}
+
+const _: () = assert!(size_of::<Bbo>() == 64);
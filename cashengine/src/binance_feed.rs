@@ -0,0 +1,346 @@
+use crate::bbo::find;
+use crate::channel::Channel;
+use crate::error::EngineError;
+use crate::feed_source::{FeedHealth, FeedMessage, FeedSource, MessageTimes};
+use crate::metrics::ConnectionMetrics;
+use crate::websocket::{CeWebSocket, ReconnectPolicy};
+use std::io;
+use std::os::fd::RawFd;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Binance allows 1024 streams per connection and 5 incoming messages per second
+pub const MAX_STREAMS_PER_CONNECTION: usize = 1024;
+// Streams per SUBSCRIBE request, sent `SUBSCRIBE_INTERVAL` apart
+const SUBSCRIBE_BATCH_SIZE: usize = 200;
+const SUBSCRIBE_INTERVAL: Duration = Duration::from_millis(250);
+// Book ticker messages are below 200 bytes, replies to requests can list all subscribed streams
+pub const BUFFER_SIZE: usize = 64 * 1024;
+
+static STREAM: &[u8] = b"\"stream\":\"";
+static ERROR: &[u8] = b"\"error\":";
+const BOOK_TICKER: &str = "bookTicker";
+// The bookTicker stream is written into the bbo channel
+static BBO: &[u8] = b"bbo";
+
+// Binance spot book tickers over a combined stream connection, e.g. wss://stream.binance.com:9443/stream
+pub struct BinanceBookTickerFeed {
+    id: usize,
+    url: String,
+    reconnect_policy: ReconnectPolicy,
+    websocket: Option<CeWebSocket>,
+    // Currently subscribed, for the requests replayed after reconnects
+    markets: Vec<String>,
+    // Id of the next request, replies carry it
+    next_request_id: usize,
+}
+
+impl BinanceBookTickerFeed {
+    // `url` is the stream base url without path, e.g. wss://stream.binance.com:9443
+    pub fn new(id: usize, url: &str, reconnect_policy: ReconnectPolicy) -> BinanceBookTickerFeed {
+        BinanceBookTickerFeed {
+            id,
+            url: format!("{}/stream", url),
+            reconnect_policy,
+            websocket: None,
+            markets: Vec::new(),
+            next_request_id: id * 1_000_000,
+        }
+    }
+
+    // Batches of {"method":"SUBSCRIBE","params":["btcusdt@bookTicker",...],"id":1} with `method` SUBSCRIBE or UNSUBSCRIBE
+    fn requests<M: AsRef<str>>(&mut self, method: &str, markets: &[M]) -> Vec<String> {
+        markets.chunks(SUBSCRIBE_BATCH_SIZE).map(|markets| {
+            let streams: Vec<String> = markets.iter().map(|market| format!("\"{}@{}\"", market.as_ref(), BOOK_TICKER)).collect();
+            self.next_request_id += 1;
+            format!("{{\"method\":\"{}\",\"params\":[{}],\"id\":{}}}", method, streams.join(","), self.next_request_id)
+        }).collect()
+    }
+
+    // The markets that still fit into the connection, the server rejects a subscription above
+    // `MAX_STREAMS_PER_CONNECTION` streams. The others are refused and never subscribed.
+    fn within_stream_limit<'m>(&self, markets: &'m [&'m str]) -> &'m [&'m str] {
+        let free = MAX_STREAMS_PER_CONNECTION.saturating_sub(self.markets.len());
+        if markets.len() > free {
+            tracing::error!("Binance feed {} refuses {} of {} markets, the server allows {} streams per connection: {:?}",
+                            self.id, markets.len() - free, markets.len(), MAX_STREAMS_PER_CONNECTION, &markets[free..]);
+        }
+        &markets[..markets.len().min(free)]
+    }
+
+    // Sends the requests paced like the initial subscription, the replayed subscription covers all markets
+    fn send_requests(&mut self, requests: Vec<String>) {
+        let markets = self.markets.clone();
+        let replayed = self.requests("SUBSCRIBE", &markets);
+        if let Some(websocket) = self.websocket.as_mut() {
+            websocket.send_paced(requests);
+            websocket.set_subscribe_requests(replayed);
+        }
+    }
+}
+
+impl FeedSource for BinanceBookTickerFeed {
+    fn name(&self) -> &str {
+        &self.url
+    }
+
+    fn connect(&mut self) -> Result<(), EngineError> {
+        let websocket = CeWebSocket::connect(&self.url, BUFFER_SIZE, self.reconnect_policy)
+            .map_err(|error| EngineError::WebSocket { url: self.url.clone(), error })?;
+        tracing::debug!("Connected to Binance websocket server with id {}", self.id);
+        self.websocket = Some(websocket);
+        Ok(())
+    }
+
+    // Only the bbo channel exists on Binance, it maps to the `<symbol>@bookTicker` streams
+    fn subscribe(&mut self, markets: &[&str], channels: &[Channel]) {
+        if channels.iter().any(|channel| *channel != Channel::Bbo) {
+            tracing::warn!("Binance feed {} serves the bbo channel only, ignoring {:?}", self.id, channels);
+        }
+        self.markets.clear();
+        let markets = self.within_stream_limit(markets);
+        self.markets = markets.iter().map(|market| market.to_string()).collect();
+        let requests = self.requests("SUBSCRIBE", markets);
+
+        tracing::info!("Subscribing to {} book tickers in {} requests on {}", markets.len(), requests.len(), self.url);
+        match self.websocket.as_mut() {
+            Some(websocket) => websocket.subscribe_all(requests, SUBSCRIBE_INTERVAL),
+            None => tracing::error!("Websocket {} is not connected, can't subscribe", self.url),
+        }
+    }
+
+    fn add_markets(&mut self, markets: &[&str], _channels: &[Channel]) {
+        let markets = self.within_stream_limit(markets);
+        if markets.is_empty() {
+            return;
+        }
+        tracing::info!("Subscribing to {} additional book tickers on {}", markets.len(), self.url);
+        self.markets.extend(markets.iter().map(|market| market.to_string()));
+        let requests = self.requests("SUBSCRIBE", markets);
+        self.send_requests(requests);
+    }
+
+    fn remove_markets(&mut self, markets: &[&str], _channels: &[Channel]) {
+        tracing::info!("Unsubscribing from {} book tickers on {}", markets.len(), self.url);
+        self.markets.retain(|market| !markets.contains(&market.as_str()));
+        let requests = self.requests("UNSUBSCRIBE", markets);
+        self.send_requests(requests);
+    }
+
+    fn next_message(&mut self) -> Option<FeedMessage<'_>> {
+        let id = self.id;
+        let (message, times) = self.websocket.as_mut()?.next_message();
+        Some(classify(id, message, times))
+    }
+
+    fn try_next_message(&mut self) -> Option<FeedMessage<'_>> {
+        let id = self.id;
+        let (message, times) = self.websocket.as_mut()?.try_next_message()?;
+        Some(classify(id, message, times))
+    }
+
+    fn send(&mut self, request: &str) {
+        if let Some(websocket) = self.websocket.as_mut() {
+            websocket.send_message(request);
+        }
+    }
+
+    fn set_nonblocking(&mut self) -> Result<(), EngineError> {
+        match self.websocket.as_mut() {
+            Some(websocket) => websocket.set_nonblocking().map_err(EngineError::EventLoop),
+            None => Err(EngineError::EventLoop(io::Error::new(io::ErrorKind::NotConnected, format!("{} is not connected", self.url)))),
+        }
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        self.websocket.as_ref().map(CeWebSocket::raw_fd)
+    }
+
+    fn reconnect_due(&self) -> Option<Instant> {
+        self.websocket.as_ref().and_then(CeWebSocket::reconnect_due)
+    }
+
+    fn requests_due(&self) -> Option<Instant> {
+        self.websocket.as_ref().and_then(CeWebSocket::requests_due)
+    }
+
+    fn connection_metrics(&self) -> Option<Arc<ConnectionMetrics>> {
+        self.websocket.as_ref().map(|websocket| websocket.metrics().clone())
+    }
+
+    fn health(&self) -> FeedHealth {
+        match &self.websocket {
+            Some(websocket) => FeedHealth {
+                connected: websocket.reconnect_due().is_none(),
+                reconnects: websocket.reconnects(),
+                messages: websocket.messages(),
+                max_message_size: websocket.max_size(),
+            },
+            None => FeedHealth::default(),
+        }
+    }
+}
+
+// Combined stream messages name their stream, e.g. {"stream":"btcusdt@bookTicker","data":{...}}.
+// Replies to requests such as {"result":null,"id":1} and errors are control messages.
+pub(crate) fn classify(id: usize, message: &[u8], times: MessageTimes) -> FeedMessage<'_> {
+    if let Some(start) = find(message, STREAM) {
+        let stream = &message[start + STREAM.len()..];
+        let stream = stream.iter().position(|&c| c == b'"').and_then(|end| std::str::from_utf8(&stream[..end]).ok());
+        if let Some((market, BOOK_TICKER)) = stream.and_then(|stream| stream.split_once('@')) {
+            return FeedMessage::Market { market, channel: BBO, message, times };
+        }
+    }
+    if find(message, ERROR).is_some() {
+        tracing::warn!("Websocket {} received an error: {}", id, String::from_utf8_lossy(message));
+    } else if !message.starts_with(b"{\"result\"") {
+        tracing::warn!("Websocket {} received a message without market: {}", id, String::from_utf8_lossy(message));
+    }
+    FeedMessage::Control { message, times }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bbo::Bbo;
+    use crate::config::{LatencyConfig, ReconnectConfig};
+    use crate::exchange::Exchange;
+    use crate::feed_handler::FeedHandler;
+    use crate::metrics::FeedLatency;
+    use crate::shm_block_writer::SharedMemoryWriter;
+    use crate::shm_chunk;
+    use crate::shm_file::{self, FileHeader};
+    use crate::shm_reader::SharedMemoryReader;
+    use serde_json::Value;
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+    use tungstenite::Message;
+
+    const BOOK_TICKER_MESSAGE: &str = r#"{"stream":"ethusdt@bookTicker","data":{"u":400900217,"s":"ETHUSDT","b":"2512.34000000","B":"31.21000000","a":"2512.35000000","A":"40.66000000"}}"#;
+
+    // Accepts one websocket connection. Per step it waits for the given number of requests and then sends
+    // the replies. Returns the url and the received requests once the client closed the connection.
+    fn serve(steps: Vec<(usize, Vec<&'static str>)>) -> (String, JoinHandle<Vec<Value>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            let mut requests = Vec::new();
+            let mut expected = 0;
+            for (count, replies) in steps {
+                expected += count;
+                while requests.len() < expected {
+                    if let Message::Text(request) = socket.read().unwrap() {
+                        requests.push(serde_json::from_str(request.as_str()).unwrap());
+                    }
+                }
+                for reply in replies {
+                    socket.send(Message::text(reply)).unwrap();
+                }
+            }
+            while socket.read().is_ok() {}
+            requests
+        });
+        (url, server)
+    }
+
+    fn markets(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("coin{}usdt", i)).collect()
+    }
+
+    fn streams(request: &Value) -> usize {
+        request["params"].as_array().unwrap().len()
+    }
+
+    #[test]
+    fn subscribes_in_batches_with_increasing_ids() {
+        let (url, server) = serve(vec![(3, vec![r#"{"result":null,"id":1}"#]), (2, vec![r#"{"result":null,"id":2}"#])]);
+        let markets = markets(450);
+        let markets: Vec<&str> = markets.iter().map(String::as_str).collect();
+        let mut feed = BinanceBookTickerFeed::new(1, &url, ReconnectPolicy::new(&ReconnectConfig::default()));
+        feed.connect().unwrap();
+        feed.subscribe(&markets, &[Channel::Bbo]);
+        assert!(matches!(feed.next_message(), Some(FeedMessage::Control { .. })));
+        feed.remove_markets(&markets[..250], &[Channel::Bbo]);
+        assert!(matches!(feed.next_message(), Some(FeedMessage::Control { .. })));
+        drop(feed);
+
+        let requests = server.join().unwrap();
+        let methods: Vec<&str> = requests.iter().map(|request| request["method"].as_str().unwrap()).collect();
+        assert_eq!(methods, ["SUBSCRIBE", "SUBSCRIBE", "SUBSCRIBE", "UNSUBSCRIBE", "UNSUBSCRIBE"]);
+        assert_eq!(requests.iter().map(streams).collect::<Vec<_>>(), [SUBSCRIBE_BATCH_SIZE, SUBSCRIBE_BATCH_SIZE, 50, SUBSCRIBE_BATCH_SIZE, 50]);
+        assert_eq!(requests[0]["params"][0], "coin0usdt@bookTicker");
+        assert_eq!(requests[3]["params"][0], "coin0usdt@bookTicker");
+        let ids: Vec<u64> = requests.iter().map(|request| request["id"].as_u64().unwrap()).collect();
+        assert!(ids.windows(2).all(|ids| ids[0] < ids[1]), "{:?}", ids);
+    }
+
+    #[test]
+    fn refuses_markets_above_the_stream_limit() {
+        let (url, server) = serve(vec![(6, vec![r#"{"result":null,"id":1}"#])]);
+        let markets = markets(MAX_STREAMS_PER_CONNECTION + 10);
+        let markets: Vec<&str> = markets.iter().map(String::as_str).collect();
+        let mut feed = BinanceBookTickerFeed::new(0, &url, ReconnectPolicy::new(&ReconnectConfig::default()));
+        feed.connect().unwrap();
+        feed.subscribe(&markets, &[Channel::Bbo]);
+        feed.add_markets(&["btcusdt"], &[Channel::Bbo]);
+        assert!(matches!(feed.next_message(), Some(FeedMessage::Control { .. })));
+        assert_eq!(feed.markets.len(), MAX_STREAMS_PER_CONNECTION);
+        drop(feed);
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.iter().map(streams).sum::<usize>(), MAX_STREAMS_PER_CONNECTION);
+        assert!(requests.iter().all(|request| request["method"] == "SUBSCRIBE"));
+    }
+
+    #[test]
+    fn classifies_streams_replies_and_errors() {
+        let times = MessageTimes::default();
+        match classify(0, BOOK_TICKER_MESSAGE.as_bytes(), times) {
+            FeedMessage::Market { market, channel, .. } => {
+                assert_eq!(market, "ethusdt");
+                assert_eq!(channel, BBO);
+            }
+            FeedMessage::Control { .. } => panic!("book ticker classified as control message"),
+        }
+        for message in [
+            r#"{"result":null,"id":1}"#,
+            r#"{"error":{"code":2,"msg":"Invalid request: unknown variant"},"id":2}"#,
+            r#"{"stream":"ethusdt@depth","data":{}}"#,
+        ] {
+            assert!(matches!(classify(0, message.as_bytes(), times), FeedMessage::Control { .. }), "{}", message);
+        }
+    }
+
+    #[test]
+    fn writes_book_tickers_into_shm() {
+        let (url, server) = serve(vec![(1, vec![r#"{"result":null,"id":1}"#, BOOK_TICKER_MESSAGE])]);
+        let path = std::env::temp_dir().join(format!("cashengine-binance-feed-{}.mmap", std::process::id()));
+        let file = shm_file::create(path.to_str().unwrap(), &FileHeader::new(shm_chunk::chunk_size_for::<Bbo>(), 1, 2)).unwrap();
+        let writer = SharedMemoryWriter::create(&file, 0).unwrap();
+        let mut feed = BinanceBookTickerFeed::new(0, &url, ReconnectPolicy::new(&ReconnectConfig::default()));
+        feed.connect().unwrap();
+        let markets = ["btcusdt", "ethusdt"];
+        feed.subscribe(&markets, &[Channel::Bbo]);
+        let channels = [Channel::Bbo];
+        let mut feed_handler = FeedHandler::new(0, Exchange::Binance, &channels, vec![writer], &markets, 0, None);
+        let mut latency = FeedLatency::new(0, &LatencyConfig::default());
+        let mut requests = Vec::new();
+        for _ in 0..2 {
+            let message = feed.next_message().unwrap();
+            feed_handler.on_message(message, &mut requests, &mut latency);
+        }
+        drop(feed);
+        server.join().unwrap();
+
+        let mut reader = SharedMemoryReader::attach(&file).unwrap();
+        assert!(reader.read_chunk(0).is_none());
+        let chunk = reader.read_chunk(1).unwrap();
+        let bbo = *chunk.bbo().unwrap();
+        assert_eq!(chunk.header().market_index, 1);
+        assert_eq!((bbo.symbol_index, bbo.seq_id), (1, 400900217));
+        assert_eq!((bbo.bid, bbo.bid_size, bbo.ask, bbo.ask_size), (2512.34, 31.21, 2512.35, 40.66));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,168 @@
+use crate::decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+pub const PATH: &str = "/api/v3/exchangeInfo";
+
+static PRICE_FILTER: &str = "PRICE_FILTER";
+static LOT_SIZE: &str = "LOT_SIZE";
+static NOTIONAL: &str = "NOTIONAL";
+static MIN_NOTIONAL: &str = "MIN_NOTIONAL";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BinanceSymbol {
+    pub symbol: Option<String>,                  // e.g. BTCUSDT
+    pub status: Option<String>,                  // PRE_TRADING, TRADING, POST_TRADING, END_OF_DAY, HALT, AUCTION_MATCH, BREAK
+    pub base_asset: Option<String>,
+    pub base_asset_precision: Option<u32>,
+    pub quote_asset: Option<String>,
+    pub quote_asset_precision: Option<u32>,
+    pub is_spot_trading_allowed: Option<bool>,
+    pub is_margin_trading_allowed: Option<bool>,
+    #[serde(default)]
+    pub filters: Vec<BinanceSymbolFilter>,
+}
+
+// Only the filters defining the price and quantity increments and the minimum order value are kept
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BinanceSymbolFilter {
+    pub filter_type: String,
+    pub tick_size: Option<String>,  // PRICE_FILTER
+    pub step_size: Option<String>,  // LOT_SIZE
+    pub min_qty: Option<String>,  // LOT_SIZE
+    pub max_qty: Option<String>,  // LOT_SIZE
+    pub min_notional: Option<String>,  // NOTIONAL, MIN_NOTIONAL on older symbols
+    pub max_notional: Option<String>,  // NOTIONAL
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BinanceSymbols {
+    pub timezone: Option<String>,
+    pub server_time: Option<u64>,
+    #[serde(default)]
+    pub symbols: Vec<BinanceSymbol>,
+    // Returned instead of the data when the request fails, e.g. {"code":-1121,"msg":"Invalid symbol."}
+    pub code: Option<i64>,
+    pub msg: Option<String>,
+}
+
+impl BinanceSymbol {
+    pub fn tick_size(&self) -> Option<&str> {
+        self.filter(PRICE_FILTER).and_then(|filter| filter.tick_size.as_deref())
+    }
+
+    pub fn step_size(&self) -> Option<&str> {
+        self.filter(LOT_SIZE).and_then(|filter| filter.step_size.as_deref())
+    }
+
+    pub fn min_qty(&self) -> Option<&str> {
+        self.filter(LOT_SIZE).and_then(|filter| filter.min_qty.as_deref())
+    }
+
+    pub fn max_qty(&self) -> Option<&str> {
+        self.filter(LOT_SIZE).and_then(|filter| filter.max_qty.as_deref())
+    }
+
+    pub fn min_notional(&self) -> Option<&str> {
+        self.filter(NOTIONAL).or_else(|| self.filter(MIN_NOTIONAL)).and_then(|filter| filter.min_notional.as_deref())
+    }
+
+    pub fn max_notional(&self) -> Option<&str> {
+        self.filter(NOTIONAL).and_then(|filter| filter.max_notional.as_deref())
+    }
+
+    // Number of decimals of the tick size, e.g. 2 for "0.01000000"
+    pub fn price_precision(&self) -> Option<i32> {
+        self.tick_size().and_then(decimals)
+    }
+
+    pub fn amount_precision(&self) -> Option<i32> {
+        self.step_size().and_then(decimals)
+    }
+
+    fn filter(&self, filter_type: &str) -> Option<&BinanceSymbolFilter> {
+        self.filters.iter().find(|filter| filter.filter_type == filter_type)
+    }
+}
+
+// Decimals of an increment such as "0.00100000" -> 3 or "1.00000000" -> 0
+fn decimals(increment: &str) -> Option<i32> {
+    let increment = increment.parse::<Decimal>().ok().filter(Decimal::is_positive)?;
+    Some(increment.normalized().scale() as i32)
+}
+
+impl BinanceSymbols {
+    fn filter<F>(&self, predicate: F) -> Self
+    where
+        F: Fn(&BinanceSymbol) -> bool,
+    {
+        Self {
+            timezone: self.timezone.clone(),
+            server_time: self.server_time,
+            symbols: self.symbols.iter().filter(|s| predicate(s)).cloned().collect(),
+            code: self.code,
+            msg: self.msg.clone(),
+        }
+    }
+
+    pub fn with_trading_symbols(&self) -> Self {
+        self.filter(|s| s.status.as_deref() == Some("TRADING"))
+    }
+
+    pub fn with_spot_trading_allowed(&self) -> Self {
+        self.filter(|s| s.is_spot_trading_allowed == Some(true))
+    }
+
+    // Keeps symbols quoted in one of `quote_assets`, e.g. ["USDT", "FDUSD"]
+    pub fn with_quote_assets(&self, quote_assets: &[String]) -> Self {
+        self.filter(|s| s.quote_asset.as_ref().is_some_and(|quote| quote_assets.iter().any(|q| q.eq_ignore_ascii_case(quote))))
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn get_symbols(&self) -> Vec<&BinanceSymbol> {
+        self.symbols.iter().collect()
+    }
+
+    pub fn get_error(&self) -> Result<(), String> {
+        match (&self.code, &self.msg) {
+            (None, None) => Ok(()),
+            (Some(code), Some(msg)) => Err(format!("{}: {}", code, msg)),
+            (Some(code), None) => Err(code.to_string()),
+            (None, Some(msg)) => Err(msg.clone()),
+        }
+    }
+
+    pub fn log_compact(&self) {
+        tracing::info!("Timezone: {}", self.timezone.as_deref().unwrap_or("N/A"));
+        tracing::info!("Server Time: {}", self.server_time.map_or("N/A".to_string(), |t| t.to_string()));
+        if let Err(err) = self.get_error() {
+            tracing::error!("Error: {}", err);
+        }
+        tracing::debug!("\nSymbols:");
+        for (index, symbol) in self.symbols.iter().enumerate() {
+            tracing::debug!("{}", CompactSymbolPrinter(index + 1, symbol));
+        }
+        tracing::debug!("Total Symbols: {}", self.symbols.len());
+    }
+}
+
+struct CompactSymbolPrinter<'a>(usize, &'a BinanceSymbol);
+
+impl<'a> fmt::Display for CompactSymbolPrinter<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (index, symbol) = (self.0, self.1);
+        write!(f, "{}. Symbol: {}", index, symbol.symbol.as_deref().unwrap_or("N/A"))?;
+        write!(f, ", Base: {}", symbol.base_asset.as_deref().unwrap_or("N/A"))?;
+        write!(f, ", Quote: {}", symbol.quote_asset.as_deref().unwrap_or("N/A"))?;
+        write!(f, ", Status: {}", symbol.status.as_deref().unwrap_or("N/A"))?;
+        write!(f, ", Tick Size: {}", symbol.tick_size().unwrap_or("N/A"))?;
+        write!(f, ", Step Size: {}", symbol.step_size().unwrap_or("N/A"))?;
+        Ok(())
+    }
+}
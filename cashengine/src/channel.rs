@@ -0,0 +1,195 @@
+use crate::bbo::Bbo;
+use crate::exchange::Exchange;
+use crate::market_data::{Depth, Kline, MarketDetail, TradeBatch};
+use crate::order_book::MBP_LEVELS;
+use crate::shm_block_writer::SharedMemoryWriter;
+use crate::shm_chunk;
+use serde::Deserialize;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+static MARKET_DOT: &[u8] = b"market.";
+
+pub const KLINE_PERIODS: &[&str] = &["1min", "5min", "15min", "30min", "60min", "4hour", "1day", "1mon", "1week", "1year"];
+
+// HTX websocket market channel, subscribed as `market.$symbol.<channel>`.
+// Each channel is written into its own SHM file.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Channel {
+    Bbo,
+    TradeDetail,
+    DepthStep0,
+    Kline(String),
+    Detail,
+    // Incremental order book updates with the number of levels, maintained by `order_book::OrderBook`
+    Mbp(usize),
+}
+
+impl Channel {
+    pub fn topic(&self, symbol: &str) -> String {
+        format!("market.{}.{}", symbol, self)
+    }
+
+    // The bbo channel keeps the configured chunk size, the typed channels need a chunk per message type
+    pub fn chunk_size(&self, bbo_chunk_size: usize) -> usize {
+        match self {
+            Channel::Bbo => bbo_chunk_size,
+            Channel::TradeDetail => shm_chunk::chunk_size_for::<TradeBatch>(),
+            Channel::DepthStep0 => shm_chunk::chunk_size_for::<Depth>(),
+            Channel::Kline(_) => shm_chunk::chunk_size_for::<Kline>(),
+            Channel::Detail => shm_chunk::chunk_size_for::<MarketDetail>(),
+            Channel::Mbp(_) => shm_chunk::chunk_size_for::<Depth>(),
+        }
+    }
+
+    // Upper bound of the inflated JSON, used to size the websocket buffer
+    pub fn max_raw_message_size(&self) -> usize {
+        match self {
+            Channel::DepthStep0 | Channel::Mbp(_) => 32 * 1024,
+            Channel::TradeDetail => 8 * 1024,
+            _ => 1024,
+        }
+    }
+
+    // The bbo channel is written into `base_path`, other channels next to it,
+    // e.g. /tmp/ticks.mmap -> /tmp/ticks.kline-1min.mmap
+    pub fn shm_file_path(&self, base_path: &str) -> String {
+        if *self == Channel::Bbo {
+            return base_path.to_string();
+        }
+        let region = self.to_string().replace('.', "-");
+        let path = Path::new(base_path);
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(extension) => path.with_extension(format!("{}.{}", region, extension)),
+            None => path.with_extension(region),
+        }
+        .to_string_lossy()
+        .into_owned()
+    }
+
+    // Parses the message of `exchange` into this channel's type and writes it into the chunk.
+    // Returns false if the message could not be parsed. Binance feeds only serve the bbo channel.
+    // Mbp updates need the state of the book and are written by `order_book::OrderBooks` instead.
+    pub(crate) fn write(&self, exchange: Exchange, shm_writer: &mut SharedMemoryWriter, chunk_index: usize, symbol_index: u32, message: &[u8]) -> bool {
+        match self {
+            Channel::Bbo => match exchange {
+                Exchange::Htx => Bbo::parse_htx(message, symbol_index),
+                Exchange::Binance => Bbo::parse_binance(message, symbol_index),
+            }.map(|m| shm_writer.write_typed(chunk_index, &m)),
+            Channel::TradeDetail => TradeBatch::parse_htx(message, symbol_index).map(|m| shm_writer.write_typed(chunk_index, &m)),
+            Channel::DepthStep0 => Depth::parse_htx(message, symbol_index).map(|m| shm_writer.write_typed(chunk_index, &m)),
+            Channel::Kline(_) => Kline::parse_htx(message, symbol_index).map(|m| shm_writer.write_typed(chunk_index, &m)),
+            Channel::Detail => MarketDetail::parse_htx(message, symbol_index).map(|m| shm_writer.write_typed(chunk_index, &m)),
+            Channel::Mbp(_) => None,
+        }
+        .is_some()
+    }
+}
+
+// Splits the `ch` of an HTX market message into symbol and channel,
+// e.g. "market.btcusdt.kline.1min" into ("btcusdt", "kline.1min")
+pub(crate) fn split_topic(message: &[u8]) -> Option<(&str, &[u8])> {
+    let start = message.windows(MARKET_DOT.len()).position(|window| window == MARKET_DOT)? + MARKET_DOT.len();
+    let topic = &message[start..];
+    let symbol_end = topic.iter().position(|&c| c == b'.')?;
+    let channel = &topic[symbol_end + 1..];
+    let channel_end = channel.iter().position(|&c| c == b'"')?;
+    let symbol = std::str::from_utf8(&topic[..symbol_end]).ok()?;
+    Some((symbol, &channel[..channel_end]))
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Channel::Bbo => write!(f, "bbo"),
+            Channel::TradeDetail => write!(f, "trade.detail"),
+            Channel::DepthStep0 => write!(f, "depth.step0"),
+            Channel::Kline(period) => write!(f, "kline.{}", period),
+            Channel::Detail => write!(f, "detail"),
+            Channel::Mbp(levels) => write!(f, "mbp.{}", levels),
+        }
+    }
+}
+
+impl FromStr for Channel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bbo" => Ok(Channel::Bbo),
+            "trade.detail" => Ok(Channel::TradeDetail),
+            "depth.step0" => Ok(Channel::DepthStep0),
+            "detail" => Ok(Channel::Detail),
+            _ => {
+                if let Some(period) = s.strip_prefix("kline.") {
+                    if KLINE_PERIODS.contains(&period) {
+                        return Ok(Channel::Kline(period.to_string()));
+                    }
+                    return Err(format!("unknown kline period '{}', expected one of {:?}", period, KLINE_PERIODS));
+                }
+                if let Some(levels) = s.strip_prefix("mbp.") {
+                    return match levels.parse() {
+                        Ok(levels) if MBP_LEVELS.contains(&levels) => Ok(Channel::Mbp(levels)),
+                        _ => Err(format!("unsupported mbp levels '{}', expected one of {:?}", levels, MBP_LEVELS)),
+                    };
+                }
+                Err(format!(
+                    "unknown channel '{}', expected bbo, trade.detail, depth.step0, kline.$period, detail or mbp.$levels", s))
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for Channel {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_displays_channels() {
+        for (name, channel) in [
+            ("bbo", Channel::Bbo),
+            ("trade.detail", Channel::TradeDetail),
+            ("depth.step0", Channel::DepthStep0),
+            ("kline.1min", Channel::Kline("1min".to_string())),
+            ("kline.1year", Channel::Kline("1year".to_string())),
+            ("detail", Channel::Detail),
+            ("mbp.150", Channel::Mbp(150)),
+        ] {
+            assert_eq!(name.parse::<Channel>().unwrap(), channel);
+            assert_eq!(channel.to_string(), name);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_channels() {
+        for name in ["", "ticker", "kline.2min", "kline.", "mbp.400", "mbp.x", "depth.step1", "BBO"] {
+            assert!(name.parse::<Channel>().is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn splits_topics() {
+        assert_eq!(split_topic(br#"{"ch":"market.btcusdt.kline.1min","ts":1}"#), Some(("btcusdt", &b"kline.1min"[..])));
+        assert_eq!(split_topic(br#"{"ch":"market.ethbtc.bbo","ts":1}"#), Some(("ethbtc", &b"bbo"[..])));
+        assert_eq!(split_topic(br#"{"ch":"market.btcusdt.mbp.5","ts":1}"#), Some(("btcusdt", &b"mbp.5"[..])));
+        assert_eq!(split_topic(br#"{"ping":1630998026649}"#), None);
+        assert_eq!(split_topic(br#"{"ch":"market.btcusdt"}"#), None);
+    }
+
+    #[test]
+    fn places_channels_next_to_the_bbo_file() {
+        assert_eq!(Channel::Bbo.shm_file_path("/tmp/ticks.mmap"), "/tmp/ticks.mmap");
+        assert_eq!(Channel::Kline("1min".to_string()).shm_file_path("/tmp/ticks.mmap"), "/tmp/ticks.kline-1min.mmap");
+        assert_eq!(Channel::TradeDetail.shm_file_path("/tmp/ticks"), "/tmp/ticks.trade-detail");
+    }
+}
@@ -2,7 +2,69 @@ use flate2::read::MultiGzDecoder;
 use std::io;
 use std::io::Read;
 
-pub fn gz_inflate_to_buffer(bytes: &Vec<u8>, buffer: &mut [u8]) -> io::Result<usize> {
-    let mut gz = MultiGzDecoder::new(&bytes[..]);
-    gz.read(buffer)
-}
\ No newline at end of file
+// Inflates until the buffer is full or the stream ends, a single read may return only part of a large message.
+// A message larger than the buffer is an error rather than a truncated message.
+pub fn gz_inflate_to_buffer(bytes: &[u8], buffer: &mut [u8]) -> io::Result<usize> {
+    let mut gz = MultiGzDecoder::new(bytes);
+    let mut len = 0;
+    while len < buffer.len() {
+        match gz.read(&mut buffer[len..]) {
+            Ok(0) => return Ok(len),
+            Ok(read) => len += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    // The buffer is full, the message fits only if the stream ends here
+    let mut next = [0u8; 1];
+    loop {
+        match gz.read(&mut next) {
+            Ok(0) => return Ok(len),
+            Ok(_) => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                               format!("inflated message exceeds the buffer of {} bytes", buffer.len()))),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn gzip(message: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(message).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn inflates_messages_that_fit() {
+        let message = br#"{"ch":"market.btcusdt.bbo","ts":1}"#;
+        let mut buffer = [0u8; 64];
+        let len = gz_inflate_to_buffer(&gzip(message), &mut buffer).unwrap();
+        assert_eq!(&buffer[..len], message);
+
+        let mut exact = vec![0u8; message.len()];
+        assert_eq!(gz_inflate_to_buffer(&gzip(message), &mut exact).unwrap(), message.len());
+        assert_eq!(exact, message);
+    }
+
+    #[test]
+    fn rejects_messages_larger_than_the_buffer() {
+        let message = vec![b'x'; 100];
+        let mut buffer = [0u8; 99];
+        let error = gz_inflate_to_buffer(&gzip(&message), &mut buffer).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_corrupt_streams() {
+        let mut compressed = gzip(b"{\"ping\":1}");
+        compressed.truncate(compressed.len() / 2);
+        assert!(gz_inflate_to_buffer(&compressed, &mut [0u8; 64]).is_err());
+    }
+}
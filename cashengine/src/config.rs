@@ -0,0 +1,746 @@
+use crate::binance_feed;
+use crate::binance_symbol::BinanceSymbols;
+use crate::channel::Channel;
+use crate::exchange::Exchange;
+use crate::htx_state::HtxState;
+use crate::htx_symbol::HtxSymbols;
+use crate::shm_chunk;
+use serde::Deserialize;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+pub const ENV_PREFIX: &str = "CASHENGINE_";
+
+// Smallest chunk that still fits the seqlock version and chunk header plus a payload
+const MIN_CHUNK_SIZE: usize = 128;
+
+// Highest SCHED_FIFO priority on Linux
+const MAX_REALTIME_PRIORITY: u32 = 99;
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct EngineConfig {
+    // Exchanges to connect to, e.g. ["htx", "binance"]. The HTX settings are the top level keys.
+    pub exchanges: Vec<Exchange>,
+    pub websocket_url: String,
+    pub rest_url: String,
+    // TODO: On Linux use tmpfs shared memory: /dev/shm/ticks.shm;
+    pub shm_file_path: String,
+    pub markets_per_websocket: usize,
+    // Websocket connections multiplexed by one feed thread, of all exchanges. With 1 every connection has its
+    // own thread blocking on reads, with more the threads wait for the connections in an epoll event loop.
+    pub connections_per_thread: usize,
+    pub chunk_size: usize,
+    pub log_level: String,
+    // HTX channels subscribed for every market, e.g. ["bbo", "trade.detail", "kline.1min"]
+    pub channels: Vec<Channel>,
+    pub symbol_filters: SymbolFilters,
+    pub rest: RestConfig,
+    pub reconnect: ReconnectConfig,
+    pub binance: BinanceConfig,
+    pub refresh: RefreshConfig,
+    pub recording: RecordingConfig,
+    pub replay: ReplayConfig,
+    pub placement: PlacementConfig,
+    pub latency: LatencyConfig,
+    pub metrics: MetricsConfig,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SymbolFilters {
+    pub online: bool,
+    pub trade_enabled: bool,
+    pub cancel_enabled: bool,
+    pub visible: bool,
+    pub listed: bool,
+    pub country_enabled: bool,
+}
+
+// Requests to the HTX and Binance REST APIs
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RestConfig {
+    pub connect_timeout_ms: u64,
+    // Until the whole response body is read
+    pub read_timeout_ms: u64,
+    // Retries after timeouts, connection failures, 5xx and 429 responses
+    pub max_retries: u32,
+    // Doubled after every retry up to max_backoff_ms. A Retry-After header waits at least as long as requested.
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    // A Retry-After beyond this gives up instead of waiting, retrying earlier risks an IP ban
+    pub max_retry_after_ms: u64,
+    // Token bucket per host, refilled with requests_per_second up to burst requests
+    pub requests_per_second: f64,
+    pub burst: u32,
+}
+
+// Reconnects of the websocket connections after disconnects and failed handshakes
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReconnectConfig {
+    // Multiplied by multiplier after every failed reconnect up to max_backoff_ms
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub multiplier: u32,
+    // Jitter in percent of the current backoff, applied in both directions
+    pub jitter_percent: u32,
+    // A session that stayed connected at least this long resets the backoff
+    pub stable_after_secs: u64,
+}
+
+// Binance spot book tickers, written into their own SHM file with the same layout as the HTX bbo file
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct BinanceConfig {
+    // Stream base url, the feed connects to <websocket_url>/stream
+    pub websocket_url: String,
+    pub rest_url: String,
+    pub shm_file_path: String,
+    // At most `binance_feed::MAX_STREAMS_PER_CONNECTION`
+    pub markets_per_websocket: usize,
+    pub symbol_filters: BinanceSymbolFilters,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct BinanceSymbolFilters {
+    pub trading: bool,
+    pub spot_trading_allowed: bool,
+    // Keeps symbols quoted in one of these assets, e.g. ["USDT"]. Empty keeps all.
+    pub quote_assets: Vec<String>,
+}
+
+// Re-polls the HTX reference data while running and resubscribes listings and delistings, see `reference_data`
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RefreshConfig {
+    pub enabled: bool,
+    pub interval_secs: u64,
+    // Chunks reserved for markets listed while running. Their feeds start with the engine, so they may need extra cores.
+    pub spare_markets: usize,
+}
+
+// Records every inflated websocket message into rotating gzip files, see `recorder`
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordingConfig {
+    pub enabled: bool,
+    pub directory: String,
+    // Uncompressed message bytes per file before a new file is started
+    pub max_file_bytes: u64,
+}
+
+// Replays recorded feeds instead of connecting to the exchange, see `replay`
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplayConfig {
+    pub enabled: bool,
+    // A recording file or a directory of them, replayed in recording order
+    pub path: String,
+    // 1.0 keeps the original timing, 10.0 replays 10x faster, 0.0 as fast as possible
+    pub speed: f64,
+}
+
+// Cores and scheduling of the pinned feed and reader threads, see `placement`
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlacementConfig {
+    pub mode: PlacementMode,
+    // CPU ids of the feed threads in thread order, and of the reader thread. Empty and unset are placed automatically.
+    pub feed_cores: Vec<usize>,
+    pub reader_core: Option<usize>,
+    // Automatic placement only uses the CPUs in /sys/devices/system/cpu/isolated (isolcpus=)
+    pub isolated_cpus: bool,
+    // Automatic placement only uses the CPUs of this NUMA node
+    pub numa_node: Option<usize>,
+    // SCHED_FIFO priority of the pinned threads from 1 to 99, 0 keeps the default scheduler
+    pub realtime_priority: u32,
+}
+
+// Latency histograms of the feed and reader threads, see `metrics::LatencyTracker`
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LatencyConfig {
+    // Reported percentiles, e.g. [50.0, 99.9], the max is always reported
+    pub percentiles: Vec<f64>,
+    pub report_interval_secs: u64,
+    // Reports cover the values of this rolling window
+    pub window_secs: u64,
+    // Reports cover all values since the previous report instead of the window
+    pub reset_on_report: bool,
+}
+
+// Prometheus endpoint served by its own thread, see `exporter`
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    // e.g. "0.0.0.0:9184" to be scraped from other hosts
+    pub listen_address: String,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum PlacementMode {
+    // Threads that can't be placed as configured share cores or run unpinned, with a warning
+    BestEffort,
+    // The engine doesn't start unless every thread is placed as configured
+    Strict,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io { path: PathBuf, error: std::io::Error },
+    Parse { path: PathBuf, message: String },
+    UnsupportedFormat(PathBuf),
+    InvalidEnv { name: String, value: String, message: String },
+    Invalid { field: &'static str, message: String },
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        EngineConfig {
+            exchanges: vec![Exchange::Htx],
+            websocket_url: "wss://api-aws.huobi.pro/ws".to_string(),
+            rest_url: "https://api-aws.huobi.pro".to_string(),
+            shm_file_path: "/tmp/ticks.mmap".to_string(),
+            markets_per_websocket: 150,
+            connections_per_thread: 1,
+            chunk_size: 320,
+            log_level: "debug".to_string(),
+            channels: vec![Channel::Bbo],
+            symbol_filters: SymbolFilters::default(),
+            rest: RestConfig::default(),
+            reconnect: ReconnectConfig::default(),
+            binance: BinanceConfig::default(),
+            refresh: RefreshConfig::default(),
+            recording: RecordingConfig::default(),
+            replay: ReplayConfig::default(),
+            placement: PlacementConfig::default(),
+            latency: LatencyConfig::default(),
+            metrics: MetricsConfig::default(),
+        }
+    }
+}
+
+impl Default for SymbolFilters {
+    fn default() -> Self {
+        SymbolFilters {
+            online: true,
+            trade_enabled: true,
+            cancel_enabled: true,
+            visible: true,
+            listed: true,
+            country_enabled: true,
+        }
+    }
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig {
+            initial_backoff_ms: 100,
+            max_backoff_ms: 30_000,
+            multiplier: 2,
+            jitter_percent: 20,
+            stable_after_secs: 60,
+        }
+    }
+}
+
+impl Default for BinanceConfig {
+    fn default() -> Self {
+        BinanceConfig {
+            websocket_url: "wss://stream.binance.com:9443".to_string(),
+            rest_url: "https://api.binance.com".to_string(),
+            shm_file_path: "/tmp/ticks.binance.mmap".to_string(),
+            markets_per_websocket: 200,
+            symbol_filters: BinanceSymbolFilters::default(),
+        }
+    }
+}
+
+impl Default for BinanceSymbolFilters {
+    fn default() -> Self {
+        BinanceSymbolFilters {
+            trading: true,
+            spot_trading_allowed: true,
+            quote_assets: Vec::new(),
+        }
+    }
+}
+
+impl Default for RestConfig {
+    fn default() -> Self {
+        RestConfig {
+            connect_timeout_ms: 5_000,
+            read_timeout_ms: 30_000,
+            max_retries: 3,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 10_000,
+            max_retry_after_ms: 60_000,
+            requests_per_second: 5.0,
+            burst: 5,
+        }
+    }
+}
+
+impl Default for RefreshConfig {
+    fn default() -> Self {
+        RefreshConfig {
+            enabled: false,
+            interval_secs: 300,
+            spare_markets: 50,
+        }
+    }
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        RecordingConfig {
+            enabled: false,
+            directory: "/tmp/cashengine-recordings".to_string(),
+            max_file_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        ReplayConfig {
+            enabled: false,
+            path: "/tmp/cashengine-recordings".to_string(),
+            speed: 1.0,
+        }
+    }
+}
+
+impl Default for PlacementConfig {
+    fn default() -> Self {
+        PlacementConfig {
+            mode: PlacementMode::BestEffort,
+            feed_cores: Vec::new(),
+            reader_core: None,
+            isolated_cpus: false,
+            numa_node: None,
+            realtime_priority: 0,
+        }
+    }
+}
+
+impl Default for LatencyConfig {
+    fn default() -> Self {
+        LatencyConfig {
+            percentiles: vec![50.0, 90.0, 99.0, 99.9],
+            report_interval_secs: 10,
+            window_secs: 60,
+            reset_on_report: false,
+        }
+    }
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            enabled: false,
+            listen_address: "127.0.0.1:9184".to_string(),
+        }
+    }
+}
+
+impl EngineConfig {
+    // Load the config from a .toml or .json file, then apply environment overrides and validate
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path).map_err(|error| ConfigError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        let config: EngineConfig = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&content).map_err(|e| ConfigError::Parse {
+                path: path.to_path_buf(),
+                message: e.to_string(),
+            })?,
+            Some("json") => serde_json::from_str(&content).map_err(|e| ConfigError::Parse {
+                path: path.to_path_buf(),
+                message: e.to_string(),
+            })?,
+            _ => return Err(ConfigError::UnsupportedFormat(path.to_path_buf())),
+        };
+        config.with_env_overrides()?.validated()
+    }
+
+    // Built-in defaults with environment overrides, for running without a config file
+    pub fn from_env() -> Result<Self, ConfigError> {
+        EngineConfig::default().with_env_overrides()?.validated()
+    }
+
+    fn with_env_overrides(mut self) -> Result<Self, ConfigError> {
+        list_from_env("EXCHANGES", &mut self.exchanges)?;
+        override_from_env("WEBSOCKET_URL", &mut self.websocket_url)?;
+        override_from_env("REST_URL", &mut self.rest_url)?;
+        override_from_env("SHM_FILE_PATH", &mut self.shm_file_path)?;
+        override_from_env("MARKETS_PER_WEBSOCKET", &mut self.markets_per_websocket)?;
+        override_from_env("CONNECTIONS_PER_THREAD", &mut self.connections_per_thread)?;
+        override_from_env("CHUNK_SIZE", &mut self.chunk_size)?;
+        override_from_env("LOG_LEVEL", &mut self.log_level)?;
+        list_from_env("CHANNELS", &mut self.channels)?;
+        let filters = &mut self.symbol_filters;
+        override_from_env("SYMBOL_FILTERS_ONLINE", &mut filters.online)?;
+        override_from_env("SYMBOL_FILTERS_TRADE_ENABLED", &mut filters.trade_enabled)?;
+        override_from_env("SYMBOL_FILTERS_CANCEL_ENABLED", &mut filters.cancel_enabled)?;
+        override_from_env("SYMBOL_FILTERS_VISIBLE", &mut filters.visible)?;
+        override_from_env("SYMBOL_FILTERS_LISTED", &mut filters.listed)?;
+        override_from_env("SYMBOL_FILTERS_COUNTRY_ENABLED", &mut filters.country_enabled)?;
+        let rest = &mut self.rest;
+        override_from_env("REST_CONNECT_TIMEOUT_MS", &mut rest.connect_timeout_ms)?;
+        override_from_env("REST_READ_TIMEOUT_MS", &mut rest.read_timeout_ms)?;
+        override_from_env("REST_MAX_RETRIES", &mut rest.max_retries)?;
+        override_from_env("REST_INITIAL_BACKOFF_MS", &mut rest.initial_backoff_ms)?;
+        override_from_env("REST_MAX_BACKOFF_MS", &mut rest.max_backoff_ms)?;
+        override_from_env("REST_MAX_RETRY_AFTER_MS", &mut rest.max_retry_after_ms)?;
+        override_from_env("REST_REQUESTS_PER_SECOND", &mut rest.requests_per_second)?;
+        override_from_env("REST_BURST", &mut rest.burst)?;
+        let reconnect = &mut self.reconnect;
+        override_from_env("RECONNECT_INITIAL_BACKOFF_MS", &mut reconnect.initial_backoff_ms)?;
+        override_from_env("RECONNECT_MAX_BACKOFF_MS", &mut reconnect.max_backoff_ms)?;
+        override_from_env("RECONNECT_MULTIPLIER", &mut reconnect.multiplier)?;
+        override_from_env("RECONNECT_JITTER_PERCENT", &mut reconnect.jitter_percent)?;
+        override_from_env("RECONNECT_STABLE_AFTER_SECS", &mut reconnect.stable_after_secs)?;
+        let binance = &mut self.binance;
+        override_from_env("BINANCE_WEBSOCKET_URL", &mut binance.websocket_url)?;
+        override_from_env("BINANCE_REST_URL", &mut binance.rest_url)?;
+        override_from_env("BINANCE_SHM_FILE_PATH", &mut binance.shm_file_path)?;
+        override_from_env("BINANCE_MARKETS_PER_WEBSOCKET", &mut binance.markets_per_websocket)?;
+        let filters = &mut binance.symbol_filters;
+        override_from_env("BINANCE_SYMBOL_FILTERS_TRADING", &mut filters.trading)?;
+        override_from_env("BINANCE_SYMBOL_FILTERS_SPOT_TRADING_ALLOWED", &mut filters.spot_trading_allowed)?;
+        list_from_env("BINANCE_SYMBOL_FILTERS_QUOTE_ASSETS", &mut filters.quote_assets)?;
+        let refresh = &mut self.refresh;
+        override_from_env("REFRESH_ENABLED", &mut refresh.enabled)?;
+        override_from_env("REFRESH_INTERVAL_SECS", &mut refresh.interval_secs)?;
+        override_from_env("REFRESH_SPARE_MARKETS", &mut refresh.spare_markets)?;
+        let recording = &mut self.recording;
+        override_from_env("RECORDING_ENABLED", &mut recording.enabled)?;
+        override_from_env("RECORDING_DIRECTORY", &mut recording.directory)?;
+        override_from_env("RECORDING_MAX_FILE_BYTES", &mut recording.max_file_bytes)?;
+        let replay = &mut self.replay;
+        override_from_env("REPLAY_ENABLED", &mut replay.enabled)?;
+        override_from_env("REPLAY_PATH", &mut replay.path)?;
+        override_from_env("REPLAY_SPEED", &mut replay.speed)?;
+        let placement = &mut self.placement;
+        override_from_env("PLACEMENT_MODE", &mut placement.mode)?;
+        list_from_env("PLACEMENT_FEED_CORES", &mut placement.feed_cores)?;
+        option_from_env("PLACEMENT_READER_CORE", &mut placement.reader_core)?;
+        override_from_env("PLACEMENT_ISOLATED_CPUS", &mut placement.isolated_cpus)?;
+        option_from_env("PLACEMENT_NUMA_NODE", &mut placement.numa_node)?;
+        override_from_env("PLACEMENT_REALTIME_PRIORITY", &mut placement.realtime_priority)?;
+        let latency = &mut self.latency;
+        list_from_env("LATENCY_PERCENTILES", &mut latency.percentiles)?;
+        override_from_env("LATENCY_REPORT_INTERVAL_SECS", &mut latency.report_interval_secs)?;
+        override_from_env("LATENCY_WINDOW_SECS", &mut latency.window_secs)?;
+        override_from_env("LATENCY_RESET_ON_REPORT", &mut latency.reset_on_report)?;
+        let metrics = &mut self.metrics;
+        override_from_env("METRICS_ENABLED", &mut metrics.enabled)?;
+        override_from_env("METRICS_LISTEN_ADDRESS", &mut metrics.listen_address)?;
+        Ok(self)
+    }
+
+    fn validated(self) -> Result<Self, ConfigError> {
+        if self.exchanges.is_empty() {
+            return Err(ConfigError::invalid("exchanges", "must not be empty".to_string()));
+        }
+        for (i, exchange) in self.exchanges.iter().enumerate() {
+            if self.exchanges[..i].contains(exchange) {
+                return Err(ConfigError::invalid("exchanges", format!("'{}' is listed more than once", exchange)));
+            }
+        }
+        if !(self.websocket_url.starts_with("wss://") || self.websocket_url.starts_with("ws://")) {
+            return Err(ConfigError::invalid("websocket_url", format!("'{}' must start with wss:// or ws://", self.websocket_url)));
+        }
+        if !(self.rest_url.starts_with("https://") || self.rest_url.starts_with("http://")) {
+            return Err(ConfigError::invalid("rest_url", format!("'{}' must start with https:// or http://", self.rest_url)));
+        }
+        if self.rest_url.ends_with('/') {
+            return Err(ConfigError::invalid("rest_url", format!("'{}' must not end with '/'", self.rest_url)));
+        }
+        if self.shm_file_path.is_empty() {
+            return Err(ConfigError::invalid("shm_file_path", "must not be empty".to_string()));
+        }
+        if self.markets_per_websocket == 0 {
+            return Err(ConfigError::invalid("markets_per_websocket", "must be greater than 0".to_string()));
+        }
+        if self.connections_per_thread == 0 {
+            return Err(ConfigError::invalid("connections_per_thread", "must be greater than 0".to_string()));
+        }
+        if self.chunk_size < MIN_CHUNK_SIZE {
+            return Err(ConfigError::invalid("chunk_size", format!("{} is smaller than the minimum of {} bytes", self.chunk_size, MIN_CHUNK_SIZE)));
+        }
+        if !self.chunk_size.is_multiple_of(shm_chunk::CHUNK_ALIGNMENT) {
+            return Err(ConfigError::invalid("chunk_size", format!("{} is not a multiple of {}", self.chunk_size, shm_chunk::CHUNK_ALIGNMENT)));
+        }
+        if self.channels.is_empty() {
+            return Err(ConfigError::invalid("channels", "must not be empty".to_string()));
+        }
+        for (i, channel) in self.channels.iter().enumerate() {
+            if self.channels[..i].contains(channel) {
+                return Err(ConfigError::invalid("channels", format!("'{}' is listed more than once", channel)));
+            }
+        }
+        let binance = &self.binance;
+        if !(binance.websocket_url.starts_with("wss://") || binance.websocket_url.starts_with("ws://")) {
+            return Err(ConfigError::invalid("binance.websocket_url", format!("'{}' must start with wss:// or ws://", binance.websocket_url)));
+        }
+        if binance.websocket_url.ends_with('/') {
+            return Err(ConfigError::invalid("binance.websocket_url", format!("'{}' must not end with '/'", binance.websocket_url)));
+        }
+        if !(binance.rest_url.starts_with("https://") || binance.rest_url.starts_with("http://")) {
+            return Err(ConfigError::invalid("binance.rest_url", format!("'{}' must start with https:// or http://", binance.rest_url)));
+        }
+        if binance.rest_url.ends_with('/') {
+            return Err(ConfigError::invalid("binance.rest_url", format!("'{}' must not end with '/'", binance.rest_url)));
+        }
+        if binance.shm_file_path.is_empty() {
+            return Err(ConfigError::invalid("binance.shm_file_path", "must not be empty".to_string()));
+        }
+        if self.exchanges.contains(&Exchange::Binance) && self.exchanges.contains(&Exchange::Htx)
+            && Channel::Bbo.shm_file_path(&self.shm_file_path) == binance.shm_file_path {
+            return Err(ConfigError::invalid("binance.shm_file_path", "must differ from shm_file_path".to_string()));
+        }
+        if binance.markets_per_websocket == 0 || binance.markets_per_websocket > binance_feed::MAX_STREAMS_PER_CONNECTION {
+            return Err(ConfigError::invalid("binance.markets_per_websocket", format!("{} must be between 1 and {}",
+                binance.markets_per_websocket, binance_feed::MAX_STREAMS_PER_CONNECTION)));
+        }
+        let rest = &self.rest;
+        if rest.connect_timeout_ms == 0 {
+            return Err(ConfigError::invalid("rest.connect_timeout_ms", "must be greater than 0".to_string()));
+        }
+        if rest.read_timeout_ms == 0 {
+            return Err(ConfigError::invalid("rest.read_timeout_ms", "must be greater than 0".to_string()));
+        }
+        if rest.max_backoff_ms < rest.initial_backoff_ms {
+            return Err(ConfigError::invalid("rest.max_backoff_ms", format!("{} must not be below rest.initial_backoff_ms {}",
+                rest.max_backoff_ms, rest.initial_backoff_ms)));
+        }
+        if !(rest.requests_per_second.is_finite() && rest.requests_per_second > 0.0) {
+            return Err(ConfigError::invalid("rest.requests_per_second", format!("{} must be a positive number", rest.requests_per_second)));
+        }
+        if rest.burst == 0 {
+            return Err(ConfigError::invalid("rest.burst", "must be greater than 0".to_string()));
+        }
+        if self.refresh.interval_secs == 0 {
+            return Err(ConfigError::invalid("refresh.interval_secs", "must be greater than 0".to_string()));
+        }
+        if self.recording.enabled && self.recording.directory.is_empty() {
+            return Err(ConfigError::invalid("recording.directory", "must not be empty".to_string()));
+        }
+        if self.recording.max_file_bytes == 0 {
+            return Err(ConfigError::invalid("recording.max_file_bytes", "must be greater than 0".to_string()));
+        }
+        if self.replay.enabled && self.replay.path.is_empty() {
+            return Err(ConfigError::invalid("replay.path", "must not be empty".to_string()));
+        }
+        if !(self.replay.speed.is_finite() && self.replay.speed >= 0.0) {
+            return Err(ConfigError::invalid("replay.speed", format!("{} must be 0 or a positive number", self.replay.speed)));
+        }
+        if self.replay.enabled && self.recording.enabled {
+            return Err(ConfigError::invalid("recording.enabled", "must be false while replaying".to_string()));
+        }
+        if self.placement.realtime_priority > MAX_REALTIME_PRIORITY {
+            return Err(ConfigError::invalid("placement.realtime_priority", format!("{} must be between 0 and {}",
+                self.placement.realtime_priority, MAX_REALTIME_PRIORITY)));
+        }
+        let latency = &self.latency;
+        if let Some(percentile) = latency.percentiles.iter().find(|percentile| !(**percentile > 0.0 && **percentile <= 100.0)) {
+            return Err(ConfigError::invalid("latency.percentiles", format!("{} must be above 0 and at most 100", percentile)));
+        }
+        if latency.report_interval_secs == 0 {
+            return Err(ConfigError::invalid("latency.report_interval_secs", "must be greater than 0".to_string()));
+        }
+        if latency.window_secs == 0 {
+            return Err(ConfigError::invalid("latency.window_secs", "must be greater than 0".to_string()));
+        }
+        if let Err(e) = self.metrics.listen_address.parse::<SocketAddr>() {
+            return Err(ConfigError::invalid("metrics.listen_address", format!("'{}': {}", self.metrics.listen_address, e)));
+        }
+        if let Err(e) = tracing::Level::from_str(&self.log_level) {
+            return Err(ConfigError::invalid("log_level", format!("'{}': {}", self.log_level, e)));
+        }
+        let reconnect = &self.reconnect;
+        if reconnect.initial_backoff_ms == 0 {
+            return Err(ConfigError::invalid("reconnect.initial_backoff_ms", "must be greater than 0".to_string()));
+        }
+        if reconnect.max_backoff_ms < reconnect.initial_backoff_ms {
+            return Err(ConfigError::invalid("reconnect.max_backoff_ms", format!("{} must not be below reconnect.initial_backoff_ms {}",
+                reconnect.max_backoff_ms, reconnect.initial_backoff_ms)));
+        }
+        if reconnect.multiplier == 0 {
+            return Err(ConfigError::invalid("reconnect.multiplier", "must be greater than 0".to_string()));
+        }
+        if reconnect.jitter_percent > 100 {
+            return Err(ConfigError::invalid("reconnect.jitter_percent", format!("{} must not exceed 100", reconnect.jitter_percent)));
+        }
+        Ok(self)
+    }
+
+    pub fn log_level(&self) -> tracing::Level {
+        // Validated on load
+        tracing::Level::from_str(&self.log_level).unwrap_or(tracing::Level::DEBUG)
+    }
+}
+
+impl SymbolFilters {
+    // `online` keeps the symbols in one of `states`, online only at startup
+    pub(crate) fn apply(&self, symbols: HtxSymbols, states: &[HtxState]) -> HtxSymbols {
+        let mut symbols = symbols;
+        if self.online {
+            symbols = symbols.with_states(states);
+        }
+        if self.trade_enabled {
+            symbols = symbols.with_trade_enabled_symbols();
+        }
+        if self.cancel_enabled {
+            symbols = symbols.with_cancel_enabled_symbols();
+        }
+        if self.visible {
+            symbols = symbols.with_visible_symbols();
+        }
+        if self.listed {
+            symbols = symbols.with_listed_symbols();
+        }
+        if self.country_enabled {
+            symbols = symbols.with_country_enabled();
+        }
+        symbols
+    }
+}
+
+fn override_from_env<T>(name: &str, target: &mut T) -> Result<(), ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let name = format!("{ENV_PREFIX}{name}");
+    if let Ok(value) = std::env::var(&name) {
+        match value.parse() {
+            Ok(parsed) => *target = parsed,
+            Err(e) => {
+                let message = e.to_string();
+                return Err(ConfigError::InvalidEnv { name, value, message });
+            }
+        }
+    }
+    Ok(())
+}
+
+// An empty value unsets the option, e.g. CASHENGINE_PLACEMENT_READER_CORE=
+fn option_from_env<T>(name: &str, target: &mut Option<T>) -> Result<(), ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let name = format!("{ENV_PREFIX}{name}");
+    if let Ok(value) = std::env::var(&name) {
+        if value.trim().is_empty() {
+            *target = None;
+            return Ok(());
+        }
+        match value.trim().parse() {
+            Ok(parsed) => *target = Some(parsed),
+            Err(e) => {
+                let message = e.to_string();
+                return Err(ConfigError::InvalidEnv { name, value, message });
+            }
+        }
+    }
+    Ok(())
+}
+
+// Comma separated list, e.g. CASHENGINE_CHANNELS=bbo,trade.detail
+fn list_from_env<T>(name: &str, target: &mut Vec<T>) -> Result<(), ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let name = format!("{ENV_PREFIX}{name}");
+    if let Ok(value) = std::env::var(&name) {
+        let items = value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(str::parse).collect();
+        match items {
+            Ok(items) => *target = items,
+            Err(e) => {
+                let message = e.to_string();
+                return Err(ConfigError::InvalidEnv { name, value, message });
+            }
+        }
+    }
+    Ok(())
+}
+
+impl BinanceSymbolFilters {
+    pub(crate) fn apply(&self, symbols: BinanceSymbols) -> BinanceSymbols {
+        let mut symbols = symbols;
+        if self.trading {
+            symbols = symbols.with_trading_symbols();
+        }
+        if self.spot_trading_allowed {
+            symbols = symbols.with_spot_trading_allowed();
+        }
+        if !self.quote_assets.is_empty() {
+            symbols = symbols.with_quote_assets(&self.quote_assets);
+        }
+        symbols
+    }
+}
+
+impl fmt::Display for PlacementMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlacementMode::BestEffort => write!(f, "best_effort"),
+            PlacementMode::Strict => write!(f, "strict"),
+        }
+    }
+}
+
+impl FromStr for PlacementMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "best_effort" => Ok(PlacementMode::BestEffort),
+            "strict" => Ok(PlacementMode::Strict),
+            _ => Err(format!("unknown placement mode '{}', expected best_effort or strict", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for PlacementMode {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl ConfigError {
+    fn invalid(field: &'static str, message: String) -> Self {
+        ConfigError::Invalid { field, message }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, error } => write!(f, "Failed to read config file {}: {}", path.display(), error),
+            ConfigError::Parse { path, message } => write!(f, "Failed to parse config file {}: {}", path.display(), message),
+            ConfigError::UnsupportedFormat(path) => write!(f, "Unsupported config file format {}, expected .toml or .json", path.display()),
+            ConfigError::InvalidEnv { name, value, message } => write!(f, "Invalid value '{}' in environment variable {}: {}", value, name, message),
+            ConfigError::Invalid { field, message } => write!(f, "Invalid config value for {}: {}", field, message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
@@ -0,0 +1,552 @@
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::{Add, Mul, Neg, Sub};
+use std::str::FromStr;
+
+// Most decimals a value can have, 10^18 still fits into the i64 mantissa
+pub const MAX_SCALE: u32 = 18;
+
+// Fixed-point decimal `mantissa * 10^-scale` for prices, amounts and order values.
+// Parsed exactly from the exchanges' decimal strings and numbers, so tick and lot checks and price
+// comparisons don't suffer from binary floating point rounding. Values with different scales compare
+// by value, e.g. 0.10 == 0.1, while Display keeps the scale.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Decimal {
+    mantissa: i64,
+    scale: u32,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RoundingMode {
+    // Toward negative infinity, e.g. bid prices or amounts that must stay within a budget
+    Down,
+    // Toward positive infinity, e.g. ask prices
+    Up,
+    // To the nearest value, ties away from zero
+    HalfUp,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecimalError {
+    // Not a plain decimal number such as -12.340
+    Invalid(String),
+    // The result doesn't fit into an i64 mantissa with at most MAX_SCALE decimals
+    Overflow,
+    // Rounding to an increment of zero or less
+    InvalidIncrement(Decimal),
+}
+
+impl Decimal {
+    pub const ZERO: Decimal = Decimal { mantissa: 0, scale: 0 };
+    pub const ONE: Decimal = Decimal { mantissa: 1, scale: 0 };
+
+    // Panics if `scale` exceeds MAX_SCALE
+    pub const fn new(mantissa: i64, scale: u32) -> Decimal {
+        assert!(scale <= MAX_SCALE, "decimal scale exceeds MAX_SCALE");
+        Decimal { mantissa, scale }
+    }
+
+    // Smallest step with `precision` decimals, e.g. 2 -> 0.01
+    pub fn increment(precision: u32) -> Option<Decimal> {
+        (precision <= MAX_SCALE).then_some(Decimal { mantissa: 1, scale: precision })
+    }
+
+    // Shortest decimal that converts back to `value`, e.g. 0.1 and not 0.1000000000000000055511151231257827
+    pub fn from_f64(value: f64) -> Result<Decimal, DecimalError> {
+        if !value.is_finite() {
+            return Err(DecimalError::Invalid(value.to_string()));
+        }
+        value.to_string().parse()
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        // Both operands are exact for mantissas below 2^53, so the quotient is correctly rounded
+        self.mantissa as f64 / 10f64.powi(self.scale as i32)
+    }
+
+    pub fn mantissa(&self) -> i64 {
+        self.mantissa
+    }
+
+    pub fn scale(&self) -> u32 {
+        self.scale
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.mantissa == 0
+    }
+
+    pub fn is_positive(&self) -> bool {
+        self.mantissa > 0
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.mantissa < 0
+    }
+
+    // Without trailing zeros in the fraction, e.g. 0.01000000 -> 0.01
+    pub fn normalized(&self) -> Decimal {
+        let mut normalized = *self;
+        while normalized.scale > 0 && normalized.mantissa % 10 == 0 {
+            normalized.mantissa /= 10;
+            normalized.scale -= 1;
+        }
+        normalized
+    }
+
+    // Same value with `scale` decimals, rounded with `mode` if decimals are dropped
+    pub fn rescale(&self, scale: u32, mode: RoundingMode) -> Result<Decimal, DecimalError> {
+        if scale > MAX_SCALE {
+            return Err(DecimalError::Overflow);
+        }
+        let mantissa = if scale >= self.scale {
+            self.mantissa as i128 * pow10(scale - self.scale)
+        } else {
+            div_round(self.mantissa as i128, pow10(self.scale - scale), mode)
+        };
+        from_parts(mantissa, scale)
+    }
+
+    // Nearest multiple of `increment` in the direction of `mode`, with the scale of `increment`,
+    // e.g. a price rounded to the tick size
+    pub fn round_to(&self, increment: Decimal, mode: RoundingMode) -> Result<Decimal, DecimalError> {
+        if !increment.is_positive() {
+            return Err(DecimalError::InvalidIncrement(increment));
+        }
+        let scale = self.scale.max(increment.scale);
+        let steps = div_round(self.aligned(scale), increment.aligned(scale), mode);
+        from_parts(steps * increment.mantissa as i128, increment.scale)
+    }
+
+    // False for increments of zero or less
+    pub fn is_multiple_of(&self, increment: Decimal) -> bool {
+        let scale = self.scale.max(increment.scale);
+        increment.is_positive() && self.aligned(scale) % increment.aligned(scale) == 0
+    }
+
+    pub fn checked_add(self, other: Decimal) -> Result<Decimal, DecimalError> {
+        let scale = self.scale.max(other.scale);
+        from_parts(self.aligned(scale) + other.aligned(scale), scale)
+    }
+
+    pub fn checked_sub(self, other: Decimal) -> Result<Decimal, DecimalError> {
+        let scale = self.scale.max(other.scale);
+        from_parts(self.aligned(scale) - other.aligned(scale), scale)
+    }
+
+    // Exact product, e.g. the order value of a price and an amount. Trailing zeros are dropped to stay
+    // within MAX_SCALE and the i64 mantissa, e.g. for 30000.00000000 * 1.00000000, a product needing
+    // more decimals is an overflow.
+    pub fn checked_mul(self, other: Decimal) -> Result<Decimal, DecimalError> {
+        let mut mantissa = self.mantissa as i128 * other.mantissa as i128;
+        let mut scale = self.scale + other.scale;
+        while (scale > MAX_SCALE || i64::try_from(mantissa).is_err()) && scale > 0 && mantissa % 10 == 0 {
+            mantissa /= 10;
+            scale -= 1;
+        }
+        if scale > MAX_SCALE {
+            return Err(DecimalError::Overflow);
+        }
+        from_parts(mantissa, scale)
+    }
+
+    // Mantissa with `scale` decimals, `scale` must not be below the own scale. Fits since
+    // |i64| * 10^MAX_SCALE < 2^127.
+    fn aligned(&self, scale: u32) -> i128 {
+        self.mantissa as i128 * pow10(scale - self.scale)
+    }
+}
+
+fn pow10(exponent: u32) -> i128 {
+    10i128.pow(exponent)
+}
+
+fn from_parts(mantissa: i128, scale: u32) -> Result<Decimal, DecimalError> {
+    let mantissa = i64::try_from(mantissa).map_err(|_| DecimalError::Overflow)?;
+    Ok(Decimal { mantissa, scale })
+}
+
+// `value / divisor` rounded with `mode`, `divisor` is positive
+fn div_round(value: i128, divisor: i128, mode: RoundingMode) -> i128 {
+    let (quotient, remainder) = (value / divisor, value % divisor);
+    match mode {
+        RoundingMode::Down if remainder < 0 => quotient - 1,
+        RoundingMode::Up if remainder > 0 => quotient + 1,
+        RoundingMode::HalfUp if 2 * remainder.abs() >= divisor => quotient + value.signum(),
+        _ => quotient,
+    }
+}
+
+impl PartialEq for Decimal {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Decimal {}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Decimal {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let scale = self.scale.max(other.scale);
+        self.aligned(scale).cmp(&other.aligned(scale))
+    }
+}
+
+// Equal values hash the same regardless of their scale
+impl Hash for Decimal {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let normalized = self.normalized();
+        normalized.mantissa.hash(state);
+        normalized.scale.hash(state);
+    }
+}
+
+// The operators panic on overflow like the integer operators, use the checked methods for untrusted values
+impl Add for Decimal {
+    type Output = Decimal;
+
+    fn add(self, other: Decimal) -> Decimal {
+        self.checked_add(other).expect("decimal addition overflowed")
+    }
+}
+
+impl Sub for Decimal {
+    type Output = Decimal;
+
+    fn sub(self, other: Decimal) -> Decimal {
+        self.checked_sub(other).expect("decimal subtraction overflowed")
+    }
+}
+
+impl Mul for Decimal {
+    type Output = Decimal;
+
+    fn mul(self, other: Decimal) -> Decimal {
+        self.checked_mul(other).expect("decimal multiplication overflowed")
+    }
+}
+
+impl Neg for Decimal {
+    type Output = Decimal;
+
+    fn neg(self) -> Decimal {
+        Decimal { mantissa: self.mantissa.checked_neg().expect("decimal negation overflowed"), scale: self.scale }
+    }
+}
+
+impl FromStr for Decimal {
+    type Err = DecimalError;
+
+    // Plain decimals such as 42, -0.5 or 0.00100000, without exponent. Decimals beyond MAX_SCALE must be zeros.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || DecimalError::Invalid(value.to_string());
+        let (negative, digits) = match value.as_bytes().first() {
+            Some(b'-') => (true, &value[1..]),
+            Some(b'+') => (false, &value[1..]),
+            _ => (false, value),
+        };
+        let (integer, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        if integer.is_empty() && fraction.is_empty() {
+            return Err(invalid());
+        }
+        if !integer.bytes().chain(fraction.bytes()).all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let fraction = if fraction.len() > MAX_SCALE as usize {
+            let (kept, dropped) = fraction.split_at(MAX_SCALE as usize);
+            if dropped.bytes().any(|c| c != b'0') {
+                return Err(DecimalError::Overflow);
+            }
+            kept
+        } else {
+            fraction
+        };
+
+        let mut mantissa: i128 = 0;
+        for digit in integer.bytes().chain(fraction.bytes()) {
+            mantissa = mantissa * 10 + (digit - b'0') as i128;
+            if mantissa > i64::MAX as i128 + 1 {
+                return Err(DecimalError::Overflow);
+            }
+        }
+        from_parts(if negative { -mantissa } else { mantissa }, fraction.len() as u32)
+    }
+}
+
+impl TryFrom<f64> for Decimal {
+    type Error = DecimalError;
+
+    fn try_from(value: f64) -> Result<Self, Self::Error> {
+        Decimal::from_f64(value)
+    }
+}
+
+impl From<i64> for Decimal {
+    fn from(value: i64) -> Self {
+        Decimal { mantissa: value, scale: 0 }
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let digits = self.mantissa.unsigned_abs().to_string();
+        let scale = self.scale as usize;
+        let sign = if self.mantissa < 0 { "-" } else { "" };
+        if scale == 0 {
+            write!(f, "{}{}", sign, digits)
+        } else if digits.len() > scale {
+            let (integer, fraction) = digits.split_at(digits.len() - scale);
+            write!(f, "{}{}.{}", sign, integer, fraction)
+        } else {
+            write!(f, "{}0.{:0>width$}", sign, digits, width = scale)
+        }
+    }
+}
+
+impl fmt::Display for DecimalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecimalError::Invalid(value) => write!(f, "invalid decimal {}", value),
+            DecimalError::Overflow => write!(f, "decimal overflow, the mantissa is an i64 with at most {} decimals", MAX_SCALE),
+            DecimalError::InvalidIncrement(increment) => write!(f, "invalid increment {}, must be positive", increment),
+        }
+    }
+}
+
+impl std::error::Error for DecimalError {}
+
+// Serialized as string to keep the exact value, deserialized from strings and JSON numbers
+impl Serialize for Decimal {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Decimal {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(DecimalVisitor)
+    }
+}
+
+struct DecimalVisitor;
+
+impl<'de> Visitor<'de> for DecimalVisitor {
+    type Value = Decimal;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a decimal number or string")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Decimal, E> {
+        value.parse().map_err(E::custom)
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Decimal, E> {
+        Ok(Decimal::from(value))
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Decimal, E> {
+        i64::try_from(value).map(Decimal::from).map_err(|_| E::custom(DecimalError::Overflow))
+    }
+
+    // JSON numbers with a fraction arrive as f64, their shortest representation is the number as sent
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<Decimal, E> {
+        Decimal::from_f64(value).map_err(E::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::hash_map::DefaultHasher;
+    use std::collections::HashSet;
+
+    fn decimal(value: &str) -> Decimal {
+        value.parse().unwrap()
+    }
+
+    fn hash(value: Decimal) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn div_round_positive() {
+        assert_eq!(div_round(15, 10, RoundingMode::Down), 1);
+        assert_eq!(div_round(15, 10, RoundingMode::Up), 2);
+        assert_eq!(div_round(15, 10, RoundingMode::HalfUp), 2);
+        assert_eq!(div_round(14, 10, RoundingMode::HalfUp), 1);
+        assert_eq!(div_round(20, 10, RoundingMode::Up), 2);
+    }
+
+    #[test]
+    fn div_round_negative() {
+        // Down and Up round toward negative and positive infinity, HalfUp ties away from zero
+        assert_eq!(div_round(-15, 10, RoundingMode::Down), -2);
+        assert_eq!(div_round(-15, 10, RoundingMode::Up), -1);
+        assert_eq!(div_round(-15, 10, RoundingMode::HalfUp), -2);
+        assert_eq!(div_round(-14, 10, RoundingMode::HalfUp), -1);
+        assert_eq!(div_round(-11, 10, RoundingMode::Down), -2);
+        assert_eq!(div_round(-19, 10, RoundingMode::Up), -1);
+        for mode in [RoundingMode::Down, RoundingMode::Up, RoundingMode::HalfUp] {
+            assert_eq!(div_round(-20, 10, mode), -2);
+            assert_eq!(div_round(0, 10, mode), 0);
+        }
+    }
+
+    #[test]
+    fn rescale_rounds_dropped_decimals() {
+        assert_eq!(decimal("1.25").rescale(1, RoundingMode::Down).unwrap().to_string(), "1.2");
+        assert_eq!(decimal("1.25").rescale(1, RoundingMode::HalfUp).unwrap().to_string(), "1.3");
+        assert_eq!(decimal("-1.25").rescale(1, RoundingMode::Down).unwrap().to_string(), "-1.3");
+        assert_eq!(decimal("-1.25").rescale(1, RoundingMode::Up).unwrap().to_string(), "-1.2");
+        assert_eq!(decimal("1.5").rescale(3, RoundingMode::Down).unwrap().to_string(), "1.500");
+        assert_eq!(decimal("1").rescale(MAX_SCALE + 1, RoundingMode::Down), Err(DecimalError::Overflow));
+    }
+
+    #[test]
+    fn round_to_increment_of_smaller_scale() {
+        let tick = decimal("0.01");
+        assert_eq!(decimal("1.2345").round_to(tick, RoundingMode::Down).unwrap().to_string(), "1.23");
+        assert_eq!(decimal("1.2345").round_to(tick, RoundingMode::Up).unwrap().to_string(), "1.24");
+        assert_eq!(decimal("-1.2345").round_to(tick, RoundingMode::Down).unwrap().to_string(), "-1.24");
+        assert_eq!(decimal("-1.2345").round_to(tick, RoundingMode::Up).unwrap().to_string(), "-1.23");
+        assert_eq!(decimal("123.45").round_to(decimal("10"), RoundingMode::HalfUp).unwrap().to_string(), "120");
+        assert_eq!(decimal("125").round_to(decimal("10"), RoundingMode::HalfUp).unwrap().to_string(), "130");
+    }
+
+    #[test]
+    fn round_to_increment_of_larger_scale() {
+        // The result has the scale of the increment
+        assert_eq!(decimal("1.2").round_to(decimal("0.05"), RoundingMode::Down).unwrap().to_string(), "1.20");
+        assert_eq!(decimal("1.3").round_to(decimal("0.50"), RoundingMode::Down).unwrap().to_string(), "1.00");
+        assert_eq!(decimal("1.3").round_to(decimal("0.50"), RoundingMode::Up).unwrap().to_string(), "1.50");
+        assert_eq!(decimal("-1.3").round_to(decimal("0.50"), RoundingMode::HalfUp).unwrap().to_string(), "-1.50");
+    }
+
+    #[test]
+    fn round_to_rejects_non_positive_increments() {
+        assert_eq!(decimal("1").round_to(Decimal::ZERO, RoundingMode::Down), Err(DecimalError::InvalidIncrement(Decimal::ZERO)));
+        assert!(decimal("1").round_to(decimal("-0.1"), RoundingMode::Down).is_err());
+        assert!(!decimal("1").is_multiple_of(Decimal::ZERO));
+    }
+
+    #[test]
+    fn is_multiple_of() {
+        assert!(decimal("1.20").is_multiple_of(decimal("0.05")));
+        assert!(decimal("-0.3").is_multiple_of(decimal("0.1")));
+        assert!(!decimal("1.23").is_multiple_of(decimal("0.05")));
+        assert!(decimal("120").is_multiple_of(decimal("10")));
+    }
+
+    #[test]
+    fn from_str_valid() {
+        assert_eq!(decimal("42"), Decimal::new(42, 0));
+        assert_eq!(decimal("-0.5"), Decimal::new(-5, 1));
+        assert_eq!(decimal("+1.50").to_string(), "1.50");
+        assert_eq!(decimal(".5"), Decimal::new(5, 1));
+        assert_eq!(decimal("1."), Decimal::new(1, 0));
+        assert_eq!(decimal("0.00100000").scale(), 8);
+    }
+
+    #[test]
+    fn from_str_invalid() {
+        for value in ["", "-", "+", ".", "-.", "1.2.3", "--1", " 1", "1e5", "0x10", "1,5"] {
+            assert_eq!(value.parse::<Decimal>(), Err(DecimalError::Invalid(value.to_string())), "{}", value);
+        }
+    }
+
+    #[test]
+    fn from_str_more_than_max_scale_decimals() {
+        // Trailing zeros beyond MAX_SCALE are dropped, other digits don't fit
+        let value = decimal("1.00000000000000000000");
+        assert_eq!(value.scale(), MAX_SCALE);
+        assert_eq!(value, Decimal::ONE);
+        assert_eq!("0.1234567890123456789".parse::<Decimal>(), Err(DecimalError::Overflow));
+        assert_eq!(decimal("0.000000000000000001"), Decimal::new(1, MAX_SCALE));
+    }
+
+    #[test]
+    fn from_str_mantissa_limits() {
+        assert_eq!(decimal("-9223372036854775808").mantissa(), i64::MIN);
+        assert_eq!(decimal("9223372036854775807").mantissa(), i64::MAX);
+        assert_eq!(decimal("-922337203685477580.8"), Decimal::new(i64::MIN, 1));
+        assert_eq!("9223372036854775808".parse::<Decimal>(), Err(DecimalError::Overflow));
+        assert_eq!("-9223372036854775809".parse::<Decimal>(), Err(DecimalError::Overflow));
+        assert_eq!("100000000000000000000".parse::<Decimal>(), Err(DecimalError::Overflow));
+    }
+
+    #[test]
+    fn display_keeps_scale() {
+        assert_eq!(Decimal::new(5, 3).to_string(), "0.005");
+        assert_eq!(Decimal::new(-5, 3).to_string(), "-0.005");
+        assert_eq!(Decimal::new(1230, 2).to_string(), "12.30");
+        assert_eq!(Decimal::new(i64::MIN, 0).to_string(), "-9223372036854775808");
+    }
+
+    #[test]
+    fn checked_mul_drops_trailing_zeros_beyond_max_scale() {
+        // 1e-9 * 1.0e-9 needs 19 decimals with the trailing zero, 18 without
+        let product = decimal("0.000000001").checked_mul(decimal("0.0000000010")).unwrap();
+        assert_eq!(product, Decimal::new(1, 18));
+        assert_eq!(product.scale(), MAX_SCALE);
+        assert_eq!(decimal("0.0000000001").checked_mul(decimal("0.00000000010")), Err(DecimalError::Overflow));
+    }
+
+    #[test]
+    fn checked_mul_drops_trailing_zeros_to_fit_the_mantissa() {
+        let value = decimal("30000.00000000").checked_mul(decimal("1.00000000")).unwrap();
+        assert_eq!(value, decimal("30000"));
+        assert_eq!(decimal("0.5").checked_mul(decimal("-0.25")).unwrap().to_string(), "-0.125");
+        assert_eq!(Decimal::from(i64::MAX).checked_mul(decimal("2")), Err(DecimalError::Overflow));
+    }
+
+    #[test]
+    fn checked_add_and_sub_align_scales() {
+        assert_eq!(decimal("0.1").checked_add(decimal("0.02")).unwrap().to_string(), "0.12");
+        assert_eq!(decimal("1").checked_sub(decimal("1.5")).unwrap().to_string(), "-0.5");
+        assert_eq!(Decimal::from(i64::MAX).checked_add(Decimal::ONE), Err(DecimalError::Overflow));
+    }
+
+    #[test]
+    fn equal_values_of_different_scales_hash_the_same() {
+        let pairs = [("0.1", "0.10"), ("1", "1.000"), ("-0.5", "-0.50000"), ("0", "0.00"), ("100", "100.0")];
+        for (a, b) in pairs {
+            let (a, b) = (decimal(a), decimal(b));
+            assert_eq!(a, b);
+            assert_eq!(a.cmp(&b), Ordering::Equal);
+            assert_eq!(hash(a), hash(b));
+        }
+        let set: HashSet<Decimal> = ["0.1", "0.10", "0.100", "0.2"].into_iter().map(decimal).collect();
+        assert_eq!(set.len(), 2);
+        assert_ne!(decimal("0.1"), decimal("0.01"));
+        assert!(decimal("-1") < decimal("-0.5"));
+    }
+
+    #[test]
+    fn from_f64_is_shortest() {
+        assert_eq!(Decimal::from_f64(0.1).unwrap().to_string(), "0.1");
+        assert_eq!(Decimal::from_f64(0.0000001).unwrap(), Decimal::new(1, 7));
+        assert!(Decimal::from_f64(f64::NAN).is_err());
+        assert!(Decimal::from_f64(f64::INFINITY).is_err());
+    }
+
+    #[test]
+    fn deserializes_strings_and_numbers() {
+        assert_eq!(serde_json::from_str::<Decimal>("\"0.010\"").unwrap().to_string(), "0.010");
+        assert_eq!(serde_json::from_str::<Decimal>("0.01").unwrap(), decimal("0.01"));
+        assert_eq!(serde_json::from_str::<Decimal>("5").unwrap(), Decimal::from(5));
+        assert!(serde_json::from_str::<Decimal>("18446744073709551615").is_err());
+        assert_eq!(serde_json::to_string(&decimal("1.50")).unwrap(), "\"1.50\"");
+    }
+}
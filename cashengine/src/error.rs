@@ -0,0 +1,78 @@
+use std::any::Any;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum EngineError {
+    // Request to the exchange REST API failed
+    Rest { url: String, message: String },
+    // Response body could not be parsed
+    Parse { what: &'static str, error: serde_json::Error },
+    // Exchange returned an err-code/err-msg or no data
+    Exchange { code: String, msg: String },
+    // Creating, resizing or mapping the SHM file failed
+    Shm { path: String, error: std::io::Error },
+    // Core ids could not be determined or are insufficient
+    Affinity(String),
+    // Websocket connection could not be established
+    WebSocket { url: String, error: Box<tungstenite::Error> },
+    // Recording directory or file could not be created
+    Recording { path: String, error: std::io::Error },
+    // Recording files to replay could not be read
+    Replay { path: String, error: std::io::Error },
+    // Feeds could not be set up or polled by a multiplexing feed thread
+    EventLoop(std::io::Error),
+    // The metrics endpoint could not listen on its address
+    Metrics { address: String, error: std::io::Error },
+    // A feed, replay or reader thread panicked
+    Panic { thread: String, message: String },
+}
+
+impl EngineError {
+    pub(crate) fn exchange(code: Option<&String>, msg: Option<&String>) -> Self {
+        EngineError::Exchange {
+            code: code.cloned().unwrap_or_default(),
+            msg: msg.cloned().unwrap_or_default(),
+        }
+    }
+
+    // From the payload of a joined thread, panic! with a message passes a &str or String
+    pub(crate) fn panic(thread: &str, payload: Box<dyn Any + Send>) -> Self {
+        let message = payload.downcast_ref::<&str>().map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        EngineError::Panic { thread: thread.to_string(), message }
+    }
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineError::Rest { url, message } => write!(f, "Failed to request {}: {}", url, message),
+            EngineError::Parse { what, error } => write!(f, "Failed to parse {}: {}", what, error),
+            EngineError::Exchange { code, msg } => write!(f, "Exchange error: {}: {}", code, msg),
+            EngineError::Shm { path, error } => write!(f, "SHM file {} error: {}", path, error),
+            EngineError::Affinity(message) => write!(f, "CPU affinity error: {}", message),
+            EngineError::WebSocket { url, error } => write!(f, "Failed to connect websocket url {}: {}", url, error),
+            EngineError::Recording { path, error } => write!(f, "Failed to record into {}: {}", path, error),
+            EngineError::Replay { path, error } => write!(f, "Failed to replay {}: {}", path, error),
+            EngineError::EventLoop(error) => write!(f, "Feed event loop failed: {}", error),
+            EngineError::Metrics { address, error } => write!(f, "Failed to serve metrics on {}: {}", address, error),
+            EngineError::Panic { thread, message } => write!(f, "The {} thread panicked: {}", thread, message),
+        }
+    }
+}
+
+impl std::error::Error for EngineError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EngineError::Parse { error, .. } => Some(error),
+            EngineError::Shm { error, .. } => Some(error),
+            EngineError::WebSocket { error, .. } => Some(error.as_ref()),
+            EngineError::Recording { error, .. } => Some(error),
+            EngineError::Replay { error, .. } => Some(error),
+            EngineError::EventLoop(error) => Some(error),
+            EngineError::Metrics { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
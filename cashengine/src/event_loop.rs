@@ -0,0 +1,197 @@
+use crate::error::EngineError;
+use crate::feed_handler::FeedHandler;
+use crate::feed_source::FeedSource;
+use crate::metrics::FeedLatency;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::time::{Duration, Instant};
+
+// Longest wait for events, bounds the delay of refresher commands on idle connections
+const MAX_WAIT: Duration = Duration::from_millis(100);
+
+// Multiplexes the websocket connections of one feed thread on epoll. Connections are level triggered,
+// so a connection left with buffered messages after its share of a round is polled again without waiting.
+// Reconnect handshakes still block the whole thread, paced subscribe requests are sent once due.
+pub(crate) struct EventLoop {
+    id: usize,
+    epoll: OwnedFd,
+    // Per feed in registration order: the registered fd, None while it waits for a reconnect
+    registered: Vec<Option<RawFd>>,
+    // Per feed: reconnects when it was registered, a change means a new socket
+    reconnects: Vec<u64>,
+}
+
+struct FeedState {
+    // Reported by epoll in the last wait
+    ready: bool,
+    // false if the last poll stopped with messages left
+    drained: bool,
+}
+
+impl EventLoop {
+    pub(crate) fn new(id: usize) -> Result<EventLoop, EngineError> {
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return Err(EngineError::EventLoop(io::Error::last_os_error()));
+        }
+        // Owned from here, closed on drop
+        let epoll = unsafe { OwnedFd::from_raw_fd(fd) };
+        Ok(EventLoop { id, epoll, registered: Vec::new(), reconnects: Vec::new() })
+    }
+
+    // Waits for the connected source, the feeds passed to `run` must be in the same order
+    pub(crate) fn register<S: FeedSource + ?Sized>(&mut self, source: &S) -> Result<(), EngineError> {
+        let fd = source.raw_fd().ok_or_else(|| EngineError::EventLoop(
+            io::Error::new(io::ErrorKind::NotConnected, format!("{} has no connection to wait on", source.name()))))?;
+        let token = self.registered.len();
+        self.add(fd, token).map_err(EngineError::EventLoop)?;
+        self.registered.push(Some(fd));
+        self.reconnects.push(source.health().reconnects);
+        Ok(())
+    }
+
+    // Switches the sources to non-blocking reads, after they sent their first subscribe request while blocking as after reconnects.
+    // Only returns if a source can't be switched or waiting for events fails.
+    pub(crate) fn run(mut self, feeds: &mut [(FeedHandler<'_>, Box<dyn FeedSource + Send>)], latency: &mut FeedLatency) -> Result<(), EngineError> {
+        assert_eq!(feeds.len(), self.registered.len(), "every feed of event loop {} must be registered", self.id);
+        for (_, source) in feeds.iter_mut() {
+            source.set_nonblocking()?;
+        }
+        tracing::info!("Event loop {} polling {} connections", self.id, feeds.len());
+        let mut events = vec![libc::epoll_event { events: 0, u64: 0 }; feeds.len()];
+        let mut states: Vec<FeedState> = feeds.iter().map(|_| FeedState { ready: false, drained: true }).collect();
+        let mut requests = Vec::new();
+        loop {
+            let timeout = if states.iter().all(|state| state.drained) {
+                let now = Instant::now();
+                feeds.iter()
+                    .flat_map(|(_, source)| [source.reconnect_due(), source.requests_due()])
+                    .flatten()
+                    .min()
+                    .map_or(MAX_WAIT, |due| due.saturating_duration_since(now).min(MAX_WAIT))
+            } else {
+                Duration::ZERO
+            };
+            let count = self.wait(&mut events, timeout).map_err(|e| EngineError::EventLoop(
+                io::Error::new(e.kind(), format!("event loop {} failed waiting for events: {}", self.id, e))))?;
+            for event in &events[..count] {
+                states[event.u64 as usize].ready = true;
+            }
+
+            let now = Instant::now();
+            for (index, ((feed_handler, source), state)) in feeds.iter_mut().zip(states.iter_mut()).enumerate() {
+                let due = [source.reconnect_due(), source.requests_due()].into_iter().flatten().any(|due| due <= now);
+                if state.ready || !state.drained || due {
+                    state.ready = false;
+                    state.drained = feed_handler.poll(source.as_mut(), &mut requests, latency);
+                    self.update(index, source.as_ref());
+                }
+                // Commands don't wait for the next message of idle connections as in blocking mode
+                feed_handler.apply_commands(source.as_mut());
+            }
+        }
+    }
+
+    // A disconnected socket stays readable until it is replaced, it is removed so it doesn't wake the loop
+    // until the reconnect is due, and the new socket is added once the source reconnected
+    fn update(&mut self, index: usize, source: &dyn FeedSource) {
+        let reconnects = source.health().reconnects;
+        if source.reconnect_due().is_some() {
+            if let Some(fd) = self.registered[index].take() {
+                if let Err(e) = self.ctl(libc::EPOLL_CTL_DEL, fd, index) {
+                    tracing::warn!("Event loop {} failed removing connection of {}: {}", self.id, source.name(), e);
+                }
+            }
+        } else if self.registered[index].is_none() || self.reconnects[index] != reconnects {
+            // The replaced socket was closed, which removed it from the epoll set
+            let Some(fd) = source.raw_fd() else {
+                return;
+            };
+            match self.add(fd, index) {
+                Ok(()) => {
+                    tracing::debug!("Event loop {} waits on connection {} of {}", self.id, fd, source.name());
+                    self.registered[index] = Some(fd);
+                    self.reconnects[index] = reconnects;
+                }
+                Err(e) => tracing::error!("Event loop {} failed adding connection of {}: {}", self.id, source.name(), e),
+            }
+        }
+    }
+
+    fn add(&self, fd: RawFd, token: usize) -> io::Result<()> {
+        self.ctl(libc::EPOLL_CTL_ADD, fd, token)
+    }
+
+    fn ctl(&self, op: libc::c_int, fd: RawFd, token: usize) -> io::Result<()> {
+        let mut event = libc::epoll_event { events: libc::EPOLLIN as u32, u64: token as u64 };
+        if unsafe { libc::epoll_ctl(self.epoll.as_raw_fd(), op, fd, &mut event) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // Number of events written into `events`, 0 on timeout or when interrupted by a signal
+    fn wait(&self, events: &mut [libc::epoll_event], timeout: Duration) -> io::Result<usize> {
+        // Rounded up, so a wait for a due reconnect doesn't wake up just before it
+        let timeout_ms = timeout.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32;
+        let count = unsafe { libc::epoll_wait(self.epoll.as_raw_fd(), events.as_mut_ptr(), events.len() as i32, timeout_ms) };
+        if count < 0 {
+            let error = io::Error::last_os_error();
+            return match error.kind() {
+                io::ErrorKind::Interrupted => Ok(0),
+                _ => Err(error),
+            };
+        }
+        Ok(count as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::Channel;
+    use crate::config::LatencyConfig;
+    use crate::exchange::Exchange;
+    use crate::feed_source::{FeedHealth, FeedMessage};
+    use std::os::unix::net::UnixStream;
+
+    // Pollable, but keeps the default `set_nonblocking` that refuses non-blocking reads
+    struct BlockingSource(UnixStream);
+
+    impl FeedSource for BlockingSource {
+        fn name(&self) -> &str {
+            "blocking"
+        }
+        fn connect(&mut self) -> Result<(), EngineError> {
+            Ok(())
+        }
+        fn subscribe(&mut self, _: &[&str], _: &[Channel]) {}
+        fn add_markets(&mut self, _: &[&str], _: &[Channel]) {}
+        fn remove_markets(&mut self, _: &[&str], _: &[Channel]) {}
+        fn next_message(&mut self) -> Option<FeedMessage<'_>> {
+            None
+        }
+        fn send(&mut self, _: &str) {}
+        fn health(&self) -> FeedHealth {
+            FeedHealth::default()
+        }
+        fn raw_fd(&self) -> Option<RawFd> {
+            Some(self.0.as_raw_fd())
+        }
+    }
+
+    #[test]
+    fn returns_an_error_if_a_source_cant_be_polled() {
+        let (socket, _peer) = UnixStream::pair().unwrap();
+        let source: Box<dyn FeedSource + Send> = Box::new(BlockingSource(socket));
+        let mut event_loop = EventLoop::new(0).unwrap();
+        event_loop.register(source.as_ref()).unwrap();
+        let mut feeds = vec![(FeedHandler::new(0, Exchange::Htx, &[], Vec::new(), &[], 0, None), source)];
+        let mut latency = FeedLatency::new(0, &LatencyConfig::default());
+
+        match event_loop.run(&mut feeds, &mut latency) {
+            Err(EngineError::EventLoop(error)) => assert_eq!(error.kind(), io::ErrorKind::Unsupported),
+            other => panic!("expected an event loop error, got {:?}", other),
+        }
+    }
+}
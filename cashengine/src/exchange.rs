@@ -0,0 +1,40 @@
+use serde::Deserialize;
+use std::fmt;
+use std::str::FromStr;
+
+// Exchange a feed connects to. Selects the REST model, the websocket protocol and the message parsers.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Exchange {
+    Htx,
+    Binance,
+}
+
+impl fmt::Display for Exchange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Exchange::Htx => write!(f, "htx"),
+            Exchange::Binance => write!(f, "binance"),
+        }
+    }
+}
+
+impl FromStr for Exchange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "htx" => Ok(Exchange::Htx),
+            "binance" => Ok(Exchange::Binance),
+            _ => Err(format!("unknown exchange '{}', expected htx or binance", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for Exchange {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
@@ -0,0 +1,226 @@
+use crate::config::MetricsConfig;
+use crate::error::EngineError;
+use crate::exchange::Exchange;
+use crate::metrics::{ConnectionMetrics, Counter, ExportedHistogram, Gauge, Stage};
+use crate::shm_reader::SharedMemoryReader;
+use std::fmt::Write as _;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Serves the metrics in the Prometheus text format on GET /metrics, from its own unpinned thread.
+// The feed and reader threads only bump relaxed atomics, the exporter reads them when scraped.
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+// Scrapes are small GET requests, anything larger is rejected
+const MAX_REQUEST_SIZE: usize = 8192;
+// A stalled client must not block the next scrape for long
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Name, type, help text and value of the metrics of every websocket connection
+type ConnectionMetric = (&'static str, &'static str, &'static str, fn(&ConnectionMetrics) -> u64);
+
+const CONNECTION_METRICS: [ConnectionMetric; 7] = [
+    ("cashengine_feed_messages_total", "counter", "Messages received by the websocket connection",
+     |connection| connection.messages.get()),
+    ("cashengine_feed_reconnects_total", "counter", "Reconnects of the websocket connection",
+     |connection| connection.reconnects.get()),
+    ("cashengine_feed_inflate_errors_total", "counter", "Binary messages that failed to inflate",
+     |connection| connection.inflate_errors.get()),
+    ("cashengine_feed_pings_total", "counter", "Pings received from the exchange",
+     |connection| connection.pings.get()),
+    ("cashengine_feed_pongs_total", "counter", "Pongs sent to the exchange",
+     |connection| connection.pongs.get()),
+    ("cashengine_feed_connected", "gauge", "1 while the websocket is connected",
+     |connection| connection.connected.get()),
+    ("cashengine_feed_max_message_bytes", "gauge", "Largest inflated message received in bytes",
+     |connection| connection.max_message_size.get()),
+];
+
+// Metrics of the feeds and feed threads, registered before the threads are spawned
+pub(crate) struct MetricsRegistry {
+    feeds: Vec<FeedMetrics>,
+    // Per feed thread, indexed by `Stage as usize`
+    latency: Vec<[Arc<ExportedHistogram>; Stage::ALL.len()]>,
+}
+
+// Metrics of one websocket connection and its markets
+pub(crate) struct FeedMetrics {
+    id: usize,
+    exchange: Exchange,
+    // Global chunk index of the first market, names are looked up in the market directory of the exchange
+    markets_start_index: usize,
+    connection: Option<Arc<ConnectionMetrics>>,
+    pub(crate) subscribed_markets: Gauge,
+    pub(crate) unknown_channel_messages: Counter,
+    // Per chunk of the feed
+    pub(crate) market_messages: Box<[Counter]>,
+}
+
+impl MetricsRegistry {
+    pub(crate) fn new(thread_count: usize) -> MetricsRegistry {
+        MetricsRegistry {
+            feeds: Vec::new(),
+            latency: (0..thread_count).map(|_| Stage::ALL.map(|_| Arc::new(ExportedHistogram::default()))).collect(),
+        }
+    }
+
+    // Feeds are looked up by their id, so they are added in id order
+    pub(crate) fn add_feed(
+        &mut self,
+        exchange: Exchange,
+        markets_start_index: usize,
+        markets_per_websocket: usize,
+        connection: Option<Arc<ConnectionMetrics>>,
+    ) {
+        self.feeds.push(FeedMetrics {
+            id: self.feeds.len(),
+            exchange,
+            markets_start_index,
+            connection,
+            subscribed_markets: Gauge::default(),
+            unknown_channel_messages: Counter::new(),
+            market_messages: (0..markets_per_websocket).map(|_| Counter::new()).collect(),
+        });
+    }
+
+    pub(crate) fn feed(&self, id: usize) -> &FeedMetrics {
+        &self.feeds[id]
+    }
+
+    pub(crate) fn latency(&self, thread_id: usize, stage: Stage) -> Arc<ExportedHistogram> {
+        self.latency[thread_id][stage as usize].clone()
+    }
+
+    // `directories` hold a reader of every exchange for the market names
+    fn render(&self, directories: &[(Exchange, SharedMemoryReader)]) -> String {
+        let mut out = String::new();
+        for (name, kind, help, value) in CONNECTION_METRICS {
+            family(&mut out, name, kind, help);
+            for feed in &self.feeds {
+                if let Some(connection) = &feed.connection {
+                    let _ = writeln!(out, "{}{{{}}} {}", name, feed.labels(), value(connection));
+                }
+            }
+        }
+
+        family(&mut out, "cashengine_feed_subscribed_markets", "gauge", "Markets subscribed on the websocket connection");
+        for feed in &self.feeds {
+            let _ = writeln!(out, "cashengine_feed_subscribed_markets{{{}}} {}", feed.labels(), feed.subscribed_markets.get());
+        }
+
+        family(&mut out, "cashengine_feed_unknown_channel_messages_total", "counter", "Messages of channels that were not subscribed");
+        for feed in &self.feeds {
+            let _ = writeln!(out, "cashengine_feed_unknown_channel_messages_total{{{}}} {}", feed.labels(), feed.unknown_channel_messages.get());
+        }
+
+        family(&mut out, "cashengine_market_messages_total", "counter", "Market data messages routed per market");
+        for feed in &self.feeds {
+            let directory = directories.iter().find(|(exchange, _)| *exchange == feed.exchange).map(|(_, reader)| reader);
+            for (index, messages) in feed.market_messages.iter().enumerate() {
+                let entry = directory.and_then(|reader| reader.market(feed.markets_start_index + index));
+                // Spare chunks, removed markets and torn entries have no name
+                let Some(entry) = entry.filter(|entry| !entry.is_empty()) else {
+                    continue;
+                };
+                let _ = writeln!(out, "cashengine_market_messages_total{{{},market=\"{}\"}} {}",
+                                 feed.labels(), escape(entry.symbol()), messages.get());
+            }
+        }
+
+        family(&mut out, "cashengine_latency_seconds", "histogram", "Latency of the message stages per feed thread");
+        for (thread_id, histograms) in self.latency.iter().enumerate() {
+            for (stage, histogram) in Stage::ALL.iter().zip(histograms) {
+                let labels = format!("thread=\"{}\",stage=\"{}\"", thread_id, stage.label());
+                let counts = histogram.cumulative_counts();
+                for (bound, count) in &counts {
+                    let bound = bound.map_or("+Inf".to_string(), |nanos| seconds(nanos).to_string());
+                    let _ = writeln!(out, "cashengine_latency_seconds_bucket{{{},le=\"{}\"}} {}", labels, bound, count);
+                }
+                let _ = writeln!(out, "cashengine_latency_seconds_sum{{{}}} {}", labels, seconds(histogram.sum_nanos()));
+                let _ = writeln!(out, "cashengine_latency_seconds_count{{{}}} {}", labels, counts.last().map_or(0, |(_, count)| *count));
+            }
+        }
+        out
+    }
+}
+
+impl FeedMetrics {
+    fn labels(&self) -> String {
+        format!("exchange=\"{}\",feed=\"{}\"", self.exchange, self.id)
+    }
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn seconds(nanos: u64) -> f64 {
+    nanos as f64 / 1_000_000_000.0
+}
+
+// Label values escape backslashes, quotes and line feeds
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// Bound before the threads are spawned, so an address in use fails the startup
+pub(crate) fn bind(config: &MetricsConfig) -> Result<TcpListener, EngineError> {
+    let listener = TcpListener::bind(&config.listen_address)
+        .map_err(|error| EngineError::Metrics { address: config.listen_address.clone(), error })?;
+    tracing::info!("Serving metrics on http://{}/metrics", config.listen_address);
+    Ok(listener)
+}
+
+// Answers one scrape at a time until the process exits
+pub(crate) fn run(listener: TcpListener, registry: &MetricsRegistry, directories: Vec<(Exchange, SharedMemoryReader)>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if let Err(e) = respond(stream, registry, &directories) {
+                    tracing::debug!("Failed answering metrics request: {}", e);
+                }
+            }
+            Err(e) => tracing::warn!("Failed accepting metrics connection: {}", e),
+        }
+    }
+}
+
+fn respond(mut stream: TcpStream, registry: &MetricsRegistry, directories: &[(Exchange, SharedMemoryReader)]) -> io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+        if request.len() > MAX_REQUEST_SIZE {
+            return write_response(&mut stream, "413 Payload Too Large", "text/plain", "Request too large\n");
+        }
+    }
+
+    // e.g. GET /metrics HTTP/1.1
+    let request_line = String::from_utf8_lossy(request.split(|c| *c == b'\r').next().unwrap_or_default()).to_string();
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+    let path = target.split('?').next().unwrap_or_default();
+    match (method, path) {
+        ("GET", "/metrics") => write_response(&mut stream, "200 OK", CONTENT_TYPE, &registry.render(directories)),
+        ("GET", _) => write_response(&mut stream, "404 Not Found", "text/plain", "Metrics are served on /metrics\n"),
+        _ => write_response(&mut stream, "405 Method Not Allowed", "text/plain", "Only GET is supported\n"),
+    }
+}
+
+fn write_response(stream: &mut TcpStream, status: &str, content_type: &str, body: &str) -> io::Result<()> {
+    let header = format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                         status, content_type, body.len());
+    stream.write_all(header.as_bytes())?;
+    stream.write_all(body.as_bytes())?;
+    stream.flush()
+}
@@ -0,0 +1,243 @@
+use crate::channel::Channel;
+use crate::exchange::Exchange;
+use crate::exporter::FeedMetrics;
+use crate::feed_source::{FeedMessage, FeedSource};
+use crate::metrics::FeedLatency;
+use crate::order_book::OrderBooks;
+use crate::recorder::Recorder;
+use crate::shm_block_writer::SharedMemoryWriter;
+use std::collections::HashMap;
+use std::sync::mpsc::Receiver;
+
+// Messages routed per connection before the event loop moves on to the next ready one
+const MAX_MESSAGES_PER_POLL: usize = 64;
+
+// Changes of the subscribed markets while running, sent by the reference data refresher
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum FeedCommand {
+    // Subscribes `market` into chunk `index` of the feed
+    Subscribe { market: String, index: usize },
+    Unsubscribe { market: String },
+}
+
+// Routes the messages of a `FeedSource` to the SHM writer of their channel.
+// Used by the live feed threads and by the replay of recorded feeds.
+pub(crate) struct FeedHandler<'a> {
+    id: usize,
+    exchange: Exchange,
+    channels: &'a [Channel],
+    // Same order as `channels`
+    channel_names: Vec<String>,
+    shm_writers: Vec<SharedMemoryWriter<'a>>,
+    order_books: Vec<Option<OrderBooks>>,
+    // Market to chunk index of this feed
+    indexed_markets: HashMap<String, usize>,
+    // Global chunk index of the first market of this feed
+    markets_start_index: usize,
+    recorder: Option<Recorder>,
+    commands: Option<Receiver<FeedCommand>>,
+    metrics: Option<&'a FeedMetrics>,
+    // Messages of channels that were not subscribed, the channel name comes from the exchange
+    unknown_channel_messages: u64,
+}
+
+impl<'a> FeedHandler<'a> {
+    pub(crate) fn new(
+        id: usize,
+        exchange: Exchange,
+        channels: &'a [Channel],
+        shm_writers: Vec<SharedMemoryWriter<'a>>,
+        markets: &[&str],
+        markets_start_index: usize,
+        recorder: Option<Recorder>,
+    ) -> FeedHandler<'a> {
+        let order_books = channels.iter().map(|channel| match channel {
+            Channel::Mbp(levels) => {
+                let topics = markets.iter().map(|market| channel.topic(market)).collect();
+                Some(OrderBooks::new(topics, *levels))
+            }
+            _ => None,
+        }).collect();
+        FeedHandler {
+            id,
+            exchange,
+            channels,
+            channel_names: channels.iter().map(|channel| channel.to_string()).collect(),
+            shm_writers,
+            order_books,
+            indexed_markets: markets.iter().enumerate().map(|(index, market)| (market.to_string(), index)).collect(),
+            markets_start_index,
+            recorder,
+            commands: None,
+            metrics: None,
+            unknown_channel_messages: 0,
+        }
+    }
+
+    // Commands are applied between messages, so in blocking mode they wait for the next message of the source
+    pub(crate) fn set_commands(&mut self, commands: Receiver<FeedCommand>) {
+        self.commands = Some(commands);
+    }
+
+    // Counts routed messages per market for the metrics exporter
+    pub(crate) fn set_metrics(&mut self, metrics: &'a FeedMetrics) {
+        metrics.subscribed_markets.set(self.indexed_markets.len() as u64);
+        self.metrics = Some(metrics);
+    }
+
+    // Routes messages until the source is exhausted and sends the requests of the order books back to it
+    pub(crate) fn run<S: FeedSource + ?Sized>(&mut self, source: &mut S, latency: &mut FeedLatency) {
+        let mut requests = Vec::new();
+        while let Some(message) = source.next_message() {
+            self.on_message(message, &mut requests, latency);
+            for request in requests.drain(..) {
+                source.send(&request);
+            }
+            self.apply_commands(source);
+        }
+        tracing::info!("Feed {} from {} ended after {} messages", self.id, source.name(), source.health().messages);
+    }
+
+    // Non-blocking variant of `run` for the event loop: routes up to `MAX_MESSAGES_PER_POLL` buffered
+    // messages, so one busy connection can't starve the others on the thread.
+    // Returns false if messages may be left, then the source has to be polled again without waiting.
+    pub(crate) fn poll<S: FeedSource + ?Sized>(&mut self, source: &mut S, requests: &mut Vec<String>, latency: &mut FeedLatency) -> bool {
+        for _ in 0..MAX_MESSAGES_PER_POLL {
+            let Some(message) = source.try_next_message() else {
+                return true;
+            };
+            self.on_message(message, requests, latency);
+            for request in requests.drain(..) {
+                source.send(&request);
+            }
+        }
+        false
+    }
+
+    pub(crate) fn apply_commands<S: FeedSource + ?Sized>(&mut self, source: &mut S) {
+        if let Some(commands) = self.commands.take() {
+            for command in commands.try_iter() {
+                self.on_command(command, source);
+            }
+            self.commands = Some(commands);
+        }
+    }
+
+    pub(crate) fn on_command<S: FeedSource + ?Sized>(&mut self, command: FeedCommand, source: &mut S) {
+        self.apply_command(command, source);
+        if let Some(metrics) = self.metrics {
+            metrics.subscribed_markets.set(self.indexed_markets.len() as u64);
+        }
+    }
+
+    fn apply_command<S: FeedSource + ?Sized>(&mut self, command: FeedCommand, source: &mut S) {
+        match command {
+            FeedCommand::Subscribe { market, index } => {
+                tracing::info!("Feed {} subscribes {} into chunk {}", self.id, market, self.markets_start_index + index);
+                for (channel, books) in self.channels.iter().zip(self.order_books.iter_mut()) {
+                    if let Some(books) = books {
+                        books.add(index, channel.topic(&market));
+                    }
+                }
+                source.add_markets(&[&market], self.channels);
+                self.indexed_markets.insert(market, index);
+            }
+            FeedCommand::Unsubscribe { market } => {
+                let Some(index) = self.indexed_markets.remove(&market) else {
+                    tracing::warn!("Feed {} can't unsubscribe {}, it is not subscribed", self.id, market);
+                    return;
+                };
+                tracing::info!("Feed {} unsubscribes {} from chunk {}", self.id, market, self.markets_start_index + index);
+                source.remove_markets(&[&market], self.channels);
+                for books in self.order_books.iter_mut().flatten() {
+                    books.remove(index);
+                }
+            }
+        }
+    }
+
+    // Requests to the exchange, such as order book snapshots, are pushed into `requests`.
+    // Messages written into SHM are recorded in `latency` of the feed thread.
+    pub(crate) fn on_message(&mut self, message: FeedMessage, requests: &mut Vec<String>, latency: &mut FeedLatency) {
+        let id = self.id;
+        if let Some(recorder) = &self.recorder {
+            recorder.record(id, message.raw(), message.times().received_epoch_nanos);
+        }
+
+        let FeedMessage::Market { market, channel: channel_name, message, times } = message else {
+            return;
+        };
+        // Messages of unsubscribed markets can still be in flight
+        let Some(index) = self.indexed_markets.get(market) else {
+            tracing::warn!("Ignoring message of unsubscribed market {} from websocket {}, message: {}",
+                           market, id, String::from_utf8_lossy(message));
+            return;
+        };
+        let Some(channel_index) = self.channel_names.iter().position(|name| name.as_bytes() == channel_name) else {
+            self.unknown_channel_messages += 1;
+            if let Some(metrics) = self.metrics {
+                metrics.unknown_channel_messages.increment();
+            }
+            tracing::warn!("Ignoring message of unknown channel {} from websocket {}, total: {}, message: {}",
+                           String::from_utf8_lossy(channel_name), id, self.unknown_channel_messages, String::from_utf8_lossy(message));
+            return;
+        };
+        if let Some(metrics) = self.metrics {
+            metrics.market_messages[*index].increment();
+        }
+        let symbol_index = (self.markets_start_index + *index) as u32;
+        let channel = &self.channels[channel_index];
+        let shm_writer = &mut self.shm_writers[channel_index];
+        let last_write_nanos = shm_writer.last_write_nanos();
+        let written = match &mut self.order_books[channel_index] {
+            Some(books) => books.on_message(shm_writer, *index, symbol_index, message, requests),
+            None => channel.write(self.exchange, shm_writer, *index, symbol_index, message),
+        };
+        if !written {
+            tracing::error!("Failed to parse {} for market {} from websocket {}, message: {}",
+                            channel, market, id, String::from_utf8_lossy(message));
+        }
+        // Order books don't write while they wait for a snapshot
+        if shm_writer.last_write_nanos() != last_write_nanos {
+            latency.record(&times, shm_writer.last_write_nanos());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bbo::Bbo;
+    use crate::config::LatencyConfig;
+    use crate::feed_source::MessageTimes;
+    use crate::shm_chunk;
+    use crate::shm_file::{self, FileHeader};
+    use crate::shm_reader::SharedMemoryReader;
+
+    #[test]
+    fn ignores_messages_of_unknown_channels() {
+        let path = std::env::temp_dir().join(format!("cashengine-feed-handler-{}.mmap", std::process::id()));
+        let file = shm_file::create(path.to_str().unwrap(), &FileHeader::new(shm_chunk::chunk_size_for::<Bbo>(), 1, 1)).unwrap();
+        let writer = SharedMemoryWriter::create(&file, 0).unwrap();
+        let channels = [Channel::Bbo];
+        let mut feed_handler = FeedHandler::new(0, Exchange::Htx, &channels, vec![writer], &["btcusdt"], 0, None);
+        let mut latency = FeedLatency::new(0, &LatencyConfig::default());
+        let mut requests = Vec::new();
+
+        let kline = br#"{"ch":"market.btcusdt.kline.1min","ts":1,"tick":{"id":1,"open":1.0,"close":1.0,"low":1.0,"high":1.0,"amount":1.0,"vol":1.0,"count":1}}"#;
+        for _ in 0..2 {
+            let message = FeedMessage::Market { market: "btcusdt", channel: b"kline.1min", message: kline, times: MessageTimes::default() };
+            feed_handler.on_message(message, &mut requests, &mut latency);
+        }
+        assert_eq!(feed_handler.unknown_channel_messages, 2);
+        let mut reader = SharedMemoryReader::attach(&file).unwrap();
+        assert!(reader.read_chunk(0).is_none());
+
+        let bbo = br#"{"ch":"market.btcusdt.bbo","ts":1,"tick":{"seqId":5,"ask":2.0,"askSize":1.0,"bid":1.0,"bidSize":3.0,"quoteTime":1,"symbol":"btcusdt"}}"#;
+        let message = FeedMessage::Market { market: "btcusdt", channel: b"bbo", message: bbo, times: MessageTimes::default() };
+        feed_handler.on_message(message, &mut requests, &mut latency);
+        assert_eq!(feed_handler.unknown_channel_messages, 2);
+        assert_eq!(reader.read_chunk(0).unwrap().bbo().unwrap().seq_id, 5);
+        std::fs::remove_file(path).unwrap();
+    }
+}
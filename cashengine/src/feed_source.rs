@@ -0,0 +1,111 @@
+use crate::channel::Channel;
+use crate::error::EngineError;
+use crate::metrics::ConnectionMetrics;
+use std::io;
+use std::os::fd::RawFd;
+use std::sync::Arc;
+use std::time::Instant;
+
+// A source of market data messages for one feed thread, such as an exchange websocket or a replayed recording.
+// The feed thread pins itself, asks the source for messages and routes them into the SHM files of their channels.
+pub trait FeedSource {
+    // Name for logs, e.g. the websocket url
+    fn name(&self) -> &str;
+
+    // Establishes the connection. Sources that reconnect on their own only call this once.
+    fn connect(&mut self) -> Result<(), EngineError>;
+
+    // Subscribes to `channels` of all `markets`. Market data arrives for these only.
+    fn subscribe(&mut self, markets: &[&str], channels: &[Channel]);
+
+    // Adds `markets` to the subscription of a running source, e.g. after a new listing.
+    // Like the initial subscription they are renewed after reconnects.
+    fn add_markets(&mut self, markets: &[&str], channels: &[Channel]);
+
+    // Removes `markets` from the subscription, messages already in flight may still arrive
+    fn remove_markets(&mut self, markets: &[&str], channels: &[Channel]);
+
+    // Blocks until the next message. None if the source is exhausted, e.g. at the end of a recording.
+    fn next_message(&mut self) -> Option<FeedMessage<'_>>;
+
+    // Sends a request to the exchange, such as an order book snapshot request
+    fn send(&mut self, request: &str);
+
+    fn health(&self) -> FeedHealth;
+
+    // Counters of the connection for the metrics exporter, None for sources without one
+    fn connection_metrics(&self) -> Option<Arc<ConnectionMetrics>> {
+        None
+    }
+
+    // Switches to non-blocking reads, for feeds sharing a thread in an event loop. Sources without
+    // a pollable connection, such as replays, keep the defaults and run in their own thread.
+    fn set_nonblocking(&mut self) -> Result<(), EngineError> {
+        Err(EngineError::EventLoop(io::Error::new(io::ErrorKind::Unsupported, format!("{} can't be polled", self.name()))))
+    }
+
+    // File descriptor to wait on for readability, it changes after reconnects
+    fn raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    // Non-blocking mode: the next buffered message, None once the connection would block
+    fn try_next_message(&mut self) -> Option<FeedMessage<'_>> {
+        None
+    }
+
+    // Non-blocking mode: when the source wants to be polled for its next reconnect attempt
+    fn reconnect_due(&self) -> Option<Instant> {
+        None
+    }
+
+    // Non-blocking mode: when the source wants to be polled to send its next paced request
+    fn requests_due(&self) -> Option<Instant> {
+        None
+    }
+}
+
+pub enum FeedMessage<'a> {
+    // Market data of `market` on the channel displayed as `channel`, e.g. "kline.1min"
+    Market { market: &'a str, channel: &'a [u8], message: &'a [u8], times: MessageTimes },
+    // Subscription replies, errors and other messages without market data
+    Control { message: &'a [u8], times: MessageTimes },
+}
+
+impl<'a> FeedMessage<'a> {
+    // The message as received, for recording
+    pub fn raw(&self) -> &'a [u8] {
+        match self {
+            FeedMessage::Market { message, .. } => message,
+            FeedMessage::Control { message, .. } => message,
+        }
+    }
+
+    pub fn times(&self) -> MessageTimes {
+        match self {
+            FeedMessage::Market { times, .. } | FeedMessage::Control { times, .. } => *times,
+        }
+    }
+}
+
+// When a message passed the stages before the SHM write, 0 where unknown, e.g. in replays
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MessageTimes {
+    // Exchange timestamp in milliseconds since UNIX epoch, HTX only
+    pub exchange_ms: u64,
+    // Nanoseconds since UNIX epoch when the frame was read, compared with the exchange timestamp
+    pub received_epoch_nanos: u64,
+    // `time_util::monotonic_nanos` when the frame was read
+    pub received_nanos: u64,
+    // `time_util::monotonic_nanos` when the message was inflated into the read buffer
+    pub inflated_nanos: u64,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FeedHealth {
+    pub connected: bool,
+    pub reconnects: u64,
+    pub messages: u64,
+    // Largest message received so far in bytes
+    pub max_message_size: usize,
+}
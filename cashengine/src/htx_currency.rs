@@ -1,3 +1,4 @@
+use crate::htx_state::HtxState;
 use serde::de::Error;
 use serde::{de, Deserialize, Serialize};
 
@@ -29,7 +30,7 @@ pub struct HtxCurrency {
     #[serde(alias = "qc", deserialize_with = "de_from_str")]
     pub be_quote_currency: Option<String>,                //     // qc	boolean	false	be quote currency
     #[serde(alias = "state")]
-    pub state: Option<String>,                // state	string	false	symbol state. unkown, not-online, online, offline
+    pub state: Option<HtxState>,                // state	string	false	symbol state. unkown, not-online, online, offline
     #[serde(alias = "v")]
     pub visible_or_not: Option<bool>,              // v	boolean	false	visible or not -- users who have offline currency but have assets can see it
     #[serde(alias = "whe")]
@@ -90,10 +91,6 @@ pub struct HtxCurrencies {
 }
 
 impl HtxCurrencies {
-    pub fn from(body: &str) -> Result<Self, serde_json::Error> {
-        serde_json::from_str(body)
-    }
-
     fn filter<F>(&self, predicate: F) -> Self
     where
         F: Fn(&HtxCurrency) -> bool,
@@ -111,8 +108,9 @@ impl HtxCurrencies {
         }
     }
 
-    pub fn with_online_currencies(&self) -> Self {
-        self.filter(|c| c.state == Some("online".to_string()))
+    // Keeps currencies in one of `states`
+    pub fn with_states(&self, states: &[HtxState]) -> Self {
+        self.filter(|c| c.state.as_ref().is_some_and(|state| states.contains(state)))
     }
 
     pub fn with_country_enabled(&self) -> Self {
@@ -122,23 +120,5 @@ impl HtxCurrencies {
     pub fn len(&self) -> usize {
         self.data.len()
     }
-
-    pub fn get_error(&self) -> Result<(), String> {
-        if self.err_code.is_some() || self.err_msg.is_some() {
-            let mut error_message = String::new();
-            if let Some(code) = &self.err_code {
-                error_message.push_str(code);
-            }
-            if let Some(msg) = &self.err_msg {
-                if !error_message.is_empty() {
-                    error_message.push_str(": ");
-                }
-                error_message.push_str(msg);
-            }
-            Err(error_message)
-        } else {
-            Ok(())
-        }
-    }
 }
 
@@ -0,0 +1,202 @@
+use crate::bbo::{find, number_after};
+use crate::channel;
+use crate::channel::Channel;
+use crate::error::EngineError;
+use crate::feed_source::{FeedHealth, FeedMessage, FeedSource, MessageTimes};
+use crate::metrics::ConnectionMetrics;
+use crate::websocket::{CeWebSocket, ReconnectPolicy};
+use std::io;
+use std::os::fd::RawFd;
+use std::sync::Arc;
+use std::time::Instant;
+
+static STATUS: &[u8] = b"status";
+static STATUS_ERROR: &[u8] = b"\"status\":\"error\"";
+static REP: &[u8] = b"\"rep\":";
+static PING: &[u8] = b"{\"ping\":";
+static TS: &[u8] = b"\"ts\":";
+static TICK: &[u8] = b"\"tick\":";
+
+// HTX market data over the gzip compressed websocket API
+pub struct HtxWebSocketFeed {
+    id: usize,
+    url: String,
+    buffer_size: usize,
+    reconnect_policy: ReconnectPolicy,
+    websocket: Option<CeWebSocket>,
+    // Currently subscribed, for the request replayed after reconnects
+    markets: Vec<String>,
+    channels: Vec<Channel>,
+}
+
+impl HtxWebSocketFeed {
+    // `buffer_size` must hold the largest inflated message of the subscribed channels
+    pub fn new(id: usize, url: &str, buffer_size: usize, reconnect_policy: ReconnectPolicy) -> HtxWebSocketFeed {
+        HtxWebSocketFeed {
+            id,
+            url: url.to_string(),
+            buffer_size,
+            reconnect_policy,
+            websocket: None,
+            markets: Vec::new(),
+            channels: Vec::new(),
+        }
+    }
+
+    // e.g. {"sub": ["market.btcusdt.bbo", ...], "id": "id0"} with `verb` sub or unsub
+    fn request<M: AsRef<str>>(&self, verb: &str, markets: &[M], channels: &[Channel]) -> String {
+        let mut request = String::new();
+        request.push_str(format!("{{\"{}\": [", verb).as_str());
+        for market in markets {
+            for channel in channels {
+                request.push_str(format!("\"{}\",", channel.topic(market.as_ref())).as_str());
+            }
+        }
+        if request.ends_with(',') {
+            request.pop(); // Remove the last comma
+        }
+        request.push_str("\n],\n\"id\": \"id");
+        request.push_str(self.id.to_string().as_str());
+        request.push_str("\"\n}");
+        request
+    }
+
+    // The full subscription is replayed after reconnects
+    fn update_replayed_request(&mut self) {
+        let requests = if self.markets.is_empty() {
+            Vec::new()
+        } else {
+            vec![self.request("sub", &self.markets, &self.channels)]
+        };
+        if let Some(websocket) = self.websocket.as_mut() {
+            websocket.set_subscribe_requests(requests);
+        }
+    }
+}
+
+impl FeedSource for HtxWebSocketFeed {
+    fn name(&self) -> &str {
+        &self.url
+    }
+
+    fn connect(&mut self) -> Result<(), EngineError> {
+        let websocket = CeWebSocket::connect(&self.url, self.buffer_size, self.reconnect_policy)
+            .map_err(|error| EngineError::WebSocket { url: self.url.clone(), error })?;
+        tracing::debug!("Connected to websocket server with id {}", self.id);
+        self.websocket = Some(websocket);
+        Ok(())
+    }
+
+    fn subscribe(&mut self, markets: &[&str], channels: &[Channel]) {
+        self.markets = markets.iter().map(|market| market.to_string()).collect();
+        self.channels = channels.to_vec();
+        // Feeds reserved for new listings start without markets
+        if markets.is_empty() {
+            tracing::info!("Websocket {} has no markets to subscribe yet", self.id);
+            return;
+        }
+        let subscribe_request = self.request("sub", markets, channels);
+
+        tracing::info!("Subscribing to symbols: {}", subscribe_request);
+        match self.websocket.as_mut() {
+            Some(websocket) => websocket.subscribe(subscribe_request.as_str()),
+            None => tracing::error!("Websocket {} is not connected, can't subscribe", self.url),
+        }
+    }
+
+    fn add_markets(&mut self, markets: &[&str], channels: &[Channel]) {
+        let request = self.request("sub", markets, channels);
+        tracing::info!("Subscribing to additional symbols: {}", request);
+        self.send(&request);
+        self.markets.extend(markets.iter().map(|market| market.to_string()));
+        self.update_replayed_request();
+    }
+
+    fn remove_markets(&mut self, markets: &[&str], channels: &[Channel]) {
+        let request = self.request("unsub", markets, channels);
+        tracing::info!("Unsubscribing from symbols: {}", request);
+        self.send(&request);
+        self.markets.retain(|market| !markets.contains(&market.as_str()));
+        self.update_replayed_request();
+    }
+
+    fn next_message(&mut self) -> Option<FeedMessage<'_>> {
+        let id = self.id;
+        let (message, times) = self.websocket.as_mut()?.next_message();
+        Some(classify(id, message, times))
+    }
+
+    fn try_next_message(&mut self) -> Option<FeedMessage<'_>> {
+        let id = self.id;
+        let (message, times) = self.websocket.as_mut()?.try_next_message()?;
+        Some(classify(id, message, times))
+    }
+
+    fn send(&mut self, request: &str) {
+        if let Some(websocket) = self.websocket.as_mut() {
+            websocket.send_message(request);
+        }
+    }
+
+    fn set_nonblocking(&mut self) -> Result<(), EngineError> {
+        match self.websocket.as_mut() {
+            Some(websocket) => websocket.set_nonblocking().map_err(EngineError::EventLoop),
+            None => Err(EngineError::EventLoop(io::Error::new(io::ErrorKind::NotConnected, format!("{} is not connected", self.url)))),
+        }
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        self.websocket.as_ref().map(CeWebSocket::raw_fd)
+    }
+
+    fn reconnect_due(&self) -> Option<Instant> {
+        self.websocket.as_ref().and_then(CeWebSocket::reconnect_due)
+    }
+
+    fn requests_due(&self) -> Option<Instant> {
+        self.websocket.as_ref().and_then(CeWebSocket::requests_due)
+    }
+
+    fn connection_metrics(&self) -> Option<Arc<ConnectionMetrics>> {
+        self.websocket.as_ref().map(|websocket| websocket.metrics().clone())
+    }
+
+    fn health(&self) -> FeedHealth {
+        match &self.websocket {
+            Some(websocket) => FeedHealth {
+                connected: websocket.reconnect_due().is_none(),
+                reconnects: websocket.reconnects(),
+                messages: websocket.messages(),
+                max_message_size: websocket.max_size(),
+            },
+            None => FeedHealth::default(),
+        }
+    }
+}
+
+// Splits an inflated HTX message into market data and control messages
+pub(crate) fn classify(id: usize, message: &[u8], times: MessageTimes) -> FeedMessage<'_> {
+    // Already answered by the websocket
+    if message.starts_with(PING) {
+        return FeedMessage::Control { message, times };
+    }
+    // Replies to subscriptions carry a status, except for order book snapshots which are replies to `req`
+    if message.windows(STATUS.len()).any(|window| window == STATUS) && find(message, REP).is_none() {
+        if find(message, STATUS_ERROR).is_some() {
+            tracing::warn!("Websocket {} received an error: {}", id, String::from_utf8_lossy(message));
+        }
+        return FeedMessage::Control { message, times };
+    }
+    match channel::split_topic(message) {
+        Some((market, channel)) => {
+            // The push time precedes the tick, which may carry timestamps of its own
+            let head = find(message, TICK).map_or(message, |tick| &message[..tick]);
+            let exchange_ms = number_after(head, TS).unwrap_or(0);
+            FeedMessage::Market { market, channel, message, times: MessageTimes { exchange_ms, ..times } }
+        }
+        None => {
+            tracing::warn!("Websocket {} received a message without market: {}", id, String::from_utf8_lossy(message));
+            FeedMessage::Control { message, times }
+        }
+    }
+}
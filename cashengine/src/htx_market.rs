@@ -1,3 +1,5 @@
+use crate::decimal::Decimal;
+use crate::htx_state::HtxState;
 use serde::{Deserialize, Serialize};
 
 pub const PATH: &str = "/v1/settings/common/market-symbols";
@@ -13,7 +15,7 @@ pub struct HtxMarket {
     #[serde(alias = "qc")]
     pub quote_currency: Option<String>,      // false	quote currency
     #[serde(alias = "state")]
-    pub state: Option<String>, // false	symbol status. unknown，not-online，pre-online，online，suspend，offline，transfer-board，fuse
+    pub state: Option<HtxState>, // false	symbol status. unknown，not-online，pre-online，online，suspend，offline，transfer-board，fuse
     #[serde(alias = "sp")]
     pub symbol_partition: Option<String>,    // false	symbol partition
     #[serde(alias = "tags")]
@@ -29,25 +31,25 @@ pub struct HtxMarket {
     #[serde(alias = "vp")]
     pub value_precision: Option<i32>,   //	integer	false	value precision
     #[serde(alias = "minoa")]
-    pub min_order_amount: Option<f64>,  // 	decimal	false	min order amount
+    pub min_order_amount: Option<Decimal>,  // 	decimal	false	min order amount
     #[serde(alias = "maxoa")]
-    pub max_order_amount: Option<f64>,  //	decimal	false	max order amount
+    pub max_order_amount: Option<Decimal>,  //	decimal	false	max order amount
     #[serde(alias = "minov")]
-    pub min_order_value: Option<f64>,   //	decimal	false	min order value
+    pub min_order_value: Option<Decimal>,   //	decimal	false	min order value
     #[serde(alias = "lominoa")]
-    pub min_amount_of_limit_price_order: Option<f64>,   //	decimal	false	min amount of limit price order
+    pub min_amount_of_limit_price_order: Option<Decimal>,   //	decimal	false	min amount of limit price order
     #[serde(alias = "lomaxoa")]
-    pub max_amount_of_limit_price_order: Option<f64>,   //	decimal	false	max amount of limit price order
+    pub max_amount_of_limit_price_order: Option<Decimal>,   //	decimal	false	max amount of limit price order
     #[serde(alias = "lomaxba")]
-    pub max_amount_of_limit_price_buy_order: Option<f64>,   // decimal false max amount of limit price buy order
+    pub max_amount_of_limit_price_buy_order: Option<Decimal>,   // decimal false max amount of limit price buy order
     #[serde(alias = "lomaxsa")]
-    pub max_amount_of_limit_price_sell_order: Option<f64>,   // decimal false max amount of limit price sell order
+    pub max_amount_of_limit_price_sell_order: Option<Decimal>,   // decimal false max amount of limit price sell order
     #[serde(alias = "smminoa")]
-    pub min_amount_of_market_price_sell_order: Option<f64>,   // decimal false min amount of market price sell order
+    pub min_amount_of_market_price_sell_order: Option<Decimal>,   // decimal false min amount of market price sell order
     #[serde(alias = "smmaxoa")]
-    pub max_amount_of_market_price_sell_order: Option<f64>,   // decimal false max amount of market price sell order
+    pub max_amount_of_market_price_sell_order: Option<Decimal>,   // decimal false max amount of market price sell order
     #[serde(alias = "bmmaxov")]
-    pub max_amount_of_market_price_buy_order: Option<f64>,   // decimal false max amount of market price buy order
+    pub max_amount_of_market_price_buy_order: Option<Decimal>,   // decimal false max amount of market price buy order
     #[serde(alias = "blmlt")]
     pub buy_limit_must_less_than: Option<f64>,   // Buy limit must less than
     #[serde(alias = "slmgt")]
@@ -71,11 +73,11 @@ pub struct HtxMarket {
     #[serde(alias = "in")]
     pub etp_init_nav: Option<f64>,   // decimal false ETP: init nav
     #[serde(alias = "maxov")]
-    pub max_value_of_market_price_order: Option<f64>,   // decimal false max value of market price order
+    pub max_value_of_market_price_order: Option<Decimal>,   // decimal false max value of market price order
     #[serde(alias = "flr")]
     pub c2c_funding_leverage_ratio: Option<f64>,   // decimal false C2C: funding leverage ratio
     #[serde(alias = "castate")]
-    pub castate: Option<String>,    //	string	false	not Required. The state of the call auction; it will only be displayed when it is in the 1st and 2nd stage of the call auction. Enumeration values: "ca_1", "ca_2"
+    pub castate: Option<HtxState>,    //	string	false	not Required. The state of the call auction; it will only be displayed when it is in the 1st and 2nd stage of the call auction. Enumeration values: "ca_1", "ca_2"
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -92,12 +94,28 @@ pub struct HtxMarkets {
     pub err_msg: Option<String>, // false	error msg(returned when the interface reports an error)
 }
 
-impl HtxMarkets {
-    // Parse symbols strong typed
-    pub fn from(body: &str) -> Result<Self, serde_json::Error> {
-        serde_json::from_str(body)
+impl HtxMarket {
+    // Price increment from `pp`, e.g. 2 -> 0.01
+    pub fn tick_size(&self) -> Option<Decimal> {
+        self.price_precision.and_then(increment)
+    }
+
+    // Amount increment in base currency from `ap`
+    pub fn lot_size(&self) -> Option<Decimal> {
+        self.amount_precision.and_then(increment)
     }
 
+    // Order value increment in quote currency from `vp`
+    pub fn value_increment(&self) -> Option<Decimal> {
+        self.value_precision.and_then(increment)
+    }
+}
+
+fn increment(precision: i32) -> Option<Decimal> {
+    u32::try_from(precision).ok().and_then(Decimal::increment)
+}
+
+impl HtxMarkets {
     fn filter<F>(&self, predicate: F) -> Self
     where
         F: Fn(&HtxMarket) -> bool,
@@ -115,29 +133,12 @@ impl HtxMarkets {
         }
     }
 
-    pub fn with_online_markets(&self) -> Self {
-        self.filter(|m| m.state == Some("online".to_string()))
+    // Keeps markets in one of `states`
+    pub fn with_states(&self, states: &[HtxState]) -> Self {
+        self.filter(|m| m.state.as_ref().is_some_and(|state| states.contains(state)))
     }
 
     pub fn len(&self) -> usize {
         self.data.len()
     }
-
-    pub fn get_error(&self) -> Result<(), String> {
-        if self.err_code.is_some() || self.err_msg.is_some() {
-            let mut error_message = String::new();
-            if let Some(code) = &self.err_code {
-                error_message.push_str(code);
-            }
-            if let Some(msg) = &self.err_msg {
-                if !error_message.is_empty() {
-                    error_message.push_str(": ");
-                }
-                error_message.push_str(msg);
-            }
-            Err(error_message)
-        } else {
-            Ok(())
-        }
-    }
 }
@@ -0,0 +1,73 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// State of an HTX symbol, market or currency (`state`) and call auction stage (`castate`).
+// Values this version doesn't know are kept as `Other`, so new states don't break deserialization.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum HtxState {
+    Unknown,
+    NotOnline,
+    PreOnline,
+    Online,
+    Suspend,
+    Offline,
+    TransferBoard,
+    Fuse,
+    // Call auction stages, `castate` only
+    CallAuction1,
+    CallAuction2,
+    Other(String),
+}
+
+impl HtxState {
+    pub fn as_str(&self) -> &str {
+        match self {
+            HtxState::Unknown => "unknown",
+            HtxState::NotOnline => "not-online",
+            HtxState::PreOnline => "pre-online",
+            HtxState::Online => "online",
+            HtxState::Suspend => "suspend",
+            HtxState::Offline => "offline",
+            HtxState::TransferBoard => "transfer-board",
+            HtxState::Fuse => "fuse",
+            HtxState::CallAuction1 => "ca_1",
+            HtxState::CallAuction2 => "ca_2",
+            HtxState::Other(state) => state,
+        }
+    }
+}
+
+impl From<String> for HtxState {
+    fn from(state: String) -> Self {
+        match state.as_str() {
+            // The currencies endpoint documents the typo
+            "unknown" | "unkown" => HtxState::Unknown,
+            "not-online" => HtxState::NotOnline,
+            "pre-online" => HtxState::PreOnline,
+            "online" => HtxState::Online,
+            "suspend" => HtxState::Suspend,
+            "offline" => HtxState::Offline,
+            "transfer-board" => HtxState::TransferBoard,
+            "fuse" => HtxState::Fuse,
+            "ca_1" => HtxState::CallAuction1,
+            "ca_2" => HtxState::CallAuction2,
+            _ => HtxState::Other(state),
+        }
+    }
+}
+
+impl From<HtxState> for String {
+    fn from(state: HtxState) -> Self {
+        match state {
+            HtxState::Other(state) => state,
+            state => state.as_str().to_string(),
+        }
+    }
+}
+
+impl fmt::Display for HtxState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
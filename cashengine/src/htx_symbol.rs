@@ -1,3 +1,5 @@
+use crate::decimal::Decimal;
+use crate::htx_state::HtxState;
 use serde::{Deserialize, Serialize};
 use std::fmt;
 
@@ -16,7 +18,7 @@ pub struct HtxSymbol {
     #[serde(alias = "qc")]
     pub quote_currency: Option<String>,      // false	quote currency
     #[serde(alias = "state")]
-    pub state: Option<String>, // false	symbol status. unknown，not-online，pre-online，online，suspend，offline，transfer-board，fuse
+    pub state: Option<HtxState>, // false	symbol status. unknown，not-online，pre-online，online，suspend，offline，transfer-board，fuse
     #[serde(alias = "ve")]
     pub visible: Option<bool>,      // false	visible
     #[serde(alias = "we")]
@@ -64,7 +66,7 @@ pub struct HtxSymbol {
     #[serde(alias = "elr")]
     pub etp_leverage_ratio: Option<String>,   // false	etp leverage ratio
     #[serde(alias = "castate")]
-    pub call_auction_state: Option<String>, // false	Not required. The state of the call auction; it will only be displayed when it is in the 1st and 2nd stage of the call auction. Enumeration values: "ca_1", "ca_2"
+    pub call_auction_state: Option<HtxState>, // false	Not required. The state of the call auction; it will only be displayed when it is in the 1st and 2nd stage of the call auction. Enumeration values: "ca_1", "ca_2"
     #[serde(alias = "ca1oa")]
     pub open_time_of_call_auction_phase_1: Option<u64>, // false	not Required. the open time of call auction phase 1, total milliseconds since January 1, 1970 0:0:0:00ms UTC
     #[serde(alias = "ca1ca")]
@@ -89,12 +91,33 @@ pub struct HtxSymbols {
     pub err_msg: Option<String>, // false	error msg(returned when the interface reports an error)
 }
 
-impl HtxSymbols {
-    // Parse symbols strong typed
-    pub fn from(body: &str) -> Result<Self, serde_json::Error> {
-        serde_json::from_str(body)
+impl HtxSymbol {
+    // Price increment from `tpp`, e.g. 2 -> 0.01
+    pub fn tick_size(&self) -> Option<Decimal> {
+        self.trade_price_precision.and_then(increment)
+    }
+
+    // Amount increment in base currency from `tap`
+    pub fn lot_size(&self) -> Option<Decimal> {
+        self.trade_amount_precision.and_then(increment)
     }
 
+    // Order value increment in quote currency from `ttp`
+    pub fn value_increment(&self) -> Option<Decimal> {
+        self.trade_total_precision.and_then(increment)
+    }
+}
+
+// The precisions are documented as decimal(10,6) but count decimals, e.g. 2.0.
+// Fractional, negative and too large values are ignored.
+fn increment(precision: f64) -> Option<Decimal> {
+    if precision.fract() != 0.0 || precision < 0.0 {
+        return None;
+    }
+    Decimal::increment(precision as u32)
+}
+
+impl HtxSymbols {
     fn filter<F>(&self, predicate: F) -> Self
     where
         F: Fn(&HtxSymbol) -> bool,
@@ -112,8 +135,9 @@ impl HtxSymbols {
         }
     }
 
-    pub fn with_online_symbols(&self) -> Self {
-        self.filter(|s| s.state == Some("online".to_string()))
+    // Keeps symbols in one of `states`
+    pub fn with_states(&self, states: &[HtxState]) -> Self {
+        self.filter(|s| s.state.as_ref().is_some_and(|state| states.contains(state)))
     }
 
     pub fn with_trade_enabled_symbols(&self) -> Self {
@@ -144,24 +168,6 @@ impl HtxSymbols {
         self.data.iter().collect()
     }
 
-    pub fn get_error(&self) -> Result<(), String> {
-        if self.err_code.is_some() || self.err_msg.is_some() {
-            let mut error_message = String::new();
-            if let Some(code) = &self.err_code {
-                error_message.push_str(code);
-            }
-            if let Some(msg) = &self.err_msg {
-                if !error_message.is_empty() {
-                    error_message.push_str(": ");
-                }
-                error_message.push_str(msg);
-            }
-            Err(error_message)
-        } else {
-            Ok(())
-        }
-    }
-
     pub fn log_compact(&self) {
         self.log_common_info();
         self.log_symbols(|index, symbol| CompactSymbolPrinter(index, symbol).to_string());
@@ -200,7 +206,7 @@ impl<'a> fmt::Display for CompactSymbolPrinter<'a> {
         write!(f, "{}. Symbol: {}", index, symbol.symbol.as_deref().unwrap_or("N/A"))?;
         write!(f, ", Base: {}", symbol.base_currency.as_deref().unwrap_or("N/A"))?;
         write!(f, ", Quote: {}", symbol.quote_currency.as_deref().unwrap_or("N/A"))?;
-        write!(f, ", State: {}", symbol.state.as_deref().unwrap_or("N/A"))?;
+        write!(f, ", State: {}", symbol.state.as_ref().map_or("N/A", HtxState::as_str))?;
         write!(f, ", Weight Sort: {}", symbol.weight_sort.map_or("N/A".to_string(), |w| w.to_string()))?;
         write!(f, ", Trade Total Precision: {}", symbol.trade_total_precision.map_or("N/A".to_string(), |ttp| ttp.to_string()) )?;
         write!(f, ", Trade Amount Precision: {}", symbol.trade_amount_precision.map_or("N/A".to_string(), |tap| tap.to_string()))?;
@@ -0,0 +1,300 @@
+use crate::binance_symbol::BinanceSymbol;
+use crate::decimal::{Decimal, RoundingMode};
+use crate::exchange::Exchange;
+use crate::htx_currency::HtxCurrency;
+use crate::htx_market::HtxMarket;
+use crate::htx_state::HtxState;
+use crate::htx_symbol::HtxSymbol;
+use std::fmt;
+
+// Venue independent description of a tradable market, converted from the raw exchange models,
+// so strategy code doesn't depend on abbreviated exchange fields or optional state strings.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instrument {
+    pub venue: Exchange,
+    // As used by the venue's APIs, e.g. btcusdt on HTX and BTCUSDT on Binance
+    pub symbol: String,
+    // Lowercase asset codes, e.g. btc and usdt
+    pub base: String,
+    pub quote: String,
+    // Smallest price increment, None if the venue didn't provide one
+    pub tick_size: Option<Decimal>,
+    // Smallest amount increment in base currency
+    pub lot_size: Option<Decimal>,
+    // Smallest order value in quote currency
+    pub min_notional: Option<Decimal>,
+    // Order limits in base currency and the largest order value in quote currency
+    pub min_order_amount: Option<Decimal>,
+    pub max_order_amount: Option<Decimal>,
+    pub max_order_value: Option<Decimal>,
+    pub status: InstrumentStatus,
+    pub trading_hours: TradingHours,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InstrumentStatus {
+    // Listed, trading has not started yet
+    PreTrading,
+    Trading,
+    // Trading is suspended, e.g. by a circuit breaker, and expected to resume
+    Halted,
+    // Delisted or offline
+    Closed,
+    Unknown,
+}
+
+// Trading window in milliseconds since UNIX epoch, None for no limit on that side
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TradingHours {
+    pub opens_at: Option<u64>,
+    pub closes_at: Option<u64>,
+}
+
+// Venue independent description of a currency or asset
+#[derive(Clone, Debug, PartialEq)]
+pub struct Asset {
+    pub venue: Exchange,
+    // Lowercase, e.g. btc
+    pub code: String,
+    pub status: InstrumentStatus,
+    pub deposit_enabled: bool,
+    pub withdraw_enabled: bool,
+    // Decimals of withdrawal amounts
+    pub withdraw_precision: Option<i32>,
+}
+
+// Why an order doesn't satisfy the increments and limits of an instrument
+#[derive(Clone, Debug, PartialEq)]
+pub enum OrderCheckError {
+    NotPositive { field: &'static str, value: Decimal },
+    OffTick { price: Decimal, tick_size: Decimal },
+    OffLot { amount: Decimal, lot_size: Decimal },
+    BelowMinAmount { amount: Decimal, min_order_amount: Decimal },
+    AboveMaxAmount { amount: Decimal, max_order_amount: Decimal },
+    BelowMinNotional { notional: Decimal, min_notional: Decimal },
+    // Price times amount doesn't fit into a Decimal
+    Overflow { price: Decimal, amount: Decimal },
+}
+
+impl Instrument {
+    // Trading and within its trading hours
+    pub fn is_tradable(&self, now_millis: u64) -> bool {
+        self.status == InstrumentStatus::Trading && self.trading_hours.contains(now_millis)
+    }
+
+    // `price` on the tick grid, e.g. RoundingMode::Down for bids and Up for asks.
+    // Unchanged without a tick size, None on overflow.
+    pub fn round_price(&self, price: Decimal, mode: RoundingMode) -> Option<Decimal> {
+        round(price, self.tick_size, mode)
+    }
+
+    // `amount` on the lot grid, RoundingMode::Down keeps it within a budget
+    pub fn round_amount(&self, amount: Decimal, mode: RoundingMode) -> Option<Decimal> {
+        round(amount, self.lot_size, mode)
+    }
+
+    pub fn check_price(&self, price: Decimal) -> Result<(), OrderCheckError> {
+        if !price.is_positive() {
+            return Err(OrderCheckError::NotPositive { field: "price", value: price });
+        }
+        match self.tick_size {
+            Some(tick_size) if !price.is_multiple_of(tick_size) => Err(OrderCheckError::OffTick { price, tick_size }),
+            _ => Ok(()),
+        }
+    }
+
+    // Lot size and the order amount limits, limits the venue didn't provide are not checked
+    pub fn check_amount(&self, amount: Decimal) -> Result<(), OrderCheckError> {
+        if !amount.is_positive() {
+            return Err(OrderCheckError::NotPositive { field: "amount", value: amount });
+        }
+        if let Some(lot_size) = self.lot_size.filter(|lot_size| !amount.is_multiple_of(*lot_size)) {
+            return Err(OrderCheckError::OffLot { amount, lot_size });
+        }
+        if let Some(min_order_amount) = self.min_order_amount.filter(|min| amount < *min) {
+            return Err(OrderCheckError::BelowMinAmount { amount, min_order_amount });
+        }
+        if let Some(max_order_amount) = self.max_order_amount.filter(|max| amount > *max) {
+            return Err(OrderCheckError::AboveMaxAmount { amount, max_order_amount });
+        }
+        Ok(())
+    }
+
+    // Limit order checks: price, amount and the minimum order value.
+    // `max_order_value` is not checked, both venues apply it to market orders.
+    pub fn check_limit_order(&self, price: Decimal, amount: Decimal) -> Result<(), OrderCheckError> {
+        self.check_price(price)?;
+        self.check_amount(amount)?;
+        let notional = price.checked_mul(amount).map_err(|_| OrderCheckError::Overflow { price, amount })?;
+        match self.min_notional {
+            Some(min_notional) if notional < min_notional => Err(OrderCheckError::BelowMinNotional { notional, min_notional }),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn round(value: Decimal, increment: Option<Decimal>, mode: RoundingMode) -> Option<Decimal> {
+    match increment {
+        Some(increment) => value.round_to(increment, mode).ok(),
+        None => Some(value),
+    }
+}
+
+impl TradingHours {
+    pub fn contains(&self, now_millis: u64) -> bool {
+        self.opens_at.is_none_or(|opens_at| now_millis >= opens_at)
+            && self.closes_at.is_none_or(|closes_at| now_millis < closes_at)
+    }
+}
+
+impl InstrumentStatus {
+    fn from_htx(state: Option<&HtxState>) -> InstrumentStatus {
+        match state {
+            Some(HtxState::Online) => InstrumentStatus::Trading,
+            Some(HtxState::NotOnline | HtxState::PreOnline) => InstrumentStatus::PreTrading,
+            Some(HtxState::Suspend | HtxState::TransferBoard | HtxState::Fuse) => InstrumentStatus::Halted,
+            // Call auction stages only appear in `castate`
+            Some(HtxState::CallAuction1 | HtxState::CallAuction2) => InstrumentStatus::PreTrading,
+            Some(HtxState::Offline) => InstrumentStatus::Closed,
+            Some(HtxState::Unknown | HtxState::Other(_)) | None => InstrumentStatus::Unknown,
+        }
+    }
+
+    // Binance states: PRE_TRADING, TRADING, POST_TRADING, END_OF_DAY, HALT, AUCTION_MATCH, BREAK
+    pub(crate) fn from_binance(status: Option<&str>) -> InstrumentStatus {
+        match status {
+            Some("TRADING") => InstrumentStatus::Trading,
+            Some("PRE_TRADING") => InstrumentStatus::PreTrading,
+            Some("HALT" | "AUCTION_MATCH" | "BREAK") => InstrumentStatus::Halted,
+            Some("POST_TRADING" | "END_OF_DAY") => InstrumentStatus::Closed,
+            _ => InstrumentStatus::Unknown,
+        }
+    }
+
+    // Stable code of the status in the SHM market directory
+    pub fn code(self) -> u32 {
+        match self {
+            InstrumentStatus::Unknown => 0,
+            InstrumentStatus::PreTrading => 1,
+            InstrumentStatus::Trading => 2,
+            InstrumentStatus::Halted => 3,
+            InstrumentStatus::Closed => 4,
+        }
+    }
+
+    pub fn from_code(code: u32) -> InstrumentStatus {
+        match code {
+            1 => InstrumentStatus::PreTrading,
+            2 => InstrumentStatus::Trading,
+            3 => InstrumentStatus::Halted,
+            4 => InstrumentStatus::Closed,
+            _ => InstrumentStatus::Unknown,
+        }
+    }
+}
+
+impl From<&HtxSymbol> for Instrument {
+    fn from(symbol: &HtxSymbol) -> Self {
+        // An online symbol in a call auction stage doesn't trade continuously yet
+        let status = match (symbol.delist, &symbol.call_auction_state) {
+            (Some(true), _) => InstrumentStatus::Closed,
+            (_, Some(HtxState::CallAuction1 | HtxState::CallAuction2)) => InstrumentStatus::PreTrading,
+            _ => InstrumentStatus::from_htx(symbol.state.as_ref()),
+        };
+        Instrument {
+            venue: Exchange::Htx,
+            symbol: symbol.symbol.clone().unwrap_or_default(),
+            base: lowercase(symbol.base_currency.as_deref()),
+            quote: lowercase(symbol.quote_currency.as_deref()),
+            tick_size: symbol.tick_size(),
+            lot_size: symbol.lot_size(),
+            min_notional: None,
+            min_order_amount: None,
+            max_order_amount: None,
+            max_order_value: None,
+            status,
+            trading_hours: TradingHours {
+                opens_at: symbol.time_trade_open_at.filter(|&at| at > 0),
+                closes_at: symbol.time_trade_close_at.filter(|&at| at > 0),
+            },
+        }
+    }
+}
+
+impl From<&HtxMarket> for Instrument {
+    fn from(market: &HtxMarket) -> Self {
+        Instrument {
+            venue: Exchange::Htx,
+            symbol: market.symbol.clone().unwrap_or_default(),
+            base: lowercase(market.base_currency.as_deref()),
+            quote: lowercase(market.quote_currency.as_deref()),
+            tick_size: market.tick_size(),
+            lot_size: market.lot_size(),
+            min_notional: market.min_order_value,
+            min_order_amount: market.min_order_amount,
+            max_order_amount: market.max_order_amount,
+            max_order_value: market.max_value_of_market_price_order,
+            status: InstrumentStatus::from_htx(market.state.as_ref()),
+            trading_hours: TradingHours::default(),
+        }
+    }
+}
+
+impl From<&BinanceSymbol> for Instrument {
+    fn from(symbol: &BinanceSymbol) -> Self {
+        Instrument {
+            venue: Exchange::Binance,
+            symbol: symbol.symbol.clone().unwrap_or_default(),
+            base: lowercase(symbol.base_asset.as_deref()),
+            quote: lowercase(symbol.quote_asset.as_deref()),
+            // Binance sends 0 for increments and limits that don't apply
+            tick_size: positive(symbol.tick_size()),
+            lot_size: positive(symbol.step_size()),
+            min_notional: positive(symbol.min_notional()),
+            min_order_amount: positive(symbol.min_qty()),
+            max_order_amount: positive(symbol.max_qty()),
+            max_order_value: positive(symbol.max_notional()),
+            status: InstrumentStatus::from_binance(symbol.status.as_deref()),
+            trading_hours: TradingHours::default(),
+        }
+    }
+}
+
+impl From<&HtxCurrency> for Asset {
+    fn from(currency: &HtxCurrency) -> Self {
+        Asset {
+            venue: Exchange::Htx,
+            code: lowercase(currency.currency_code.as_deref()),
+            status: InstrumentStatus::from_htx(currency.state.as_ref()),
+            deposit_enabled: currency.deposit_enabled == Some(true),
+            withdraw_enabled: currency.withdraw_enabled == Some(true),
+            withdraw_precision: currency.withdraw_precision,
+        }
+    }
+}
+
+// Exact value of a decimal string such as "0.01000000", None if it is missing, malformed or 0
+fn positive(value: Option<&str>) -> Option<Decimal> {
+    value.and_then(|value| value.parse::<Decimal>().ok()).filter(Decimal::is_positive)
+}
+
+fn lowercase(value: Option<&str>) -> String {
+    value.unwrap_or_default().to_ascii_lowercase()
+}
+
+impl fmt::Display for OrderCheckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OrderCheckError::NotPositive { field, value } => write!(f, "{} {} must be positive", field, value),
+            OrderCheckError::OffTick { price, tick_size } => write!(f, "price {} is not a multiple of the tick size {}", price, tick_size),
+            OrderCheckError::OffLot { amount, lot_size } => write!(f, "amount {} is not a multiple of the lot size {}", amount, lot_size),
+            OrderCheckError::BelowMinAmount { amount, min_order_amount } =>
+                write!(f, "amount {} is below the minimum order amount {}", amount, min_order_amount),
+            OrderCheckError::AboveMaxAmount { amount, max_order_amount } =>
+                write!(f, "amount {} is above the maximum order amount {}", amount, max_order_amount),
+            OrderCheckError::BelowMinNotional { notional, min_notional } =>
+                write!(f, "order value {} is below the minimum order value {}", notional, min_notional),
+            OrderCheckError::Overflow { price, amount } => write!(f, "order value of price {} and amount {} overflows", price, amount),
+        }
+    }
+}
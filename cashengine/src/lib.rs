@@ -1,344 +1,509 @@
+pub mod config;
+pub mod error;
 mod rest_client;
 mod htx_symbol;
 mod htx_currency;
 mod htx_market;
+pub mod htx_state;
+mod binance_symbol;
 mod time_util;
 mod websocket;
 pub mod shm_block_writer;
 pub mod shm_reader;
-
-mod string_u8_util;
-mod util;
+pub mod shm_chunk;
+pub mod shm_file;
+pub mod shm_directory;
+
+pub mod bbo;
+pub mod market_data;
+pub mod channel;
+pub mod order_book;
 mod metrics;
+pub mod recorder;
+mod replay;
+pub mod feed_source;
+mod feed_handler;
+pub mod htx_feed;
+pub mod binance_feed;
+pub mod exchange;
+pub mod decimal;
+pub mod instrument;
+pub mod trading_universe;
 mod compression;
-
-use crate::metrics::P95Tracker;
+mod reference_data;
+mod event_loop;
+mod placement;
+mod exporter;
+
+use crate::binance_feed::BinanceBookTickerFeed;
+use crate::channel::Channel;
+use crate::config::{EngineConfig, LatencyConfig};
+use crate::error::EngineError;
+use crate::event_loop::EventLoop;
+use crate::exchange::Exchange;
+use crate::feed_handler::{FeedCommand, FeedHandler};
+use crate::feed_source::FeedSource;
+use crate::htx_feed::HtxWebSocketFeed;
+use crate::htx_state::HtxState;
+use crate::exporter::MetricsRegistry;
+use crate::metrics::{FeedLatency, LatencyTracker, Stage};
+use crate::placement::Placement;
+use crate::reference_data::ReferenceDataRefresher;
+use crate::rest_client::RestClient;
 use crate::shm_block_writer::SharedMemoryWriter;
+use crate::shm_directory::{DirectoryEntry, DirectoryWriter};
+use crate::shm_file::FileHeader;
 use crate::shm_reader::SharedMemoryReader;
-use crate::time_util::print_systemtime;
-use std::collections::HashMap;
+use crate::trading_universe::TradingUniverse;
+use crate::websocket::ReconnectPolicy;
+use crate::time_util::{monotonic_nanos, print_systemtime};
 use std::fs::File;
-use std::path::PathBuf;
-use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
 
-static STATUS: &[u8] = b"status";
-static MARKET_DOT: &[u8] = b"market.";
-const MARKETS_PER_WEBSOCKET: usize = 150;
-
-pub fn run() {
+pub fn try_run(config: &EngineConfig) -> Result<(), EngineError> {
 
     tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::DEBUG)
+        .with_max_level(config.log_level())
         .init();
 
     print_systemtime();
+    tracing::info!("Engine config: {:?}", config);
+
+    if config.replay.enabled {
+        return replay::try_run(config);
+    }
+
+    let rest_client = RestClient::new(&config.rest)?;
+    // The HTX universe and the index of its venue are kept for the reference data refresher, which diffs it against the next poll
+    let mut htx_universe = None;
+    let mut venues = Vec::with_capacity(config.exchanges.len());
+    for exchange in &config.exchanges {
+        let venue = match exchange {
+            Exchange::Htx => {
+                let universe = load_htx_universe(config, &rest_client, &[HtxState::Online])?;
+                let markets = universe.instruments().iter().map(DirectoryEntry::from_instrument).collect();
+                let spare_markets = if config.refresh.enabled { config.refresh.spare_markets } else { 0 };
+                htx_universe = Some((universe, venues.len()));
+                Venue::create(*exchange, config.channels.clone(), markets, spare_markets,
+                              config.markets_per_websocket, &config.shm_file_path, config.chunk_size)?
+            }
+            Exchange::Binance => Venue::create(*exchange, vec![Channel::Bbo], load_binance_markets(config, &rest_client)?, 0,
+                                               config.binance.markets_per_websocket, &config.binance.shm_file_path, config.chunk_size)?,
+        };
+        venues.push(venue);
+    }
+    let websocket_count: usize = venues.iter().map(|venue| venue.feed_count).sum();
+    // Consecutive feeds share a thread, with 1 connection per thread every feed blocks in its own thread
+    let connections_per_thread = config.connections_per_thread;
+    let thread_count = websocket_count.div_ceil(connections_per_thread);
+
+    let placement = Placement::plan(&config.placement, thread_count)?;
+
+    // Map and connect everything before spawning threads, so failures end up in the returned error.
+    let mut shm_readers = Vec::with_capacity(venues.len());
+    let mut writer_threads = Vec::with_capacity(venues.len());
+    let mut feed_offset = 0;
+    for venue in &venues {
+        shm_readers.push(attach_readers(&venue.channels, &venue.shm_files)?);
+        // Feed ids continue across exchanges, see below
+        writer_threads.push((0..venue.feed_count).map(|writer_id| (feed_offset + writer_id) / connections_per_thread).collect());
+        feed_offset += venue.feed_count;
+    }
+
+    let reconnect_policy = ReconnectPolicy::new(&config.reconnect);
+    let websocket_buffer_size = config.channels.iter()
+        .map(|channel| channel.max_raw_message_size())
+        .fold(config.chunk_size, usize::max);
+    let recording = &config.recording;
+    let recording = if recording.enabled {
+        let (recorder, record_writer, records) = recorder::create(&recording.directory, recording.max_file_bytes)
+            .map_err(|error| EngineError::Recording { path: recording.directory.clone(), error })?;
+        Some((recorder, record_writer, records))
+    } else {
+        None
+    };
+
+    // With refresh enabled every HTX feed takes subscription changes from the refresher, indexed by writer id
+    let mut refresh_commands = Vec::new();
+    let mut refresher = None;
+    if let Some((universe, venue_index)) = htx_universe.filter(|_| config.refresh.enabled) {
+        let venue = &venues[venue_index];
+        let (senders, receivers): (Vec<_>, Vec<_>) = (0..venue.feed_count).map(|_| mpsc::channel::<FeedCommand>()).unzip();
+        refresher = Some(ReferenceDataRefresher::new(config, &rest_client, universe, &venue.shm_files, venue.markets_per_websocket, senders)?);
+        refresh_commands = receivers;
+    }
+    let mut refresh_commands = refresh_commands.into_iter();
+
+    // The exporter looks up market names in the directory of every exchange
+    let mut metrics = None;
+    if config.metrics.enabled {
+        let listener = exporter::bind(&config.metrics)?;
+        let mut directories = Vec::with_capacity(venues.len());
+        for venue in &venues {
+            let (path, shm_file) = &venue.shm_files[0];
+            directories.push((venue.exchange, SharedMemoryReader::attach(shm_file).map_err(shm_error(path))?));
+        }
+        metrics = Some((MetricsRegistry::new(thread_count), listener, directories));
+    }
+
+    // Feed ids are unique across exchanges, writer ids count per exchange since each has its own SHM files
+    let mut feeds = Vec::with_capacity(websocket_count);
+    for venue in &venues {
+        for writer_id in 0..venue.feed_count {
+            let id = feeds.len();
+            let shm_writers = create_writers(&venue.shm_files, writer_id)?;
+            let mut feed_source: Box<dyn FeedSource + Send> = match venue.exchange {
+                Exchange::Htx => Box::new(HtxWebSocketFeed::new(id, &config.websocket_url, websocket_buffer_size, reconnect_policy)),
+                Exchange::Binance => Box::new(BinanceBookTickerFeed::new(id, &config.binance.websocket_url, reconnect_policy)),
+            };
+            feed_source.connect()?;
+            if let Some((registry, _, _)) = metrics.as_mut() {
+                registry.add_feed(venue.exchange, writer_id * venue.markets_per_websocket, venue.markets_per_websocket,
+                                  feed_source.connection_metrics());
+            }
+            let commands: Option<Receiver<FeedCommand>> = match venue.exchange {
+                Exchange::Htx => refresh_commands.next(),
+                Exchange::Binance => None,
+            };
+            feeds.push((writer_id, venue, shm_writers, feed_source, commands));
+        }
+    }
+
+    let mut feeds = feeds.into_iter().enumerate();
+    let mut feed_threads = Vec::with_capacity(thread_count);
+    for thread_id in 0..thread_count {
+        let thread_feeds: Vec<_> = feeds.by_ref().take(connections_per_thread).collect();
+        let event_loop = if connections_per_thread > 1 {
+            let mut event_loop = EventLoop::new(thread_id)?;
+            for (_, (_, _, _, feed_source, _)) in &thread_feeds {
+                event_loop.register(feed_source.as_ref())?;
+            }
+            Some(event_loop)
+        } else {
+            None
+        };
+        feed_threads.push((thread_feeds, event_loop));
+    }
+
+    // The registry outlives the threads, the listener and directories move to the exporter thread
+    let (registry, exporter) = match metrics {
+        Some((registry, listener, directories)) => (Some(registry), Some((listener, directories))),
+        None => (None, None),
+    };
+    let registry = registry.as_ref();
+
+    std::thread::scope(|s| {
+        // The recording thread is not pinned, compression and disk writes stay off the feed cores
+        let recorder = recording.map(|(recorder, record_writer, records)| {
+            s.spawn(move || recorder::run(record_writer, records));
+            recorder
+        });
+
+        tracing::info!("Starting {} feed threads for {} websockets", thread_count, websocket_count);
+        let placement = &placement;
+        let mut feed_handles = Vec::with_capacity(thread_count);
+        for (thread_id, (thread_feeds, event_loop)) in feed_threads.into_iter().enumerate() {
+            // Cloned once per feed, every clone registers its own buffer pool. Replay parses HTX messages only.
+            let recorders: Vec<_> = thread_feeds.iter()
+                .map(|(_, (_, venue, _, _, _))| recorder.as_ref().filter(|_| venue.exchange == Exchange::Htx).cloned())
+                .collect();
+
+            feed_handles.push(s.spawn(move || {
+                if !placement.enter(&placement.feeds[thread_id]) {
+                    return Ok(());
+                }
+                let ids: Vec<usize> = thread_feeds.iter().map(|(id, _)| *id).collect();
+                tracing::info!("Starting feed thread id {} with feed ids {:?}", thread_id, ids);
+
+                let mut latency = FeedLatency::new(thread_id, &config.latency);
+                if let Some(registry) = registry {
+                    latency.export(|stage| registry.latency(thread_id, stage));
+                }
+                let mut feeds = Vec::with_capacity(thread_feeds.len());
+                for ((id, (writer_id, venue, shm_writers, mut feed_source, commands)), recorder) in thread_feeds.into_iter().zip(recorders) {
+                    // Feed `writer_id` writes market `index` of its slice into chunk `writer_id * markets_per_websocket + index`,
+                    // which is the position of the market in the full list. Feeds past the end of the list start without
+                    // markets and only hold spare chunks for markets listed later.
+                    let markets_start_index = writer_id * venue.markets_per_websocket;
+                    let markets_end_index = (markets_start_index + venue.markets_per_websocket).min(venue.markets.len());
+                    let markets: Vec<&str> = venue.markets[markets_start_index.min(markets_end_index)..markets_end_index]
+                        .iter().map(DirectoryEntry::symbol).collect();
+
+                    tracing::info!("Starting {} feed id {}", venue.exchange, id);
+                    let mut feed_handler = FeedHandler::new(id, venue.exchange, &venue.channels, shm_writers, &markets, markets_start_index, recorder);
+                    if let Some(commands) = commands {
+                        feed_handler.set_commands(commands);
+                    }
+                    if let Some(registry) = registry {
+                        feed_handler.set_metrics(registry.feed(id));
+                    }
+                    feed_source.subscribe(&markets, &venue.channels);
+                    feeds.push((feed_handler, feed_source));
+                }
 
-    let websocket_url = "wss://api-aws.huobi.pro/ws";
+                match event_loop {
+                    // The other threads keep running, so the error is logged here as well
+                    Some(event_loop) => event_loop.run(&mut feeds, &mut latency).inspect_err(|e| {
+                        tracing::error!("Feed thread id {} stopped: {}", thread_id, e);
+                    }),
+                    // A single feed blocking on its reads
+                    None => {
+                        for (feed_handler, feed_source) in &mut feeds {
+                            feed_handler.run(feed_source.as_mut(), &mut latency);
+                        }
+                        Ok(())
+                    }
+                }
+            }));
+        }
+        // The recording thread ends once all feeds dropped their recorders
+        drop(recorder);
 
-    // TODO: On Linux use tmpfs shared memory: /dev/shm/ticks.shm;
-    let mmap_file_path = "/tmp/ticks.mmap";
+        let main_thread = s.spawn(move || {
+            if !placement.enter(&placement.reader) {
+                return;
+            }
+            tracing::info!("Starting feeds reader thread");
+            read_feeds(&mut shm_readers, &writer_threads, &config.latency, registry, None);
+        });
+        // The feed and reader threads wait for the placement report before they start
+        placement.check()?;
+
+        // Not pinned either, it sleeps between polls of the REST endpoints
+        if let Some(refresher) = refresher {
+            s.spawn(move || refresher.run());
+        }
+        // Not pinned, it only runs when scraped
+        if let (Some(registry), Some((listener, directories))) = (registry, exporter) {
+            s.spawn(move || exporter::run(listener, registry, directories));
+        }
+        main_thread.join().map_err(|payload| EngineError::panic("reader", payload))?;
+        for handle in feed_handles {
+            handle.join().map_err(|payload| EngineError::panic("feed", payload))??;
+        }
+        Ok(())
+    })
+}
+
+// The markets of one exchange, split across `feed_count` websockets, and the SHM files of its channels
+struct Venue {
+    exchange: Exchange,
+    channels: Vec<Channel>,
+    markets: Vec<DirectoryEntry>,
+    markets_per_websocket: usize,
+    feed_count: usize,
+    shm_files: Vec<(String, File)>,
+}
+
+impl Venue {
+    fn create(
+        exchange: Exchange,
+        channels: Vec<Channel>,
+        markets: Vec<DirectoryEntry>,
+        spare_markets: usize,
+        markets_per_websocket: usize,
+        shm_file_path: &str,
+        chunk_size: usize,
+    ) -> Result<Venue, EngineError> {
+        // Spare chunks after the markets take new listings without resizing the SHM files
+        let feed_count = (markets.len() + spare_markets).div_ceil(markets_per_websocket);
+        tracing::info!("Subscribing {} {} markets on {} websockets, {} spare markets", markets.len(), exchange, feed_count, spare_markets);
+        let shm_files = create_shm_files(shm_file_path, chunk_size, &channels, feed_count, markets_per_websocket, &markets)?;
+        Ok(Venue { exchange, channels, markets, markets_per_websocket, feed_count, shm_files })
+    }
+}
 
-    let rest_url = "https://api-aws.huobi.pro";
+// Symbols, currencies and markets are kept in one of `states`. Exchange errors in the responses are reported by the client.
+pub(crate) fn load_htx_universe(config: &EngineConfig, rest_client: &RestClient, states: &[HtxState]) -> Result<TradingUniverse, EngineError> {
+    let rest_url = config.rest_url.as_str();
 
     let symbols_url = format!("{rest_url}{path}",path = htx_symbol::PATH);
-    let body = rest_client::send_request(&symbols_url).expect("Failed to get symbols");
-    let mut symbols = htx_symbol::HtxSymbols::from(&body).expect("Failed to parse symbols");
-    symbols = symbols
-        .with_online_symbols()
-        .with_trade_enabled_symbols()
-        .with_cancel_enabled_symbols()
-        .with_visible_symbols()
-        .with_listed_symbols()
-        .with_country_enabled();
-    if let Err(err) = symbols.get_error() {
-        panic!("Requested symbols contained an error. Exchange error: {err}")
-    } else if symbols.len() == 0 {
-        panic!("Requested symbols are empty");
+    let symbols: htx_symbol::HtxSymbols = rest_client.get(Exchange::Htx, &symbols_url, "symbols")?;
+    let symbols = config.symbol_filters.apply(symbols, states);
+    if symbols.len() == 0 {
+        return Err(empty_data("symbols"));
     } else {
         symbols.log_compact();
     }
 
 
     let currencies_url = format!("{rest_url}{path}", path = htx_currency::PATH);
-    let body = rest_client::send_request(&currencies_url).expect("Failed to get currencies");
-    let mut currencies = htx_currency::HtxCurrencies::from(&body).expect("Failed to parse currencies");
+    let mut currencies: htx_currency::HtxCurrencies = rest_client.get(Exchange::Htx, &currencies_url, "currencies")?;
     currencies = currencies
-        .with_online_currencies()
+        .with_states(states)
         .with_country_enabled();
-    if let Err(err) = currencies.get_error() {
-        panic!("Requested currencies contained an error. Exchange error: {err}")
-    } else if currencies.len() == 0 {
-        panic!("Requested currencies are empty");
+    if currencies.len() == 0 {
+        return Err(empty_data("currencies"));
     } else {
         //currencies.print_compact();
     }
 
     let markets_url = format!("{rest_url}{path}", path = htx_market::PATH);
-    let body = rest_client::send_request(&markets_url).expect("Failed to get markets");
-    let mut markets = htx_market::HtxMarkets::from(&body).expect("Failed to parse markets");
+    let mut markets: htx_market::HtxMarkets = rest_client.get(Exchange::Htx, &markets_url, "markets")?;
     markets = markets
-        .with_online_markets();
-    if let Err(err) = markets.get_error() {
-        panic!("Requested markets contained an error. Exchange error: {err}")
-    } else if markets.len() == 0 {
-        panic!("Requested markets are empty");
+        .with_states(states);
+    if markets.len() == 0 {
+        return Err(empty_data("markets"));
     } else {
         //markets.print_compact();
     }
 
+    let universe = TradingUniverse::builder(&symbols)
+        .currencies(&currencies)
+        .markets(&markets)
+        .build();
+    universe.log_issues();
+    if universe.is_empty() {
+        return Err(empty_data("trading universe"));
+    }
+    Ok(universe)
+}
 
-    let symbols = Arc::new(symbols);
-
-
-    let websocket_count = (symbols.len() / MARKETS_PER_WEBSOCKET) + 1;
-
-    let shm_file = create_shm_file(mmap_file_path);
-    resize_shm_file(&shm_file, websocket::CHUNK_SIZE * websocket_count * MARKETS_PER_WEBSOCKET);
-
-    let shm_file = Arc::new(shm_file);
+fn load_binance_markets(config: &EngineConfig, rest_client: &RestClient) -> Result<Vec<DirectoryEntry>, EngineError> {
+    let symbols_url = format!("{rest_url}{path}", rest_url = config.binance.rest_url, path = binance_symbol::PATH);
+    let symbols: binance_symbol::BinanceSymbols = rest_client.get(Exchange::Binance, &symbols_url, "binance symbols")?;
+    let symbols = config.binance.symbol_filters.apply(symbols);
+    if symbols.len() == 0 {
+        return Err(empty_data("binance symbols"));
+    } else {
+        symbols.log_compact();
+    }
 
-    // Retrieve the IDs of all active CPU cores.
-    let core_ids = core_affinity::get_core_ids().unwrap_or_else(|| {
-        panic!("Failed getting core ids")
-    });
-    tracing::info!("Available core ids: {:?}", core_ids);
+    Ok(symbols.get_symbols().iter()
+        .filter(|symbol| match &symbol.symbol {
+            Some(_) => true,
+            None => {
+                tracing::warn!("Skipping Binance symbol without name: {:?}", symbol);
+                false
+            }
+        })
+        .map(|symbol| DirectoryEntry::from_binance_symbol(symbol))
+        .collect())
+}
 
-    if core_ids.is_empty() {
-        panic!("List of core ids is empty");
-    }
-    if core_ids.len() < (websocket_count + 1 /*main thread */) {
-        panic!("Not enough cores to run {} websockets plus 1 main thread. At least {} cores are required.", websocket_count, websocket_count + 1);
+// One SHM file per channel, each with the same market directory
+pub(crate) fn create_shm_files(
+    shm_file_path: &str,
+    chunk_size: usize,
+    channels: &[Channel],
+    writer_count: usize,
+    chunks_per_writer: usize,
+    markets: &[DirectoryEntry],
+) -> Result<Vec<(String, File)>, EngineError> {
+    let mut shm_files = Vec::with_capacity(channels.len());
+    for channel in channels {
+        let path = channel.shm_file_path(shm_file_path);
+        let shm_header = FileHeader::new(channel.chunk_size(chunk_size), writer_count, chunks_per_writer);
+        let shm_file = shm_file::create(&path, &shm_header).map_err(shm_error(&path))?;
+
+        let mut shm_directory = DirectoryWriter::create(&shm_file).map_err(shm_error(&path))?;
+        for (chunk_index, market) in markets.iter().enumerate() {
+            shm_directory.publish(chunk_index, market);
+        }
+        tracing::info!("Writing channel {} into SHM file {}", channel, path);
+        shm_files.push((path, shm_file));
     }
-    let core_ids = Arc::new(core_ids);
+    Ok(shm_files)
+}
 
-    std::thread::scope(|s| {
-        tracing::info!("Starting {} feed threads", websocket_count);
-        for id in 0..websocket_count {
-            let symbols = Arc::clone(&symbols);
-            let shm_file = Arc::clone(&shm_file);
-            let core_ids = Arc::clone(&core_ids);
-
-            s.spawn(move || {
-                let core_id = core_ids.len() - (id + 1 + 1);
-                tracing::info!("Starting feed thread id {} on core id {}", id, core_id);
-
-                match core_ids.get(core_id) {
-                    Some(core_id) => {
-                        if core_affinity::set_for_current(core_id.clone()) {
-                            tracing::info!("Pinned feed thread id {} to core id {:?}", id, core_id);
-                        } else {
-                            // TODO: Fails on Apple Silicon -> test on Linux AMD
-                            tracing::warn!("Failed pinning feed thread id {} to core id {:?} (ok on Apple Silicon)", id, core_id);
-                        }
-                    }
-                    None => {
-                        tracing::warn!("Failed getting core id {} for feed thread id {} (ok on Apple Silicon)", core_id, id);
-                    }
-                }
+// The reader thread follows the first channel, order book depth is looked up by market
+// in the first mbp channel after it
+pub(crate) fn attach_readers<'a>(
+    channels: &[Channel],
+    shm_files: &'a [(String, File)],
+) -> Result<(SharedMemoryReader<'a>, Option<SharedMemoryReader<'a>>), EngineError> {
+    let (reader_path, reader_file) = &shm_files[0];
+    let shm_reader = SharedMemoryReader::attach(reader_file).map_err(shm_error(reader_path))?;
+    let book_reader = match channels.iter().skip(1).position(|channel| matches!(channel, Channel::Mbp(_))) {
+        Some(position) => {
+            let (path, shm_file) = &shm_files[position + 1];
+            Some(SharedMemoryReader::attach(shm_file).map_err(shm_error(path))?)
+        }
+        None => None,
+    };
+    Ok((shm_reader, book_reader))
+}
 
-                let mut shm_writer = SharedMemoryWriter::create(
-                    &&shm_file,
-                    id,
-                    websocket::CHUNK_SIZE,
-                    MARKETS_PER_WEBSOCKET,
-                );
-
-                let mut subscribe_request = String::new();
-                subscribe_request.push_str("{\"sub\": [");
-                let symbols_start_index = id * MARKETS_PER_WEBSOCKET; // TODO: Make 150 configurable
-                let mut symbols_length = (id * MARKETS_PER_WEBSOCKET) + MARKETS_PER_WEBSOCKET; // TODO: Debug that this doesnt overlap with the other threads
-                if symbols_length > symbols.len() {
-                    symbols_length = symbols.len();
-                }
+// One writer per channel, same order as the channels
+pub(crate) fn create_writers(shm_files: &[(String, File)], writer_id: usize) -> Result<Vec<SharedMemoryWriter<'_>>, EngineError> {
+    shm_files.iter()
+        .map(|(path, shm_file)| SharedMemoryWriter::create(shm_file, writer_id).map_err(shm_error(path)))
+        .collect()
+}
 
-                let symbols_to_subscribe = &symbols.get_symbols()[symbols_start_index..symbols_length];
-                let mut indexed_symbols: HashMap<&str, usize> = HashMap::new();
-                symbols_to_subscribe.iter().enumerate().for_each(|(index, symbol)| {
-                    if let Some(symbol_name) = &symbol.symbol {
-                        subscribe_request.push_str(format!("\"market.{}.bbo\",", symbol_name).as_str());
-                        indexed_symbols.insert(symbol.symbol.as_ref().unwrap(), index);
-                    } else {
-                        panic!("Missing symbol name for: {:?}", symbol);
-                    }
-                });
+pub(crate) fn shm_error(path: &str) -> impl FnOnce(std::io::Error) -> EngineError {
+    let path = path.to_string();
+    move |error| EngineError::Shm { path, error }
+}
 
-                if subscribe_request.ends_with(',') {
-                    subscribe_request.pop(); // Remove the last comma
-                }
-                subscribe_request.push_str("\n],\n\"id\": \"id");
-                subscribe_request.push_str(id.to_string().as_str());
-                subscribe_request.push_str("\"\n}");
-
-                let mut websocket = websocket::CeWebSocket::connect(websocket_url)
-                    .expect(format!("Failed to connect websocket url: {}", websocket_url).as_str());
-                let (_, response) = tungstenite::connect(websocket_url)
-                    .expect(format!("Failed to connect websocket url: {}", websocket_url).as_str());
-                tracing::debug!("Connected to websocket server with id {}", id);
-                tracing::debug!("Websocket server id {} response HTTP code: {}", id, response.status());
-                tracing::debug!("Websocket server id {} response contains the following headers:", id);
-                for (header, _value) in response.headers() {
-                    tracing::debug!("* {header}");
+// Polls the readers of all exchanges in turn, each with the order book reader of its exchange.
+// Tracks the latency from the SHM write to the read per feed thread, `writer_threads` maps the writer ids
+// of each reader to the feed thread writing them. The stages before the write are tracked by the feed threads.
+// Runs until `finished` is set and no new message is left, live feeds pass None and never finish.
+pub(crate) fn read_feeds(
+    shm_readers: &mut [(SharedMemoryReader, Option<SharedMemoryReader>)],
+    writer_threads: &[Vec<usize>],
+    latency: &LatencyConfig,
+    registry: Option<&MetricsRegistry>,
+    finished: Option<&AtomicBool>,
+) {
+    let mut iterations = 0;
+    let thread_count = writer_threads.iter().flatten().max().map_or(0, |thread_id| thread_id + 1);
+    let mut read_latency: Vec<LatencyTracker> = (0..thread_count)
+        .map(|thread_id| {
+            let mut tracker = LatencyTracker::new(&Stage::WriteToRead.tracker_name(thread_id), latency);
+            if let Some(registry) = registry {
+                tracker.export(registry.latency(thread_id, Stage::WriteToRead));
+            }
+            tracker
+        })
+        .collect();
+    // Reads in a row without a new message after `finished` was set, the readers take turns
+    // so every chunk was read once after this many
+    let mut idle_reads = 0;
+    let max_chunk_count = shm_readers.iter().map(|(shm_reader, _)| shm_reader.header().chunk_count as usize).max().unwrap_or(0);
+
+    loop {
+        let reader_index = iterations % shm_readers.len();
+        let (shm_reader, book_reader) = &mut shm_readers[reader_index];
+        if let Some(chunk) = shm_reader.read_next_message() {
+            let read_nanos = monotonic_nanos();
+            let header = chunk.header();
+            let bbo = chunk.bbo();
+            tracing::trace!("Read bbo: {:?}", bbo);
+            if let (Some(bbo), Some(book_reader)) = (bbo, book_reader.as_mut()) {
+                if let Some(depth) = book_reader.read_chunk(bbo.symbol_index as usize).as_ref().and_then(|chunk| chunk.depth()) {
+                    tracing::trace!("Read order book with {} bids and {} asks at seqNum {}",
+                                    depth.bid_count, depth.ask_count, depth.version);
                 }
+            }
 
-                let on_websocket_message = |message: &[u8]| {
-                    if let Some(_) = message.windows(STATUS.len()).position(|window| window == STATUS) {
-                        return;
-                    }
-
-                    if let Some(market_index_start) = message.windows(MARKET_DOT.len()).position(|window| window == MARKET_DOT) {
-                        let market_index_start = market_index_start + "market.".len();
-                        if let Some(market_index_end) = message[market_index_start..].windows(4).position(|window| window == b".bbo") {
-                            let market_index_str = std::str::from_utf8(&message[market_index_start..market_index_start + market_index_end])
-                                .expect("Invalid UTF-8 sequence");
-                            if let Some(index) = indexed_symbols.get(market_index_str) {
-                                shm_writer.write(*index, message);
-                            } else {
-                                panic!("Failed to lookup index for market {} from websocket {}, message: {}",
-                                       market_index_str, id, std::str::from_utf8(message).unwrap_or("Invalid UTF-8"));
-                            }
-                        } else {
-                            panic!("Failed to parse market from websocket {}, message: {}",
-                                   id, std::str::from_utf8(message).unwrap_or("Invalid UTF-8"));
-                        }
-                    } else {
-                        panic!("Failed to parse market from websocket {}, message: {}",
-                               id, std::str::from_utf8(message).unwrap_or("Invalid UTF-8"));
-                    }
-                };
-
-                tracing::info!("Subscribing to symbols: {}", subscribe_request);
-                websocket.subscribe(subscribe_request.as_str());
-                websocket.run(on_websocket_message);
-            });
-        }
-
-        let main_thread = s.spawn(move || {
-            tracing::info!("Starting feeds reader thread");
-            let shm_file = Arc::clone(&shm_file);
-            let mut shm_reader = SharedMemoryReader::create(
-                &&shm_file,
-                websocket::CHUNK_SIZE,
-                websocket_count * MARKETS_PER_WEBSOCKET,
-            );
-
-            let core_ids = Arc::clone(&core_ids);
-            let core_id = core_ids.len() - 1;
-            tracing::info!("Starting feeds reader thread on core id {}", core_id);
-
-            match core_ids.get(core_id) {
-                Some(core_id) => {
-                    if core_affinity::set_for_current(core_id.clone()) {
-                        tracing::info!("Pinned feeds reader thread to core id {:?}", core_id);
-                    } else {
-                        tracing::warn!("Failed pinning feeds reader thread to core id {:?} (ok on Apple Silicon)", core_id);
-                    }
-                }
-                None => {
-                    tracing::warn!("Failed getting core id {} for feeds reader thread (ok on Apple Silicon)", core_id);
-                }
+            if let Some(&thread_id) = writer_threads[reader_index].get(header.writer_id as usize) {
+                read_latency[thread_id].record(read_nanos.saturating_sub(header.monotonic_nanos), read_nanos);
             }
 
-            let mut iterations = 0;
-            let mut p95_tracker = P95Tracker::new(128);
-
-            loop {
-                let message = shm_reader.read_next_message();
-                let message =
-                    unsafe { string_u8_util::null_terminated_u8_to_utf8_str_unchecked(message) };
-                if !message.is_empty() {
-                    tracing::trace!("Read message: '{}'", message);
-                    match message.find(':') {
-                        Some(index) => {
-                            let writer_id = &message[..index];
-                            let message = &message[index+1..];
-                            match message.find(':') {
-                                Some(start_index) => {
-                                    let sequence = &message[..start_index];
-                                    let message = &message[start_index+1..];
-                                    match message.find(':') {
-                                        Some(end_index) => {
-                                            let start_timestamp_micros = &message[..end_index];
-                                            let start_timestamp_micros: u128 = start_timestamp_micros.parse().unwrap_or_else(|e| {
-                                                tracing::error!("Failed to parse start_timestamp_micros: {}, error: {}", start_timestamp_micros, e);
-                                                0
-                                            });
-
-                                            let message = &message[end_index+1..];
-
-                                            match message.find(':') {
-                                                Some(end_index) => {
-                                                    let offset = &message[..end_index];
-                                                    let message = &message[end_index+1..];
-
-                                                    // TODO: Process message with business logic here
-
-                                                    let current_system_time = SystemTime::now();
-                                                    match current_system_time.duration_since(UNIX_EPOCH) {
-                                                        Ok(duration_since_epoch) => {
-                                                            let end_timestamp_micros = duration_since_epoch.as_micros();
-                                                            let latency = end_timestamp_micros - start_timestamp_micros;
-
-                                                            p95_tracker.push(latency);
-
-                                                            // Print message and P95 Latency every 98765 iterations (some out-of-sequence number).
-                                                            if iterations % 98765 == 0 {
-                                                                if p95_tracker.has_enough_samples() {
-                                                                    if let Some(p95) = p95_tracker.p95() {
-                                                                        tracing::debug!("P95 Latency: {} μs", p95);
-                                                                        tracing::debug!("Read message from writer_id: {}, sequence: {}, start_timestamp_micros: {}, offset: {}, message: {}",
-                                                                        writer_id, sequence, start_timestamp_micros, offset, message);
-                                                                    }
-                                                                }
-                                                            }
-
-                                                        },
-                                                        Err(e) => tracing::error!("Failed getting duration for UNIX epoch: {}", e),
-                                                    }
-                                                },
-                                                _ => ()
-                                            }
-                                        }
-                                        _ => ()
-                                    }
-                                },
-                                _ => ()
-                            }
-                        }
-                        _ => ()
-                    }
+            // Print a message every 98765 iterations (some out-of-sequence number).
+            if iterations % 98765 == 0 {
+                tracing::debug!("Read message from writer_id: {}, sequence: {}, start_timestamp_nanos: {}, market_index: {}, bbo: {:?}",
+                    header.writer_id, header.sequence, header.timestamp_nanos, header.market_index, bbo);
+            }
+            idle_reads = 0;
+        } else if finished.is_some_and(|finished| finished.load(Ordering::Acquire)) {
+            // The writers are done, stop once every chunk came up empty in a row
+            idle_reads += 1;
+            if idle_reads >= max_chunk_count * shm_readers.len() {
+                let now = monotonic_nanos();
+                for tracker in &mut read_latency {
+                    tracker.report(now);
                 }
-                iterations += 1;
+                return;
             }
-        });
-        main_thread.join().unwrap();
-    });
-}
-
-fn create_shm_file(file_path: &str) -> File {
-    tracing::info!("Creating SHM file: {}", file_path);
-    let path_buf = PathBuf::from(file_path);
-    let open_result = File::options()
-        .read(true)
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(path_buf);
-    let file: File = match open_result {
-        Ok(file) => file,
-        Err(e) => {
-            panic!("Failed to create SHM file: {}", e);
         }
-    };
-    file
+        iterations += 1;
+    }
 }
 
-fn resize_shm_file(file: &File, file_size: usize) {
-    tracing::info!("Resizing SHM file to {} bytes", file_size);
-    match file.set_len(file_size as u64) {
-        Ok(_) => (),
-        Err(e) => {
-            panic!("Failed to resize SHM file: {}", e);
-        }
-    }
-}
\ No newline at end of file
+fn empty_data(what: &str) -> EngineError {
+    EngineError::Exchange { code: "empty-data".to_string(), msg: format!("Requested {} are empty", what) }
+}
@@ -0,0 +1,359 @@
+use crate::bbo::{find, number_after};
+use crate::shm_chunk;
+use crate::shm_chunk::ChunkPayload;
+
+// Typed messages of the HTX channels besides bbo, written into their own SHM regions.
+// Each chunk holds the latest message of a market, parsed without allocating on the feed thread.
+
+// Trades beyond this count in a single trade.detail message are dropped
+pub const MAX_TRADES: usize = 16;
+// depth.step0 delivers up to 150 levels per side
+pub const MAX_DEPTH_LEVELS: usize = 150;
+
+pub const SIDE_BUY: u32 = 1;
+pub const SIDE_SELL: u32 = 2;
+
+static TS: &[u8] = b"\"ts\":";
+static TICK: &[u8] = b"\"tick\":";
+static ID: &[u8] = b"\"id\":";
+static DATA: &[u8] = b"\"data\":";
+static TRADE_ID: &[u8] = b"\"tradeId\":";
+static PRICE: &[u8] = b"\"price\":";
+static AMOUNT: &[u8] = b"\"amount\":";
+static DIRECTION: &[u8] = b"\"direction\":";
+static BIDS: &[u8] = b"\"bids\":";
+static ASKS: &[u8] = b"\"asks\":";
+static VERSION: &[u8] = b"\"version\":";
+static OPEN: &[u8] = b"\"open\":";
+static CLOSE: &[u8] = b"\"close\":";
+static LOW: &[u8] = b"\"low\":";
+static HIGH: &[u8] = b"\"high\":";
+static VOL: &[u8] = b"\"vol\":";
+static COUNT: &[u8] = b"\"count\":";
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Trade {
+    pub trade_id: u64,
+    // Trade time in milliseconds since UNIX epoch
+    pub ts: u64,
+    pub price: f64,
+    pub amount: f64,
+    // SIDE_BUY or SIDE_SELL, the taker side
+    pub side: u32,
+    // Padding, keeps the struct 8-byte aligned
+    pub reserved: u32,
+}
+
+// market.$symbol.trade.detail
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct TradeBatch {
+    // Global chunk index of the market, see `SharedMemoryReader::market`
+    pub symbol_index: u32,
+    pub count: u32,
+    // Exchange timestamp of the message in milliseconds since UNIX epoch
+    pub exchange_ts: u64,
+    pub trades: [Trade; MAX_TRADES],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Level {
+    pub price: f64,
+    pub size: f64,
+}
+
+// market.$symbol.depth.step0
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct Depth {
+    pub symbol_index: u32,
+    pub reserved: u32,
+    pub bid_count: u32,
+    pub ask_count: u32,
+    pub version: u64,
+    pub exchange_ts: u64,
+    // Best first, only the first `bid_count`/`ask_count` levels are valid
+    pub bids: [Level; MAX_DEPTH_LEVELS],
+    pub asks: [Level; MAX_DEPTH_LEVELS],
+}
+
+// market.$symbol.kline.$period
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Kline {
+    pub symbol_index: u32,
+    pub reserved: u32,
+    // Start of the candle in seconds since UNIX epoch
+    pub id: u64,
+    pub exchange_ts: u64,
+    pub open: f64,
+    pub close: f64,
+    pub low: f64,
+    pub high: f64,
+    pub amount: f64,
+    pub vol: f64,
+    pub count: u64,
+}
+
+// market.$symbol.detail, rolling 24h statistics
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct MarketDetail {
+    pub symbol_index: u32,
+    pub reserved: u32,
+    pub id: u64,
+    pub version: u64,
+    pub exchange_ts: u64,
+    pub open: f64,
+    pub close: f64,
+    pub low: f64,
+    pub high: f64,
+    pub amount: f64,
+    pub vol: f64,
+    pub count: u64,
+}
+
+impl TradeBatch {
+    // {"ch":"market.btcusdt.trade.detail","ts":1,"tick":{"id":1,"ts":1,"data":[{"id":1,"ts":1,"tradeId":1,"amount":0.1,"price":1.0,"direction":"buy"}]}}
+    pub fn parse_htx(message: &[u8], symbol_index: u32) -> Option<TradeBatch> {
+        let data = &message[find(message, DATA)? + DATA.len()..];
+        let mut batch = TradeBatch {
+            symbol_index,
+            count: 0,
+            exchange_ts: number_after(message, TS)?,
+            trades: [Trade::default(); MAX_TRADES],
+        };
+        for object in data.split(|&c| c == b'}') {
+            if batch.count as usize == MAX_TRADES || find(object, PRICE).is_none() {
+                continue;
+            }
+            let direction = &object[find(object, DIRECTION)? + DIRECTION.len()..];
+            let side = if direction.starts_with(b"\"sell\"") { SIDE_SELL } else { SIDE_BUY };
+            batch.trades[batch.count as usize] = Trade {
+                trade_id: number_after(object, TRADE_ID).or_else(|| number_after(object, ID))?,
+                ts: number_after(object, TS)?,
+                price: number_after(object, PRICE)?,
+                amount: number_after(object, AMOUNT)?,
+                side,
+                reserved: 0,
+            };
+            batch.count += 1;
+        }
+        Some(batch)
+    }
+
+    pub fn trades(&self) -> &[Trade] {
+        &self.trades[..(self.count as usize).min(MAX_TRADES)]
+    }
+}
+
+impl Depth {
+    // {"ch":"market.btcusdt.depth.step0","ts":1,"tick":{"bids":[[1.0,2.0]],"asks":[[1.1,3.0]],"version":1,"ts":1}}
+    pub fn parse_htx(message: &[u8], symbol_index: u32) -> Option<Depth> {
+        let tick = &message[find(message, TICK)? + TICK.len()..];
+        let mut depth = Depth {
+            symbol_index,
+            reserved: 0,
+            bid_count: 0,
+            ask_count: 0,
+            version: number_after(tick, VERSION).unwrap_or(0),
+            exchange_ts: number_after(message, TS)?,
+            bids: [Level::default(); MAX_DEPTH_LEVELS],
+            asks: [Level::default(); MAX_DEPTH_LEVELS],
+        };
+        depth.bid_count = parse_levels(tick, BIDS, &mut depth.bids)? as u32;
+        depth.ask_count = parse_levels(tick, ASKS, &mut depth.asks)? as u32;
+        Some(depth)
+    }
+
+    pub fn bids(&self) -> &[Level] {
+        &self.bids[..(self.bid_count as usize).min(MAX_DEPTH_LEVELS)]
+    }
+
+    pub fn asks(&self) -> &[Level] {
+        &self.asks[..(self.ask_count as usize).min(MAX_DEPTH_LEVELS)]
+    }
+}
+
+// Parses `"key":[[price,size],...]` into `levels` and returns the number of levels
+pub(crate) fn parse_levels(json: &[u8], key: &[u8], levels: &mut [Level]) -> Option<usize> {
+    let mut count = 0;
+    for_each_level(json, key, |level| {
+        if count < levels.len() {
+            levels[count] = level;
+            count += 1;
+        }
+    })?;
+    Some(count)
+}
+
+// Calls `on_level` for every `[price,size]` pair of `"key":[[price,size],...]`
+pub(crate) fn for_each_level(json: &[u8], key: &[u8], mut on_level: impl FnMut(Level)) -> Option<()> {
+    let start = find(json, key)? + key.len();
+    let array = &json[start..];
+    if array.starts_with(b"[]") {
+        return Some(());
+    }
+    let end = find(array, b"]]").map_or(array.len(), |end| end + 1);
+    for pair in array[..end].split(|&c| c == b']') {
+        let pair = match pair.iter().rposition(|&c| c == b'[') {
+            Some(open) => &pair[open + 1..],
+            None => continue,
+        };
+        let comma = pair.iter().position(|&c| c == b',')?;
+        on_level(Level {
+            price: parse_number(&pair[..comma])?,
+            size: parse_number(&pair[comma + 1..])?,
+        });
+    }
+    Some(())
+}
+
+fn parse_number<T: std::str::FromStr>(value: &[u8]) -> Option<T> {
+    std::str::from_utf8(value).ok()?.trim().parse().ok()
+}
+
+impl Kline {
+    // {"ch":"market.btcusdt.kline.1min","ts":1,"tick":{"id":1,"open":1.0,"close":1.0,"low":1.0,"high":1.0,"amount":1.0,"vol":1.0,"count":1}}
+    pub fn parse_htx(message: &[u8], symbol_index: u32) -> Option<Kline> {
+        let tick = &message[find(message, TICK)? + TICK.len()..];
+        Some(Kline {
+            symbol_index,
+            reserved: 0,
+            id: number_after(tick, ID)?,
+            exchange_ts: number_after(message, TS)?,
+            open: number_after(tick, OPEN)?,
+            close: number_after(tick, CLOSE)?,
+            low: number_after(tick, LOW)?,
+            high: number_after(tick, HIGH)?,
+            amount: number_after(tick, AMOUNT)?,
+            vol: number_after(tick, VOL)?,
+            count: number_after(tick, COUNT)?,
+        })
+    }
+}
+
+impl MarketDetail {
+    // {"ch":"market.btcusdt.detail","ts":1,"tick":{"id":1,"version":1,"open":1.0,"close":1.0,"low":1.0,"high":1.0,"amount":1.0,"vol":1.0,"count":1}}
+    pub fn parse_htx(message: &[u8], symbol_index: u32) -> Option<MarketDetail> {
+        let tick = &message[find(message, TICK)? + TICK.len()..];
+        Some(MarketDetail {
+            symbol_index,
+            reserved: 0,
+            id: number_after(tick, ID)?,
+            version: number_after(tick, VERSION).unwrap_or(0),
+            exchange_ts: number_after(message, TS)?,
+            open: number_after(tick, OPEN)?,
+            close: number_after(tick, CLOSE)?,
+            low: number_after(tick, LOW)?,
+            high: number_after(tick, HIGH)?,
+            amount: number_after(tick, AMOUNT)?,
+            vol: number_after(tick, VOL)?,
+            count: number_after(tick, COUNT)?,
+        })
+    }
+}
+
+// SAFETY: All types are repr(C) plain old data without padding.
+unsafe impl ChunkPayload for TradeBatch {
+    const KIND: u32 = shm_chunk::KIND_TRADES;
+}
+
+unsafe impl ChunkPayload for Depth {
+    const KIND: u32 = shm_chunk::KIND_DEPTH;
+}
+
+unsafe impl ChunkPayload for Kline {
+    const KIND: u32 = shm_chunk::KIND_KLINE;
+}
+
+unsafe impl ChunkPayload for MarketDetail {
+    const KIND: u32 = shm_chunk::KIND_DETAIL;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_trade_detail() {
+        let message = br#"{"ch":"market.btcusdt.trade.detail","ts":1630994963175,"tick":{"id":137005445109,"ts":1630994963173,"data":[{"id":1370054451093,"ts":1630994963173,"tradeId":102523573486,"amount":0.006754,"price":52648.62,"direction":"buy"},{"id":1370054451094,"ts":1630994963174,"tradeId":102523573487,"amount":1.5,"price":52648.61,"direction":"sell"}]}}"#;
+        let batch = TradeBatch::parse_htx(message, 7).unwrap();
+        assert_eq!((batch.symbol_index, batch.exchange_ts), (7, 1630994963175));
+        assert_eq!(batch.trades(), [
+            Trade { trade_id: 102523573486, ts: 1630994963173, price: 52648.62, amount: 0.006754, side: SIDE_BUY, reserved: 0 },
+            Trade { trade_id: 102523573487, ts: 1630994963174, price: 52648.61, amount: 1.5, side: SIDE_SELL, reserved: 0 },
+        ]);
+    }
+
+    #[test]
+    fn keeps_the_first_trades_of_large_batches() {
+        let trades: Vec<String> = (0..MAX_TRADES + 4)
+            .map(|i| format!(r#"{{"id":{},"ts":1,"tradeId":{},"amount":1.0,"price":2.0,"direction":"buy"}}"#, i, i))
+            .collect();
+        let message = format!(r#"{{"ch":"market.btcusdt.trade.detail","ts":1,"tick":{{"id":1,"ts":1,"data":[{}]}}}}"#, trades.join(","));
+        let batch = TradeBatch::parse_htx(message.as_bytes(), 0).unwrap();
+        assert_eq!(batch.trades().len(), MAX_TRADES);
+        assert_eq!(batch.trades()[MAX_TRADES - 1].trade_id, MAX_TRADES as u64 - 1);
+    }
+
+    #[test]
+    fn parses_depth_step0() {
+        let message = br#"{"ch":"market.btcusdt.depth.step0","ts":1630983549503,"tick":{"bids":[[52690.69,0.36281],[52690.68,0.2]],"asks":[[52690.7,0.372591]],"version":136998124622,"ts":1630983549500}}"#;
+        let depth = Depth::parse_htx(message, 3).unwrap();
+        assert_eq!((depth.symbol_index, depth.version, depth.exchange_ts), (3, 136998124622, 1630983549503));
+        assert_eq!(depth.bids(), [Level { price: 52690.69, size: 0.36281 }, Level { price: 52690.68, size: 0.2 }]);
+        assert_eq!(depth.asks(), [Level { price: 52690.7, size: 0.372591 }]);
+
+        let empty = br#"{"ch":"market.btcusdt.depth.step0","ts":1,"tick":{"bids":[],"asks":[],"version":2,"ts":1}}"#;
+        let depth = Depth::parse_htx(empty, 3).unwrap();
+        assert!(depth.bids().is_empty() && depth.asks().is_empty());
+    }
+
+    #[test]
+    fn parses_kline() {
+        let message = br#"{"ch":"market.btcusdt.kline.1min","ts":1630981694018,"tick":{"id":1630981680,"open":52692.43,"close":52696.4,"low":52692.43,"high":52700.0,"amount":2.43,"vol":128033.12,"count":93}}"#;
+        assert_eq!(Kline::parse_htx(message, 1).unwrap(), Kline {
+            symbol_index: 1,
+            reserved: 0,
+            id: 1630981680,
+            exchange_ts: 1630981694018,
+            open: 52692.43,
+            close: 52696.4,
+            low: 52692.43,
+            high: 52700.0,
+            amount: 2.43,
+            vol: 128033.12,
+            count: 93,
+        });
+    }
+
+    #[test]
+    fn parses_detail() {
+        let message = br#"{"ch":"market.btcusdt.detail","ts":1630998026649,"tick":{"id":273956868110,"low":51000.0,"high":52924.14,"open":51823.62,"close":52379.99,"vol":5.3e8,"amount":10213.5,"version":273956868110,"count":418233}}"#;
+        assert_eq!(MarketDetail::parse_htx(message, 2).unwrap(), MarketDetail {
+            symbol_index: 2,
+            reserved: 0,
+            id: 273956868110,
+            version: 273956868110,
+            exchange_ts: 1630998026649,
+            open: 51823.62,
+            close: 52379.99,
+            low: 51000.0,
+            high: 52924.14,
+            amount: 10213.5,
+            vol: 5.3e8,
+            count: 418233,
+        });
+    }
+
+    #[test]
+    fn rejects_incomplete_messages() {
+        assert!(TradeBatch::parse_htx(br#"{"ch":"market.btcusdt.trade.detail","ts":1}"#, 0).is_none());
+        assert!(Depth::parse_htx(br#"{"ch":"market.btcusdt.depth.step0","ts":1,"tick":{"asks":[]}}"#, 0).is_none());
+        assert!(Kline::parse_htx(br#"{"ch":"market.btcusdt.kline.1min","ts":1,"tick":{"id":1,"open":1.0}}"#, 0).is_none());
+        assert!(MarketDetail::parse_htx(br#"{"ch":"market.btcusdt.detail","tick":{"id":1}}"#, 0).is_none());
+    }
+}
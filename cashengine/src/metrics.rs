@@ -1,42 +1,502 @@
-use std::collections::VecDeque;
+use crate::config::LatencyConfig;
+use crate::feed_source::MessageTimes;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
-pub struct P95Tracker {
-    data: VecDeque<u128>,
-    capacity: usize,
+// Linear sub-buckets per power of two are 2^SUB_BUCKET_BITS, values are kept within 2^-(SUB_BUCKET_BITS - 1), below 1%
+const SUB_BUCKET_BITS: u32 = 8;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+const HALF_SUB_BUCKETS: usize = SUB_BUCKETS / 2;
+// Values below SUB_BUCKETS are exact, every further power of two up to u64::MAX takes half the sub-buckets
+const BUCKETS: usize = SUB_BUCKETS + (u64::BITS - SUB_BUCKET_BITS) as usize * HALF_SUB_BUCKETS;
+
+// Histograms of a rolling window, each covering 1/WINDOW_SLOTS of it
+const WINDOW_SLOTS: usize = 6;
+
+// Upper bounds of the exported latency buckets in nanoseconds, from 1 μs to 10 s in steps of 1, 2.5 and 5
+const EXPORTED_BOUNDS_NANOS: [u64; 22] = [
+    1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000,
+    1_000_000, 2_500_000, 5_000_000, 10_000_000, 25_000_000, 50_000_000, 100_000_000, 250_000_000, 500_000_000,
+    1_000_000_000, 2_500_000_000, 5_000_000_000, 10_000_000_000,
+];
+
+// Log-bucketed histogram in the style of HdrHistogram, with a fixed size and no allocation when recording
+#[derive(Clone)]
+pub struct Histogram {
+    counts: Box<[u64]>,
+    count: u64,
+    min: u64,
+    max: u64,
+}
+
+// Histogram of the last `window`, as a ring of histograms that are cleared once they leave the window
+pub struct RollingHistogram {
+    slots: Vec<Histogram>,
+    slot_nanos: u64,
+    current: usize,
+    // Start of the current slot in `time_util::monotonic_nanos`, 0 before the first value
+    current_start: u64,
+}
+
+// Latency of one measurement in nanoseconds, logged in microseconds with its percentiles every `report_interval_secs`
+pub struct LatencyTracker {
+    name: String,
+    samples: Samples,
+    percentiles: Vec<f64>,
+    report_interval_nanos: u64,
+    window_secs: u64,
+    next_report: u64,
+    export: Option<Arc<ExportedHistogram>>,
+}
+
+// Values covered by a report of a `LatencyTracker`
+enum Samples {
+    // The last `window_secs`
+    Window(RollingHistogram),
+    // All values since the previous report, with `reset_on_report`
+    SinceReport(Histogram),
+}
+
+// Cumulative latency histogram with a few fixed buckets, recorded by one thread and read by the metrics exporter
+#[derive(Default)]
+pub struct ExportedHistogram {
+    // Per bound of EXPORTED_BOUNDS_NANOS, the last one counts the values above all bounds
+    counts: [AtomicU64; EXPORTED_BOUNDS_NANOS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+// Stages of a message from the exchange to the reader, in order
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Stage {
+    // Includes the clock offset to the exchange
+    ExchangeToReceive,
+    ReceiveToInflate,
+    // Parsing and order book updates
+    InflateToWrite,
+    WriteToRead,
+}
+
+// Latency of the stages before the SHM write of the messages of one feed thread. With the reader's
+// write to read latency this tells whether the tail comes from the network, inflating or the engine.
+pub struct FeedLatency {
+    exchange_to_receive: LatencyTracker,
+    receive_to_inflate: LatencyTracker,
+    inflate_to_write: LatencyTracker,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Histogram { counts: vec![0; BUCKETS].into_boxed_slice(), count: 0, min: u64::MAX, max: 0 }
+    }
+
+    pub fn record(&mut self, value: u64) {
+        self.counts[bucket_index(value)] += 1;
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    // None while empty
+    pub fn max(&self) -> Option<u64> {
+        (self.count > 0).then_some(self.max)
+    }
+
+    // Highest value equivalent to the value at `percentile` (0-100], exact for the max, None while empty
+    pub fn percentile(&self, percentile: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((percentile / 100.0 * self.count as f64).ceil() as u64).clamp(1, self.count);
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(bucket_high(index).clamp(self.min, self.max));
+            }
+        }
+        Some(self.max)
+    }
+
+    pub fn add(&mut self, other: &Histogram) {
+        for (count, other_count) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += other_count;
+        }
+        self.count += other.count;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    pub fn reset(&mut self) {
+        if self.count > 0 {
+            self.counts.fill(0);
+        }
+        self.count = 0;
+        self.min = u64::MAX;
+        self.max = 0;
+    }
 }
 
-impl P95Tracker {
-    pub fn new(capacity: usize) -> Self {
-        P95Tracker {
-            data: VecDeque::with_capacity(capacity),
-            capacity,
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram::new()
+    }
+}
+
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS as u64 {
+        return value as usize;
+    }
+    // Keeps the SUB_BUCKET_BITS highest bits of the value, the highest one is always set
+    let shift = u64::BITS - value.leading_zeros() - SUB_BUCKET_BITS;
+    let sub_bucket = (value >> shift) as usize - HALF_SUB_BUCKETS;
+    SUB_BUCKETS + (shift as usize - 1) * HALF_SUB_BUCKETS + sub_bucket
+}
+
+// Highest value recorded into bucket `index`
+fn bucket_high(index: usize) -> u64 {
+    if index < SUB_BUCKETS {
+        return index as u64;
+    }
+    let shift = ((index - SUB_BUCKETS) / HALF_SUB_BUCKETS + 1) as u32;
+    let sub_bucket = ((index - SUB_BUCKETS) % HALF_SUB_BUCKETS + HALF_SUB_BUCKETS) as u64;
+    // The last bucket ends at u64::MAX
+    ((sub_bucket + 1) << shift).wrapping_sub(1)
+}
+
+impl RollingHistogram {
+    pub fn new(window_secs: u64) -> Self {
+        RollingHistogram {
+            slots: vec![Histogram::new(); WINDOW_SLOTS],
+            slot_nanos: (window_secs * 1_000_000_000 / WINDOW_SLOTS as u64).max(1),
+            current: 0,
+            current_start: 0,
+        }
+    }
+
+    pub fn record(&mut self, value: u64, now_nanos: u64) {
+        self.rotate(now_nanos);
+        self.slots[self.current].record(value);
+    }
+
+    // The values of the last `window_secs`
+    pub fn snapshot(&mut self, now_nanos: u64) -> Histogram {
+        self.rotate(now_nanos);
+        let mut histogram = Histogram::new();
+        for slot in &self.slots {
+            histogram.add(slot);
+        }
+        histogram
+    }
+
+    fn rotate(&mut self, now_nanos: u64) {
+        if self.current_start == 0 {
+            self.current_start = now_nanos;
         }
+        let elapsed_slots = now_nanos.saturating_sub(self.current_start) / self.slot_nanos;
+        if elapsed_slots == 0 {
+            return;
+        }
+        for _ in 0..elapsed_slots.min(self.slots.len() as u64) {
+            self.current = (self.current + 1) % self.slots.len();
+            self.slots[self.current].reset();
+        }
+        self.current_start += elapsed_slots * self.slot_nanos;
     }
+}
 
-    pub fn push(&mut self, value: u128) {
-        if self.data.len() < self.capacity {
-            // If we haven't reached capacity, just insert and sort
-            self.data.push_back(value);
-            self.data.make_contiguous().sort_unstable();
-        } else if value < *self.data.back().unwrap() {
-            // If the new value is smaller than the largest in our list
-            // remove the largest and insert the new value
-            self.data.pop_back();
-            let insert_pos = self.data.binary_search(&value).unwrap_or_else(|e| e);
-            self.data.insert(insert_pos, value);
+impl LatencyTracker {
+    pub fn new(name: &str, config: &LatencyConfig) -> Self {
+        LatencyTracker {
+            name: name.to_string(),
+            samples: if config.reset_on_report {
+                Samples::SinceReport(Histogram::new())
+            } else {
+                Samples::Window(RollingHistogram::new(config.window_secs))
+            },
+            percentiles: config.percentiles.clone(),
+            report_interval_nanos: config.report_interval_secs * 1_000_000_000,
+            window_secs: config.window_secs,
+            next_report: 0,
+            export: None,
         }
     }
 
-    pub fn has_enough_samples(&self) -> bool {
-        self.data.len() == self.data.capacity()
+    // Also records every value into `histogram`, which is never reset
+    pub fn export(&mut self, histogram: Arc<ExportedHistogram>) {
+        self.export = Some(histogram);
     }
 
-    pub fn p95(&self) -> Option<u128> {
-        if self.data.is_empty() {
-            None
-        } else {
-            let index = (self.data.len() as f64 * 0.95).ceil() as usize - 1;
-            Some(self.data[index])
+    // `now_nanos` from `time_util::monotonic_nanos`, reports when the report interval passed
+    pub fn record(&mut self, latency_nanos: u64, now_nanos: u64) {
+        match &mut self.samples {
+            Samples::Window(window) => window.record(latency_nanos, now_nanos),
+            Samples::SinceReport(histogram) => histogram.record(latency_nanos),
+        }
+        if let Some(export) = &self.export {
+            export.record(latency_nanos);
+        }
+        if self.next_report == 0 {
+            self.next_report = now_nanos + self.report_interval_nanos;
+        } else if now_nanos >= self.next_report {
+            self.report(now_nanos);
+            self.next_report = now_nanos + self.report_interval_nanos;
         }
     }
+
+    // Also called once more when a replay ends, before the next report would be due
+    pub fn report(&mut self, now_nanos: u64) {
+        let (histogram, period) = match &mut self.samples {
+            Samples::Window(window) => (window.snapshot(now_nanos), format!("last {} s", self.window_secs)),
+            Samples::SinceReport(histogram) => (std::mem::take(histogram), "since the last report".to_string()),
+        };
+        let mut line = String::new();
+        for percentile in &self.percentiles {
+            line.push_str(&format!("p{} {} μs, ", percentile, micros(histogram.percentile(*percentile).unwrap_or_default())));
+        }
+        tracing::info!("{} latency ({}, {} samples): {}max {} μs",
+            self.name, period, histogram.count(), line, micros(histogram.max().unwrap_or_default()));
+    }
+}
+
+// E.g. 12.3 for 12345 ns
+fn micros(nanos: u64) -> String {
+    format!("{:.1}", nanos as f64 / 1_000.0)
+}
+
+impl ExportedHistogram {
+    pub fn record(&self, nanos: u64) {
+        let bucket = EXPORTED_BOUNDS_NANOS.partition_point(|bound| *bound < nanos);
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    // Upper bounds in nanoseconds with the number of values up to them, the last bound is None for all values
+    pub fn cumulative_counts(&self) -> Vec<(Option<u64>, u64)> {
+        let mut total = 0;
+        self.counts.iter().enumerate()
+            .map(|(bucket, count)| {
+                total += count.load(Ordering::Relaxed);
+                (EXPORTED_BOUNDS_NANOS.get(bucket).copied(), total)
+            })
+            .collect()
+    }
+
+    pub fn sum_nanos(&self) -> u64 {
+        self.sum_nanos.load(Ordering::Relaxed)
+    }
+}
+
+impl Stage {
+    pub const ALL: [Stage; 4] = [Stage::ExchangeToReceive, Stage::ReceiveToInflate, Stage::InflateToWrite, Stage::WriteToRead];
+
+    // For logs, e.g. "receive to inflate"
+    pub fn description(self) -> &'static str {
+        match self {
+            Stage::ExchangeToReceive => "exchange to receive",
+            Stage::ReceiveToInflate => "receive to inflate",
+            Stage::InflateToWrite => "inflate to SHM write",
+            Stage::WriteToRead => "SHM write to read",
+        }
+    }
+
+    // For metric labels, e.g. "receive_to_inflate"
+    pub fn label(self) -> &'static str {
+        match self {
+            Stage::ExchangeToReceive => "exchange_to_receive",
+            Stage::ReceiveToInflate => "receive_to_inflate",
+            Stage::InflateToWrite => "inflate_to_write",
+            Stage::WriteToRead => "write_to_read",
+        }
+    }
+
+    // e.g. "Feed thread 3 receive to inflate"
+    pub fn tracker_name(self, thread_id: usize) -> String {
+        format!("Feed thread {} {}", thread_id, self.description())
+    }
+}
+
+impl FeedLatency {
+    pub fn new(thread_id: usize, config: &LatencyConfig) -> Self {
+        FeedLatency {
+            exchange_to_receive: LatencyTracker::new(&Stage::ExchangeToReceive.tracker_name(thread_id), config),
+            receive_to_inflate: LatencyTracker::new(&Stage::ReceiveToInflate.tracker_name(thread_id), config),
+            inflate_to_write: LatencyTracker::new(&Stage::InflateToWrite.tracker_name(thread_id), config),
+        }
+    }
+
+    // Exports the stages before the SHM write into the histograms returned by `histogram`
+    pub fn export(&mut self, histogram: impl Fn(Stage) -> Arc<ExportedHistogram>) {
+        self.exchange_to_receive.export(histogram(Stage::ExchangeToReceive));
+        self.receive_to_inflate.export(histogram(Stage::ReceiveToInflate));
+        self.inflate_to_write.export(histogram(Stage::InflateToWrite));
+    }
+
+    // Records the stages of a message written at `write_nanos`, stages without timestamps are left out
+    pub fn record(&mut self, times: &MessageTimes, write_nanos: u64) {
+        if times.exchange_ms > 0 && times.received_epoch_nanos > 0 {
+            let latency = times.received_epoch_nanos.saturating_sub(times.exchange_ms * 1_000_000);
+            self.exchange_to_receive.record(latency, write_nanos);
+        }
+        if times.received_nanos > 0 && times.inflated_nanos > 0 {
+            self.receive_to_inflate.record(times.inflated_nanos.saturating_sub(times.received_nanos), write_nanos);
+        }
+        if times.inflated_nanos > 0 {
+            self.inflate_to_write.record(write_nanos.saturating_sub(times.inflated_nanos), write_nanos);
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Counter {
+    value: AtomicU64,
+}
+
+impl Counter {
+    pub fn new() -> Self {
+        Counter {
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn increment(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub struct Gauge {
+    value: AtomicU64,
+}
+
+impl Gauge {
+    pub fn set(&self, value: u64) {
+        self.value.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+// Counted by a websocket connection, shared with the metrics exporter
+#[derive(Default)]
+pub struct ConnectionMetrics {
+    pub messages: Counter,
+    pub reconnects: Counter,
+    pub inflate_errors: Counter,
+    // HTX ping messages and websocket ping frames
+    pub pings: Counter,
+    // Answers to `pings`
+    pub pongs: Counter,
+    // 1 while connected, 0 while waiting for a reconnect
+    pub connected: Gauge,
+    // Largest inflated message in bytes
+    pub max_message_size: Gauge,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn histogram(values: impl IntoIterator<Item = u64>) -> Histogram {
+        let mut histogram = Histogram::new();
+        for value in values {
+            histogram.record(value);
+        }
+        histogram
+    }
+
+    fn tracker(reset_on_report: bool) -> LatencyTracker {
+        LatencyTracker::new("test", &LatencyConfig { report_interval_secs: 1, reset_on_report, ..LatencyConfig::default() })
+    }
+
+    fn samples(tracker: &mut LatencyTracker, now_nanos: u64) -> u64 {
+        match &mut tracker.samples {
+            Samples::Window(window) => window.snapshot(now_nanos).count(),
+            Samples::SinceReport(histogram) => histogram.count(),
+        }
+    }
+
+    #[test]
+    fn keeps_values_below_the_linear_range_exact() {
+        for value in 0..SUB_BUCKETS as u64 {
+            assert_eq!(bucket_index(value), value as usize);
+            assert_eq!(bucket_high(bucket_index(value)), value);
+        }
+        let histogram = histogram([3, 3, 7, 200]);
+        assert_eq!(histogram.percentile(50.0), Some(3));
+        assert_eq!(histogram.percentile(75.0), Some(7));
+        assert_eq!(histogram.percentile(76.0), Some(200));
+    }
+
+    #[test]
+    fn buckets_cover_their_values() {
+        let mut value = SUB_BUCKETS as u64;
+        while value < u64::MAX / 3 {
+            let index = bucket_index(value);
+            assert!(bucket_high(index) >= value, "{}", value);
+            assert!(index == 0 || bucket_high(index - 1) < value, "{}", value);
+            value = value * 3 / 2 + 1;
+        }
+    }
+
+    #[test]
+    fn percentiles_stay_within_the_relative_error() {
+        let values: Vec<u64> = (1..=10_000).map(|i| i * 7_919).collect();
+        let histogram = histogram(values.iter().copied());
+        let max_error = 1.0 / HALF_SUB_BUCKETS as f64;
+        for percentile in [1.0, 25.0, 50.0, 90.0, 99.0, 99.9] {
+            let exact = values[(percentile / 100.0 * values.len() as f64).ceil() as usize - 1];
+            let reported = histogram.percentile(percentile).unwrap();
+            assert!(reported >= exact, "p{} {} below {}", percentile, reported, exact);
+            assert!((reported - exact) as f64 / exact as f64 <= max_error, "p{} {} too far from {}", percentile, reported, exact);
+        }
+    }
+
+    #[test]
+    fn p100_is_the_max() {
+        let histogram = histogram([1_000, 123_456_789, 5_000]);
+        assert_eq!(histogram.percentile(100.0), Some(123_456_789));
+        assert_eq!(histogram.max(), Some(123_456_789));
+        assert_eq!(Histogram::new().percentile(100.0), None);
+    }
+
+    #[test]
+    fn records_u64_max_into_the_last_bucket() {
+        assert_eq!(bucket_index(u64::MAX), BUCKETS - 1);
+        assert_eq!(bucket_high(BUCKETS - 1), u64::MAX);
+        let histogram = histogram([u64::MAX, 1 << 63]);
+        assert_eq!(histogram.percentile(100.0), Some(u64::MAX));
+        assert_eq!(histogram.percentile(50.0), Some((1 << 63) + (1 << 56) - 1));
+    }
+
+    #[test]
+    fn reset_on_report_clears_the_values() {
+        let mut reset = tracker(true);
+        reset.record(5_000, SECOND);
+        reset.record(6_000, SECOND + 1);
+        assert_eq!(samples(&mut reset, SECOND + 1), 2);
+        // Reported, the values before and including the reporting one are cleared
+        reset.record(7_000, 2 * SECOND);
+        assert_eq!(samples(&mut reset, 2 * SECOND), 0);
+        reset.record(8_000, 2 * SECOND + 1);
+        assert_eq!(samples(&mut reset, 2 * SECOND + 1), 1);
+
+        // The window keeps them until they are older than `window_secs`
+        let mut window = tracker(false);
+        window.record(5_000, SECOND);
+        window.record(7_000, 2 * SECOND);
+        assert_eq!(samples(&mut window, 2 * SECOND), 2);
+        assert_eq!(samples(&mut window, 100 * SECOND), 0);
+    }
 }
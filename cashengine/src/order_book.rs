@@ -0,0 +1,497 @@
+use crate::bbo::{find, number_after};
+use crate::market_data::{for_each_level, Depth, Level, MAX_DEPTH_LEVELS};
+use crate::shm_block_writer::SharedMemoryWriter;
+use std::time::{Duration, Instant};
+
+// Order books maintained from `market.$symbol.mbp.$levels` incremental updates.
+// A book is seeded from the `req` snapshot, every update must continue the previous `seqNum`.
+// On a gap the book is reset and seeded again, updates received meanwhile are buffered and replayed.
+// HTX also serves mbp.400, but a `Depth` holds only `MAX_DEPTH_LEVELS` levels per side.
+pub const MBP_LEVELS: &[usize] = &[5, 20, 150];
+
+// A snapshot that didn't arrive within this time is requested again
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(5);
+// Buffered updates beyond this are dropped, the next snapshot is newer than them anyway
+const MAX_PENDING_UPDATES: usize = 1024;
+
+static TS: &[u8] = b"\"ts\":";
+static SEQ_NUM: &[u8] = b"\"seqNum\":";
+static PREV_SEQ_NUM: &[u8] = b"\"prevSeqNum\":";
+static BIDS: &[u8] = b"\"bids\":";
+static ASKS: &[u8] = b"\"asks\":";
+
+// Incremental update or snapshot, sizes of 0 remove the level
+#[derive(Clone, Debug, Default)]
+pub struct MbpUpdate {
+    pub seq_num: u64,
+    // None for snapshots
+    pub prev_seq_num: Option<u64>,
+    pub exchange_ts: u64,
+    pub bids: Vec<Level>,
+    pub asks: Vec<Level>,
+}
+
+impl MbpUpdate {
+    // Parses into `self` to reuse the level buffers. Incremental update and snapshot reply:
+    // {"ch":"market.btcusdt.mbp.150","ts":1,"tick":{"seqNum":2,"prevSeqNum":1,"bids":[[1.0,0]],"asks":[[1.1,2.0]]}}
+    // {"id":"id1","rep":"market.btcusdt.mbp.150","status":"ok","data":{"seqNum":2,"bids":[[1.0,2.0]],"asks":[[1.1,2.0]]}}
+    pub fn parse_htx(&mut self, message: &[u8]) -> Option<()> {
+        self.seq_num = number_after(message, SEQ_NUM)?;
+        self.prev_seq_num = number_after(message, PREV_SEQ_NUM);
+        self.exchange_ts = number_after(message, TS).unwrap_or(0);
+        self.bids.clear();
+        self.asks.clear();
+        // Sides without changes may be omitted
+        let bids = &mut self.bids;
+        if find(message, BIDS).is_some() {
+            for_each_level(message, BIDS, |level| bids.push(level))?;
+        }
+        let asks = &mut self.asks;
+        if find(message, ASKS).is_some() {
+            for_each_level(message, ASKS, |level| asks.push(level))?;
+        }
+        Some(())
+    }
+
+    pub fn is_snapshot(&self) -> bool {
+        self.prev_seq_num.is_none()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BookState {
+    AwaitingSnapshot,
+    Synced,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BookEvent {
+    Updated,
+    // Buffered until the snapshot arrives
+    Pending,
+    // The update didn't continue the book, which was reset
+    Gap { seq_num: u64, prev_seq_num: Option<u64> },
+}
+
+pub struct OrderBook {
+    max_levels: usize,
+    state: BookState,
+    seq_num: u64,
+    exchange_ts: u64,
+    // Best first
+    bids: Vec<Level>,
+    asks: Vec<Level>,
+    pending: Vec<MbpUpdate>,
+    snapshot_requested_at: Option<Instant>,
+}
+
+impl OrderBook {
+    pub fn new(max_levels: usize) -> OrderBook {
+        OrderBook {
+            max_levels,
+            state: BookState::AwaitingSnapshot,
+            seq_num: 0,
+            exchange_ts: 0,
+            bids: Vec::with_capacity(max_levels + 1),
+            asks: Vec::with_capacity(max_levels + 1),
+            pending: Vec::new(),
+            snapshot_requested_at: None,
+        }
+    }
+
+    pub fn state(&self) -> BookState {
+        self.state
+    }
+
+    pub fn seq_num(&self) -> u64 {
+        self.seq_num
+    }
+
+    pub fn bids(&self) -> &[Level] {
+        &self.bids
+    }
+
+    pub fn asks(&self) -> &[Level] {
+        &self.asks
+    }
+
+    pub fn best_bid(&self) -> Option<&Level> {
+        self.bids.first()
+    }
+
+    pub fn best_ask(&self) -> Option<&Level> {
+        self.asks.first()
+    }
+
+    pub fn apply_update(&mut self, update: &MbpUpdate) -> BookEvent {
+        if self.state == BookState::AwaitingSnapshot {
+            self.buffer(update);
+            return BookEvent::Pending;
+        }
+        if update.prev_seq_num != Some(self.seq_num) {
+            let gap = BookEvent::Gap { seq_num: self.seq_num, prev_seq_num: update.prev_seq_num };
+            self.reset();
+            self.buffer(update);
+            return gap;
+        }
+        self.apply_levels(update);
+        BookEvent::Updated
+    }
+
+    // Seeds the book and replays the buffered updates following the snapshot.
+    // Returns false if the snapshot is older than the buffered updates and another one is needed.
+    pub fn apply_snapshot(&mut self, snapshot: &MbpUpdate) -> bool {
+        if self.state == BookState::Synced {
+            // Reply to a repeated request, the book is already ahead of it
+            return true;
+        }
+        self.bids.clear();
+        self.asks.clear();
+        self.seq_num = snapshot.seq_num;
+        self.apply_levels(snapshot);
+        self.state = BookState::Synced;
+        self.snapshot_requested_at = None;
+
+        let mut pending = std::mem::take(&mut self.pending);
+        pending.retain(|update| update.seq_num > snapshot.seq_num);
+        for (i, update) in pending.iter().enumerate() {
+            if update.prev_seq_num != Some(self.seq_num) {
+                tracing::debug!("Snapshot seqNum {} doesn't connect to buffered update prevSeqNum {:?}",
+                                snapshot.seq_num, update.prev_seq_num);
+                self.reset();
+                self.pending = pending.split_off(i);
+                return false;
+            }
+            self.apply_levels(update);
+        }
+        pending.clear();
+        // Keep the allocation for the next resync
+        self.pending = pending;
+        true
+    }
+
+    pub fn reset(&mut self) {
+        self.state = BookState::AwaitingSnapshot;
+        self.snapshot_requested_at = None;
+        self.bids.clear();
+        self.asks.clear();
+        self.pending.clear();
+    }
+
+    // True if the book waits for a snapshot that wasn't requested yet or timed out
+    pub fn needs_snapshot(&self, now: Instant) -> bool {
+        self.state == BookState::AwaitingSnapshot
+            && self.snapshot_requested_at.is_none_or(|at| now.duration_since(at) >= SNAPSHOT_TIMEOUT)
+    }
+
+    pub fn snapshot_requested(&mut self, now: Instant) {
+        self.snapshot_requested_at = Some(now);
+    }
+
+    // Copies the best `MAX_DEPTH_LEVELS` levels into `depth`, `version` is the book's seqNum
+    pub fn fill_depth(&self, depth: &mut Depth) {
+        let bid_count = self.bids.len().min(MAX_DEPTH_LEVELS);
+        let ask_count = self.asks.len().min(MAX_DEPTH_LEVELS);
+        depth.bids[..bid_count].copy_from_slice(&self.bids[..bid_count]);
+        depth.asks[..ask_count].copy_from_slice(&self.asks[..ask_count]);
+        depth.bid_count = bid_count as u32;
+        depth.ask_count = ask_count as u32;
+        depth.version = self.seq_num;
+        depth.exchange_ts = self.exchange_ts;
+    }
+
+    fn buffer(&mut self, update: &MbpUpdate) {
+        if self.pending.len() == MAX_PENDING_UPDATES {
+            self.pending.clear();
+        }
+        self.pending.push(update.clone());
+    }
+
+    fn apply_levels(&mut self, update: &MbpUpdate) {
+        for level in &update.bids {
+            update_side(&mut self.bids, *level, true, self.max_levels);
+        }
+        for level in &update.asks {
+            update_side(&mut self.asks, *level, false, self.max_levels);
+        }
+        self.seq_num = update.seq_num;
+        if update.exchange_ts != 0 {
+            self.exchange_ts = update.exchange_ts;
+        }
+    }
+}
+
+fn update_side(levels: &mut Vec<Level>, level: Level, descending: bool, max_levels: usize) {
+    let position = levels.binary_search_by(|probe| {
+        if descending {
+            level.price.total_cmp(&probe.price)
+        } else {
+            probe.price.total_cmp(&level.price)
+        }
+    });
+    match position {
+        Ok(i) if level.size == 0.0 => {
+            levels.remove(i);
+        }
+        Ok(i) => levels[i].size = level.size,
+        Err(_) if level.size == 0.0 => {}
+        Err(i) => {
+            if i < max_levels {
+                levels.insert(i, level);
+                levels.truncate(max_levels);
+            }
+        }
+    }
+}
+
+// The order books of the markets of one feed thread, indexed like its chunks
+pub(crate) struct OrderBooks {
+    max_levels: usize,
+    books: Vec<OrderBook>,
+    // `market.$symbol.mbp.$levels` per book, for snapshot requests
+    topics: Vec<String>,
+    update: MbpUpdate,
+    depth: Box<Depth>,
+}
+
+impl OrderBooks {
+    pub(crate) fn new(topics: Vec<String>, max_levels: usize) -> OrderBooks {
+        OrderBooks {
+            max_levels,
+            books: topics.iter().map(|_| OrderBook::new(max_levels)).collect(),
+            topics,
+            update: MbpUpdate::default(),
+            depth: Box::new(Depth {
+                symbol_index: 0,
+                reserved: 0,
+                bid_count: 0,
+                ask_count: 0,
+                version: 0,
+                exchange_ts: 0,
+                bids: [Level::default(); MAX_DEPTH_LEVELS],
+                asks: [Level::default(); MAX_DEPTH_LEVELS],
+            }),
+        }
+    }
+
+    // Starts an empty book for a market subscribed while running
+    pub(crate) fn add(&mut self, chunk_index: usize, topic: String) {
+        if chunk_index >= self.books.len() {
+            self.books.resize_with(chunk_index + 1, || OrderBook::new(self.max_levels));
+            self.topics.resize(chunk_index + 1, String::new());
+        }
+        self.books[chunk_index] = OrderBook::new(self.max_levels);
+        self.topics[chunk_index] = topic;
+    }
+
+    // Drops the book of an unsubscribed market
+    pub(crate) fn remove(&mut self, chunk_index: usize) {
+        if let Some(book) = self.books.get_mut(chunk_index) {
+            *book = OrderBook::new(self.max_levels);
+        }
+    }
+
+    // Applies a snapshot reply or an incremental update to the book at `chunk_index` and publishes it when synced.
+    // Snapshot requests are pushed into `requests`. Returns false if the message could not be parsed.
+    pub(crate) fn on_message(
+        &mut self,
+        shm_writer: &mut SharedMemoryWriter,
+        chunk_index: usize,
+        symbol_index: u32,
+        message: &[u8],
+        requests: &mut Vec<String>,
+    ) -> bool {
+        if self.update.parse_htx(message).is_none() {
+            return false;
+        }
+        let book = &mut self.books[chunk_index];
+        let topic = self.topics[chunk_index].as_str();
+        let publish = if self.update.is_snapshot() {
+            let synced = book.apply_snapshot(&self.update);
+            if synced {
+                tracing::info!("Order book {} synced at seqNum {}", topic, book.seq_num());
+            }
+            synced
+        } else {
+            match book.apply_update(&self.update) {
+                BookEvent::Updated => true,
+                BookEvent::Pending => false,
+                BookEvent::Gap { seq_num, prev_seq_num } => {
+                    tracing::warn!("Order book {} has a gap, book seqNum {}, update prevSeqNum {:?}, resyncing",
+                                   topic, seq_num, prev_seq_num);
+                    false
+                }
+            }
+        };
+
+        if publish {
+            self.depth.symbol_index = symbol_index;
+            book.fill_depth(&mut self.depth);
+            shm_writer.write_typed(chunk_index, &*self.depth);
+        } else {
+            let now = Instant::now();
+            if book.needs_snapshot(now) {
+                book.snapshot_requested(now);
+                requests.push(format!("{{\"req\":\"{}\",\"id\":\"{}\"}}", topic, topic));
+            }
+        }
+        true
+    }
+}
+
+const _: () = assert!(MBP_LEVELS[MBP_LEVELS.len() - 1] <= MAX_DEPTH_LEVELS);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shm_chunk;
+    use crate::shm_file::{self, FileHeader};
+    use crate::shm_reader::SharedMemoryReader;
+
+    fn levels(levels: &[(f64, f64)]) -> Vec<Level> {
+        levels.iter().map(|&(price, size)| Level { price, size }).collect()
+    }
+
+    fn update(seq_num: u64, prev_seq_num: u64, bids: &[(f64, f64)], asks: &[(f64, f64)]) -> MbpUpdate {
+        MbpUpdate { seq_num, prev_seq_num: Some(prev_seq_num), exchange_ts: seq_num, bids: levels(bids), asks: levels(asks) }
+    }
+
+    fn snapshot(seq_num: u64, bids: &[(f64, f64)], asks: &[(f64, f64)]) -> MbpUpdate {
+        MbpUpdate { prev_seq_num: None, ..update(seq_num, 0, bids, asks) }
+    }
+
+    fn synced_book() -> OrderBook {
+        let mut book = OrderBook::new(5);
+        assert!(book.apply_snapshot(&snapshot(10, &[(100.0, 1.0), (99.0, 2.0)], &[(101.0, 1.5), (102.0, 3.0)])));
+        book
+    }
+
+    #[test]
+    fn parses_updates_and_snapshots() {
+        let mut update = MbpUpdate::default();
+        update.parse_htx(br#"{"ch":"market.btcusdt.mbp.150","ts":7,"tick":{"seqNum":2,"prevSeqNum":1,"bids":[[1.0,0]],"asks":[[1.1,2.0]]}}"#).unwrap();
+        assert_eq!((update.seq_num, update.prev_seq_num, update.exchange_ts), (2, Some(1), 7));
+        assert_eq!((update.bids.clone(), update.asks.clone()), (levels(&[(1.0, 0.0)]), levels(&[(1.1, 2.0)])));
+
+        update.parse_htx(br#"{"id":"id1","rep":"market.btcusdt.mbp.150","status":"ok","data":{"seqNum":5,"bids":[[1.0,2.0]]}}"#).unwrap();
+        assert!(update.is_snapshot());
+        assert_eq!((update.seq_num, update.asks.len()), (5, 0));
+    }
+
+    #[test]
+    fn applies_updates_that_continue_the_book() {
+        let mut book = synced_book();
+        assert_eq!(book.apply_update(&update(11, 10, &[(100.5, 4.0)], &[(101.0, 2.5)])), BookEvent::Updated);
+        assert_eq!(book.bids(), levels(&[(100.5, 4.0), (100.0, 1.0), (99.0, 2.0)]));
+        assert_eq!(book.asks(), levels(&[(101.0, 2.5), (102.0, 3.0)]));
+        assert_eq!(book.seq_num(), 11);
+    }
+
+    #[test]
+    fn removes_levels_with_zero_size() {
+        let mut book = synced_book();
+        assert_eq!(book.apply_update(&update(11, 10, &[(100.0, 0.0), (98.0, 0.0)], &[(102.0, 0.0)])), BookEvent::Updated);
+        assert_eq!(book.bids(), levels(&[(99.0, 2.0)]));
+        assert_eq!(book.asks(), levels(&[(101.0, 1.5)]));
+    }
+
+    #[test]
+    fn resets_the_book_on_a_gap() {
+        let mut book = synced_book();
+        let now = Instant::now();
+        assert!(!book.needs_snapshot(now));
+        assert_eq!(book.apply_update(&update(13, 12, &[(100.5, 4.0)], &[])), BookEvent::Gap { seq_num: 10, prev_seq_num: Some(12) });
+        assert_eq!(book.state(), BookState::AwaitingSnapshot);
+        assert!(book.bids().is_empty() && book.asks().is_empty());
+        assert!(book.needs_snapshot(now));
+        book.snapshot_requested(now);
+        assert!(!book.needs_snapshot(now));
+        assert!(book.needs_snapshot(now + SNAPSHOT_TIMEOUT));
+
+        // The update that revealed the gap is replayed after the next snapshot
+        assert!(book.apply_snapshot(&snapshot(12, &[(100.0, 1.0)], &[(101.0, 1.0)])));
+        assert_eq!(book.seq_num(), 13);
+        assert_eq!(book.bids(), levels(&[(100.5, 4.0), (100.0, 1.0)]));
+    }
+
+    #[test]
+    fn drops_buffered_updates_up_to_the_snapshot() {
+        let mut book = OrderBook::new(5);
+        assert_eq!(book.apply_update(&update(9, 8, &[(90.0, 1.0)], &[])), BookEvent::Pending);
+        assert_eq!(book.apply_update(&update(10, 9, &[(91.0, 1.0)], &[])), BookEvent::Pending);
+        assert_eq!(book.apply_update(&update(11, 10, &[(100.0, 5.0)], &[])), BookEvent::Pending);
+        assert!(book.apply_snapshot(&snapshot(10, &[(100.0, 1.0)], &[(101.0, 1.0)])));
+        assert_eq!(book.state(), BookState::Synced);
+        assert_eq!(book.seq_num(), 11);
+        assert_eq!(book.bids(), levels(&[(100.0, 5.0)]));
+        assert_eq!(book.apply_update(&update(12, 11, &[], &[(101.0, 0.0)])), BookEvent::Updated);
+        assert!(book.asks().is_empty());
+    }
+
+    #[test]
+    fn requests_another_snapshot_if_it_does_not_connect() {
+        let mut book = OrderBook::new(5);
+        assert_eq!(book.apply_update(&update(21, 20, &[(100.0, 5.0)], &[])), BookEvent::Pending);
+        assert_eq!(book.apply_update(&update(22, 21, &[(100.0, 6.0)], &[])), BookEvent::Pending);
+        // Older than the first buffered update
+        assert!(!book.apply_snapshot(&snapshot(15, &[(100.0, 1.0)], &[])));
+        assert_eq!(book.state(), BookState::AwaitingSnapshot);
+        assert!(book.needs_snapshot(Instant::now()));
+
+        // The buffered updates are kept for the next snapshot
+        assert!(book.apply_snapshot(&snapshot(20, &[(100.0, 1.0)], &[])));
+        assert_eq!(book.seq_num(), 22);
+        assert_eq!(book.bids(), levels(&[(100.0, 6.0)]));
+    }
+
+    #[test]
+    fn keeps_the_best_levels_up_to_the_depth_capacity() {
+        let mut book = OrderBook::new(3);
+        assert!(book.apply_snapshot(&snapshot(1, &[(1.0, 1.0), (2.0, 1.0), (3.0, 1.0), (4.0, 1.0)], &[(5.0, 1.0)])));
+        assert_eq!(book.bids(), levels(&[(4.0, 1.0), (3.0, 1.0), (2.0, 1.0)]));
+        // Worse than the worst kept level
+        assert_eq!(book.apply_update(&update(2, 1, &[(0.5, 1.0)], &[])), BookEvent::Updated);
+        assert_eq!(book.bids().len(), 3);
+        assert_eq!(book.bids()[2].price, 2.0);
+
+        let capacity = MAX_DEPTH_LEVELS + 10;
+        let mut book = OrderBook::new(capacity);
+        let bids: Vec<(f64, f64)> = (0..capacity).map(|i| (i as f64, 1.0)).collect();
+        let asks: Vec<(f64, f64)> = (0..capacity).map(|i| (1000.0 + i as f64, 1.0)).collect();
+        assert!(book.apply_snapshot(&snapshot(7, &bids, &asks)));
+        let mut depth = OrderBooks::new(Vec::new(), capacity).depth;
+        book.fill_depth(&mut depth);
+        assert_eq!((depth.bid_count as usize, depth.ask_count as usize, depth.version), (MAX_DEPTH_LEVELS, MAX_DEPTH_LEVELS, 7));
+        assert_eq!(depth.bids()[0].price, (capacity - 1) as f64);
+        assert_eq!(depth.asks()[MAX_DEPTH_LEVELS - 1].price, 1000.0 + (MAX_DEPTH_LEVELS - 1) as f64);
+    }
+
+    #[test]
+    fn publishes_synced_books_and_requests_snapshots() {
+        let path = std::env::temp_dir().join(format!("cashengine-order-book-{}.mmap", std::process::id()));
+        let file = shm_file::create(path.to_str().unwrap(), &FileHeader::new(shm_chunk::chunk_size_for::<Depth>(), 1, 1)).unwrap();
+        let mut writer = SharedMemoryWriter::create(&file, 0).unwrap();
+        let topic = "market.btcusdt.mbp.5";
+        let mut books = OrderBooks::new(vec![topic.to_string()], 5);
+        let mut requests = Vec::new();
+
+        let first = br#"{"ch":"market.btcusdt.mbp.5","ts":1,"tick":{"seqNum":11,"prevSeqNum":10,"bids":[[100.0,2.0]],"asks":[]}}"#;
+        assert!(books.on_message(&mut writer, 0, 0, first, &mut requests));
+        assert_eq!(requests, [r#"{"req":"market.btcusdt.mbp.5","id":"market.btcusdt.mbp.5"}"#]);
+        let snapshot = br#"{"id":"market.btcusdt.mbp.5","rep":"market.btcusdt.mbp.5","status":"ok","data":{"seqNum":10,"bids":[[100.0,1.0]],"asks":[[101.0,1.0]]}}"#;
+        assert!(books.on_message(&mut writer, 0, 0, snapshot, &mut requests));
+        let mut reader = SharedMemoryReader::attach(&file).unwrap();
+        let depth = *reader.read_chunk(0).unwrap().depth().unwrap();
+        assert_eq!((depth.version, depth.bids()[0].size, depth.asks()[0].price), (11, 2.0, 101.0));
+
+        // A gap resets the book and requests a new snapshot, nothing is published until it arrives
+        requests.clear();
+        let gap = br#"{"ch":"market.btcusdt.mbp.5","ts":2,"tick":{"seqNum":14,"prevSeqNum":13,"bids":[[100.0,3.0]]}}"#;
+        assert!(books.on_message(&mut writer, 0, 0, gap, &mut requests));
+        assert_eq!(requests.len(), 1);
+        assert_eq!(books.books[0].state(), BookState::AwaitingSnapshot);
+        assert_eq!(reader.read_chunk(0).unwrap().depth().unwrap().version, 11);
+        assert!(!books.on_message(&mut writer, 0, 0, b"{\"ch\":\"market.btcusdt.mbp.5\"}", &mut requests));
+        std::fs::remove_file(path).unwrap();
+    }
+}
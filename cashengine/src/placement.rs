@@ -0,0 +1,259 @@
+use crate::config::{PlacementConfig, PlacementMode};
+use crate::error::EngineError;
+use core_affinity::CoreId;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Barrier, Mutex};
+
+const ISOLATED_CPUS_PATH: &str = "/sys/devices/system/cpu/isolated";
+const NUMA_NODES_PATH: &str = "/sys/devices/system/node";
+
+// Cores and scheduling of the pinned threads, decided before they are spawned.
+// Every pinned thread applies its placement with `enter` when it starts and waits there until the spawning
+// thread checked the outcome of all of them with `check`, so a strict placement fails before any feed runs.
+pub(crate) struct Placement {
+    // Feed thread `thread_id` runs with `feeds[thread_id]`
+    pub(crate) feeds: Vec<ThreadPlacement>,
+    pub(crate) reader: ThreadPlacement,
+    mode: PlacementMode,
+    isolated: Vec<usize>,
+    // NUMA node ids with their CPUs, empty without NUMA information
+    nodes: Vec<(usize, Vec<usize>)>,
+    placed: Mutex<Vec<PlacedThread>>,
+    // Passed twice by every pinned thread and the checking thread: after placing and after the check
+    barrier: Barrier,
+    aborted: AtomicBool,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct ThreadPlacement {
+    name: String,
+    // Position in the startup report
+    order: usize,
+    // CPU id, None runs the thread unpinned
+    core: Option<usize>,
+    // SCHED_FIFO priority, 0 keeps the default scheduler
+    priority: u32,
+}
+
+// Outcome of applying a `ThreadPlacement`
+struct PlacedThread {
+    placement: ThreadPlacement,
+    pinned: bool,
+    scheduled: io::Result<()>,
+}
+
+impl Placement {
+    // Automatic placement puts the reader on the last candidate core and the feed threads on the cores before it,
+    // in reverse order, as the first cores run the OS and the unpinned threads
+    pub(crate) fn plan(config: &PlacementConfig, feed_threads: usize) -> Result<Placement, EngineError> {
+        let mode = config.mode;
+        let fallback = |message: String| match mode {
+            PlacementMode::Strict => Err(EngineError::Affinity(message)),
+            PlacementMode::BestEffort => {
+                tracing::warn!("{}, placing threads best effort", message);
+                Ok(())
+            }
+        };
+
+        let available: Vec<usize> = core_affinity::get_core_ids().unwrap_or_default().iter().map(|core_id| core_id.id).collect();
+        let isolated = read_cpu_list(ISOLATED_CPUS_PATH).unwrap_or_default();
+        let nodes = numa_nodes();
+        tracing::info!("Available core ids: {:?}, isolated: {:?}, NUMA nodes: {}", available, isolated, nodes.len());
+        if available.is_empty() {
+            fallback("Failed getting core ids".to_string())?;
+        }
+
+        // The default affinity of the process excludes isolated CPUs, they are only used when asked for
+        let mut cores = available;
+        if config.isolated_cpus {
+            if isolated.is_empty() {
+                fallback(format!("No isolated CPUs in {}", ISOLATED_CPUS_PATH))?;
+            } else {
+                cores = isolated.clone();
+            }
+        }
+        if let Some(node) = config.numa_node {
+            match nodes.iter().find(|(id, _)| *id == node) {
+                Some((_, node_cores)) => {
+                    let node_cores: Vec<usize> = cores.iter().copied().filter(|core| node_cores.contains(core)).collect();
+                    if node_cores.is_empty() {
+                        fallback(format!("None of the cores {:?} is on NUMA node {}", cores, node))?;
+                    } else {
+                        cores = node_cores;
+                    }
+                }
+                None => fallback(format!("NUMA node {} not found in {}", node, NUMA_NODES_PATH))?,
+            }
+        }
+
+        let reader_core = config.reader_core.or(cores.last().copied());
+        let feed_cores: Vec<Option<usize>> = if config.feed_cores.is_empty() {
+            let spare: Vec<usize> = cores.iter().copied().filter(|core| Some(*core) != reader_core).collect();
+            if spare.len() < feed_threads {
+                fallback(format!(
+                    "Not enough cores to run {} feed threads plus 1 reader thread on {:?}, {} cores are required. \
+                     Raise connections_per_thread or add cores",
+                    feed_threads, cores, feed_threads + 1))?;
+            }
+            // Without spare cores the feed threads share the reader's core
+            (0..feed_threads).map(|i| match spare.len() {
+                0 => reader_core,
+                len => Some(spare[len - 1 - i % len]),
+            }).collect()
+        } else {
+            if config.feed_cores.len() < feed_threads {
+                fallback(format!("placement.feed_cores lists {} cores for {} feed threads", config.feed_cores.len(), feed_threads))?;
+            }
+            (0..feed_threads).map(|i| Some(config.feed_cores[i % config.feed_cores.len()])).collect()
+        };
+
+        // A spinning SCHED_FIFO thread never yields its core to another one
+        if config.realtime_priority > 0 {
+            let mut used: Vec<usize> = feed_cores.iter().chain([&reader_core]).flatten().copied().collect();
+            used.sort_unstable();
+            if used.windows(2).any(|pair| pair[0] == pair[1]) {
+                fallback("Threads with realtime_priority share a core and can starve each other".to_string())?;
+            }
+        }
+
+        let priority = config.realtime_priority;
+        let feeds: Vec<ThreadPlacement> = feed_cores.into_iter().enumerate()
+            .map(|(thread_id, core)| ThreadPlacement { name: format!("feed thread id {}", thread_id), order: thread_id, core, priority })
+            .collect();
+        let reader = ThreadPlacement { name: "feeds reader thread".to_string(), order: feed_threads, core: reader_core, priority };
+        Ok(Placement {
+            feeds,
+            reader,
+            mode,
+            isolated,
+            nodes,
+            placed: Mutex::new(Vec::with_capacity(feed_threads + 1)),
+            barrier: Barrier::new(feed_threads + 2),
+            aborted: AtomicBool::new(false),
+        })
+    }
+
+    // Called by every pinned thread when it starts, false if the startup was aborted and the thread has to return
+    pub(crate) fn enter(&self, placement: &ThreadPlacement) -> bool {
+        let placed = placement.apply();
+        self.placed.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(placed);
+        self.barrier.wait();
+        self.barrier.wait();
+        !self.aborted.load(Ordering::Acquire)
+    }
+
+    // Called once by the spawning thread after spawning all pinned threads, logs the startup report
+    pub(crate) fn check(&self) -> Result<(), EngineError> {
+        self.barrier.wait();
+        let result = self.report();
+        if result.is_err() {
+            self.aborted.store(true, Ordering::Release);
+        }
+        self.barrier.wait();
+        result
+    }
+
+    fn report(&self) -> Result<(), EngineError> {
+        let mut placed = self.placed.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        placed.sort_by_key(|placed| placed.placement.order);
+        tracing::info!("Thread placement ({}):", self.mode);
+        let mut failures = Vec::new();
+        for placed in placed.iter() {
+            let placement = &placed.placement;
+            let core = match placement.core {
+                Some(core) if placed.pinned => format!("core {}{}", core, self.describe(core)),
+                Some(core) => {
+                    failures.push(format!("{} failed pinning to core {}", placement.name, core));
+                    format!("not pinned, failed pinning to core {}", core)
+                }
+                None => "not pinned".to_string(),
+            };
+            let scheduling = match (&placed.scheduled, placement.priority) {
+                (_, 0) => "default scheduler".to_string(),
+                (Ok(()), priority) => format!("SCHED_FIFO {}", priority),
+                (Err(e), priority) => {
+                    failures.push(format!("{} failed setting SCHED_FIFO {}: {}", placement.name, priority, e));
+                    format!("default scheduler, failed setting SCHED_FIFO {}: {}", priority, e)
+                }
+            };
+            tracing::info!("  {}: {}, {}", placement.name, core, scheduling);
+        }
+        match (self.mode, failures.is_empty()) {
+            (_, true) => Ok(()),
+            (PlacementMode::BestEffort, false) => {
+                for failure in &failures {
+                    tracing::warn!("{}", failure);
+                }
+                Ok(())
+            }
+            (PlacementMode::Strict, false) => Err(EngineError::Affinity(failures.join(", "))),
+        }
+    }
+
+    // E.g. " (isolated, NUMA node 1)"
+    fn describe(&self, core: usize) -> String {
+        let mut attributes = Vec::new();
+        if self.isolated.contains(&core) {
+            attributes.push("isolated".to_string());
+        }
+        if let Some((node, _)) = self.nodes.iter().find(|(_, cores)| cores.contains(&core)) {
+            attributes.push(format!("NUMA node {}", node));
+        }
+        if attributes.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", attributes.join(", "))
+        }
+    }
+}
+
+impl ThreadPlacement {
+    // Pins the calling thread and sets its scheduling policy
+    fn apply(&self) -> PlacedThread {
+        let pinned = self.core.is_some_and(|core| core_affinity::set_for_current(CoreId { id: core }));
+        let scheduled = match self.priority {
+            0 => Ok(()),
+            priority => set_fifo(priority),
+        };
+        PlacedThread { placement: self.clone(), pinned, scheduled }
+    }
+}
+
+fn set_fifo(priority: u32) -> io::Result<()> {
+    let param = libc::sched_param { sched_priority: priority as libc::c_int };
+    // pid 0 is the calling thread
+    if unsafe { libc::sched_setscheduler(0, libc::SCHED_FIFO, &param) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// NUMA nodes from /sys/devices/system/node/node<id>/cpulist
+fn numa_nodes() -> Vec<(usize, Vec<usize>)> {
+    let Ok(entries) = std::fs::read_dir(NUMA_NODES_PATH) else {
+        return Vec::new();
+    };
+    let mut nodes: Vec<(usize, Vec<usize>)> = entries.flatten()
+        .filter_map(|entry| {
+            let node = entry.file_name().to_str()?.strip_prefix("node")?.parse().ok()?;
+            let cores = read_cpu_list(&format!("{}/cpulist", entry.path().display()))?;
+            Some((node, cores))
+        })
+        .collect();
+    nodes.sort_unstable_by_key(|(node, _)| *node);
+    nodes
+}
+
+// Kernel CPU list format, e.g. "0-3,8,10-11", empty if there are none
+fn read_cpu_list(path: &str) -> Option<Vec<usize>> {
+    let content = std::fs::read_to_string(path).ok()?;
+    let mut cpus = Vec::new();
+    for range in content.trim().split(',').filter(|range| !range.is_empty()) {
+        match range.split_once('-') {
+            Some((first, last)) => cpus.extend(first.parse::<usize>().ok()?..=last.parse().ok()?),
+            None => cpus.push(range.parse().ok()?),
+        }
+    }
+    Some(cpus)
+}
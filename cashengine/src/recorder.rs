@@ -0,0 +1,382 @@
+use crate::metrics::Counter;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Recording files are gzip streams starting with `MAGIC`, followed by frames of socket receive time in nanoseconds
+// since UNIX epoch (u64), feed id (u32), message length (u32), all little endian, and the message. The feed id is
+// the websocket connection, the engine logs the feed ids of every feed thread at startup.
+pub const MAGIC: &[u8; 8] = b"CEREC001";
+pub const FILE_EXTENSION: &str = "rec.gz";
+pub const FRAME_HEADER_SIZE: usize = 16;
+
+// Messages queued for the recording thread, further messages are dropped instead of blocking the feed threads
+const QUEUE_CAPACITY: usize = 65536;
+// Message buffers kept per recorder for reuse, the recording thread drops buffers beyond this
+const POOL_CAPACITY: usize = 1024;
+// Buffered data is flushed to disk at least this often, so little is lost on a crash
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub receive_nanos: u64,
+    pub feed_id: u32,
+    pub message: Vec<u8>,
+}
+
+// Sent to the recording thread. A pool is registered before its first record, on the same queue.
+pub(crate) enum Queued {
+    Pool { id: usize, buffers: SyncSender<Vec<u8>> },
+    Record { pool: usize, record: Record },
+}
+
+// Handle of a feed thread to the recording thread. Every clone has its own pool of message buffers,
+// which the recording thread returns after writing, so the feed thread doesn't allocate per message.
+pub(crate) struct Recorder {
+    sender: SyncSender<Queued>,
+    dropped: Arc<Counter>,
+    next_pool: Arc<AtomicUsize>,
+    pool: usize,
+    buffers: Receiver<Vec<u8>>,
+}
+
+impl Recorder {
+    fn new(sender: SyncSender<Queued>, dropped: Arc<Counter>, next_pool: Arc<AtomicUsize>) -> Recorder {
+        let pool = next_pool.fetch_add(1, Ordering::Relaxed);
+        let (buffers_sender, buffers) = mpsc::sync_channel(POOL_CAPACITY);
+        // Blocks only while the queue is full, and recorders are created before the feeds run
+        let _ = sender.send(Queued::Pool { id: pool, buffers: buffers_sender });
+        Recorder {
+            sender,
+            dropped,
+            next_pool,
+            pool,
+            buffers,
+        }
+    }
+
+    // `receive_nanos` is the epoch time the frame was read from the socket, 0 if unknown
+    pub(crate) fn record(&self, feed_id: usize, message: &[u8], receive_nanos: u64) {
+        let mut buffer = self.buffers.try_recv().unwrap_or_default();
+        buffer.clear();
+        buffer.extend_from_slice(message);
+        let record = Record {
+            receive_nanos: if receive_nanos == 0 { now_nanos() } else { receive_nanos },
+            feed_id: feed_id as u32,
+            message: buffer,
+        };
+        match self.sender.try_send(Queued::Record { pool: self.pool, record }) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.dropped.increment();
+                let dropped = self.dropped.get();
+                if dropped == 1 || dropped.is_multiple_of(10_000) {
+                    tracing::warn!("Recording queue is full, dropped {} messages so far", dropped);
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+impl Clone for Recorder {
+    fn clone(&self) -> Recorder {
+        Recorder::new(self.sender.clone(), Arc::clone(&self.dropped), Arc::clone(&self.next_pool))
+    }
+}
+
+// Creates the recording directory and the first file. Run the returned receiver with `run` on its own thread.
+pub(crate) fn create(directory: &str, max_file_bytes: u64) -> io::Result<(Recorder, RecordWriter, Receiver<Queued>)> {
+    let writer = RecordWriter::create(directory, max_file_bytes)?;
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_CAPACITY);
+    let recorder = Recorder::new(sender, Arc::new(Counter::new()), Arc::new(AtomicUsize::new(0)));
+    Ok((recorder, writer, receiver))
+}
+
+// Writes queued records until all recorders are dropped
+pub(crate) fn run(mut writer: RecordWriter, receiver: Receiver<Queued>) {
+    // Indexed by pool id
+    let mut pools: Vec<Option<SyncSender<Vec<u8>>>> = Vec::new();
+    let mut last_flush = Instant::now();
+    loop {
+        let mut result = match receiver.recv_timeout(FLUSH_INTERVAL) {
+            Ok(Queued::Pool { id, buffers }) => {
+                if pools.len() <= id {
+                    pools.resize(id + 1, None);
+                }
+                pools[id] = Some(buffers);
+                Ok(())
+            }
+            Ok(Queued::Record { pool, record }) => {
+                let result = writer.write(&record);
+                // A full pool or a dropped recorder drops the buffer
+                if let Some(buffers) = pools.get(pool).and_then(Option::as_ref) {
+                    let _ = buffers.try_send(record.message);
+                }
+                result
+            }
+            Err(RecvTimeoutError::Timeout) => Ok(()),
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if result.is_ok() && last_flush.elapsed() >= FLUSH_INTERVAL {
+            result = writer.flush();
+            last_flush = Instant::now();
+        }
+        if let Err(e) = result {
+            tracing::error!("Failed writing recording file {}: {}", writer.path().display(), e);
+        }
+    }
+    let path = writer.path().to_path_buf();
+    if let Err(e) = writer.finish() {
+        tracing::error!("Failed finishing recording file {}: {}", path.display(), e);
+    }
+}
+
+// Appends records to gzip files in `directory`, starting a new file once `max_file_bytes` of messages are written
+pub struct RecordWriter {
+    directory: PathBuf,
+    max_file_bytes: u64,
+    path: PathBuf,
+    encoder: GzEncoder<BufWriter<File>>,
+    file_bytes: u64,
+}
+
+impl RecordWriter {
+    pub fn create<P: AsRef<Path>>(directory: P, max_file_bytes: u64) -> io::Result<RecordWriter> {
+        let directory = directory.as_ref().to_path_buf();
+        std::fs::create_dir_all(&directory)?;
+        let (path, encoder) = RecordWriter::open_file(&directory)?;
+        Ok(RecordWriter {
+            directory,
+            max_file_bytes,
+            path,
+            encoder,
+            file_bytes: 0,
+        })
+    }
+
+    // Files are named by their creation time, so they sort in recording order
+    fn open_file(directory: &Path) -> io::Result<(PathBuf, GzEncoder<BufWriter<File>>)> {
+        let path = directory.join(format!("feed-{}.{}", now_nanos(), FILE_EXTENSION));
+        tracing::info!("Recording feed into {}", path.display());
+        let file = File::options().write(true).create_new(true).open(&path)?;
+        let mut encoder = GzEncoder::new(BufWriter::new(file), Compression::fast());
+        encoder.write_all(MAGIC)?;
+        Ok((path, encoder))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        if self.file_bytes >= self.max_file_bytes {
+            self.rotate()?;
+        }
+        self.encoder.write_all(&record.receive_nanos.to_le_bytes())?;
+        self.encoder.write_all(&record.feed_id.to_le_bytes())?;
+        self.encoder.write_all(&(record.message.len() as u32).to_le_bytes())?;
+        self.encoder.write_all(&record.message)?;
+        self.file_bytes += (FRAME_HEADER_SIZE + record.message.len()) as u64;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.encoder.flush()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let (path, encoder) = RecordWriter::open_file(&self.directory)?;
+        let previous = std::mem::replace(&mut self.encoder, encoder);
+        let previous_path = std::mem::replace(&mut self.path, path);
+        self.file_bytes = 0;
+        previous.finish()?.flush()?;
+        tracing::info!("Finished recording file {}", previous_path.display());
+        Ok(())
+    }
+
+    // Writes the gzip trailer, the file is complete afterwards
+    pub fn finish(self) -> io::Result<()> {
+        self.encoder.finish()?.flush()
+    }
+}
+
+// Reads the records of a file written by `RecordWriter`
+pub struct RecordReader {
+    path: PathBuf,
+    decoder: BufReader<MultiGzDecoder<File>>,
+}
+
+impl RecordReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<RecordReader> {
+        let path = path.as_ref().to_path_buf();
+        let mut decoder = BufReader::new(MultiGzDecoder::new(File::open(&path)?));
+        let mut magic = [0u8; MAGIC.len()];
+        decoder.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a recording file", path.display())));
+        }
+        Ok(RecordReader { path, decoder })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Reads the next record into `record`, reusing its message buffer. Returns false at the end of the file.
+    pub fn read_next(&mut self, record: &mut Record) -> io::Result<bool> {
+        let mut frame_header = [0u8; FRAME_HEADER_SIZE];
+        let mut read = 0;
+        while read < FRAME_HEADER_SIZE {
+            match self.decoder.read(&mut frame_header[read..]) {
+                Ok(0) if read == 0 => return Ok(false),
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        record.receive_nanos = u64::from_le_bytes(frame_header[..8].try_into().unwrap());
+        record.feed_id = u32::from_le_bytes(frame_header[8..12].try_into().unwrap());
+        let len = u32::from_le_bytes(frame_header[12..16].try_into().unwrap()) as usize;
+        record.message.resize(len, 0);
+        self.decoder.read_exact(&mut record.message)?;
+        Ok(true)
+    }
+}
+
+// The recording file at `path` or all recording files in the directory `path`, in recording order
+pub fn recording_files<P: AsRef<Path>>(path: P) -> io::Result<Vec<PathBuf>> {
+    let path = path.as_ref();
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        let file = entry?.path();
+        if file.to_string_lossy().ends_with(FILE_EXTENSION) {
+            files.push(file);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("cashengine-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        directory
+    }
+
+    fn records(count: usize) -> Vec<Record> {
+        (0..count).map(|i| Record {
+            receive_nanos: 1_700_000_000_000_000_000 + i as u64,
+            feed_id: (i % 3) as u32,
+            message: format!(r#"{{"ch":"market.btcusdt.bbo","ts":{}}}"#, i).into_bytes(),
+        }).collect()
+    }
+
+    fn read_all(path: &Path) -> (Vec<Record>, io::Result<bool>) {
+        let mut reader = RecordReader::open(path).unwrap();
+        let mut records = Vec::new();
+        let mut record = Record { receive_nanos: 0, feed_id: 0, message: Vec::new() };
+        loop {
+            match reader.read_next(&mut record) {
+                Ok(true) => records.push(record.clone()),
+                end => return (records, end),
+            }
+        }
+    }
+
+    #[test]
+    fn reads_back_rotated_files() {
+        let directory = directory("recording-rotation");
+        let written = records(10);
+        // Rotates after every 3 records
+        let frame_size = (FRAME_HEADER_SIZE + written[0].message.len()) as u64;
+        let mut writer = RecordWriter::create(&directory, 3 * frame_size).unwrap();
+        for record in &written {
+            writer.write(record).unwrap();
+            // Distinct creation times for the file names
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        writer.finish().unwrap();
+
+        let files = recording_files(&directory).unwrap();
+        assert_eq!(files.len(), 4);
+        let mut read = Vec::new();
+        for file in &files {
+            let (records, end) = read_all(file);
+            assert!(!end.unwrap());
+            read.extend(records);
+        }
+        assert_eq!(read, written);
+        assert_eq!(recording_files(&files[0]).unwrap(), [files[0].clone()]);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn reports_a_file_truncated_mid_record() {
+        let directory = directory("recording-truncated");
+        std::fs::create_dir_all(&directory).unwrap();
+        let written = records(2);
+        let path = directory.join(format!("feed-1.{}", FILE_EXTENSION));
+        let mut encoder = GzEncoder::new(File::create(&path).unwrap(), Compression::fast());
+        encoder.write_all(MAGIC).unwrap();
+        for record in &written {
+            encoder.write_all(&record.receive_nanos.to_le_bytes()).unwrap();
+            encoder.write_all(&record.feed_id.to_le_bytes()).unwrap();
+            encoder.write_all(&(record.message.len() as u32).to_le_bytes()).unwrap();
+            encoder.write_all(&record.message).unwrap();
+        }
+        // The last record lost the end of its message
+        encoder.write_all(&written[0].receive_nanos.to_le_bytes()).unwrap();
+        encoder.write_all(&0u32.to_le_bytes()).unwrap();
+        encoder.write_all(&100u32.to_le_bytes()).unwrap();
+        encoder.write_all(b"{\"ch\"").unwrap();
+        encoder.finish().unwrap();
+
+        let (records, end) = read_all(&path);
+        assert_eq!(records, written);
+        assert_eq!(end.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn reports_a_file_without_gzip_trailer() {
+        let directory = directory("recording-unfinished");
+        let written = records(5);
+        let mut writer = RecordWriter::create(&directory, u64::MAX).unwrap();
+        for record in &written {
+            writer.write(record).unwrap();
+        }
+        writer.flush().unwrap();
+        // As after a crash, the writer never finished the file
+        let path = writer.path().to_path_buf();
+        std::mem::forget(writer);
+
+        let (records, end) = read_all(&path);
+        assert_eq!(records, written);
+        assert!(end.is_err());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
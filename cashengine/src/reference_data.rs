@@ -0,0 +1,172 @@
+use crate::config::EngineConfig;
+use crate::error::EngineError;
+use crate::feed_handler::FeedCommand;
+use crate::htx_state::HtxState;
+use crate::instrument::{Instrument, InstrumentStatus};
+use crate::rest_client::RestClient;
+use crate::shm_directory::{DirectoryEntry, DirectoryWriter};
+use crate::trading_universe::TradingUniverse;
+use std::fs::File;
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+
+// States of listed markets. Halted markets stay in the polled universe, so a suspension or a resume
+// is a status change of the market instead of a removal and a new listing.
+const LISTED_STATES: &[HtxState] = &[HtxState::Online, HtxState::Suspend, HtxState::Fuse, HtxState::TransferBoard];
+
+// Owner of the markets in the HTX SHM chunks. Chunks of removed markets are retired and only handed out again
+// to the same market, so readers never see the last message of a removed market under a new name.
+#[derive(Clone, Debug, PartialEq)]
+enum ChunkSlot {
+    Free,
+    Used(String),
+    Retired(String),
+}
+
+// Re-polls the HTX reference data every `refresh.interval_secs` and applies the changes to the running feeds.
+// New markets are published in the SHM directories and then subscribed by the feed owning a spare chunk,
+// removed markets are unsubscribed and cleared from the directories. Status changes are republished.
+pub(crate) struct ReferenceDataRefresher<'a> {
+    config: &'a EngineConfig,
+    rest_client: &'a RestClient,
+    // The subscribed markets, the next poll is diffed against it
+    universe: TradingUniverse,
+    slots: Vec<ChunkSlot>,
+    markets_per_websocket: usize,
+    directories: Vec<DirectoryWriter<'a>>,
+    // Indexed by writer id
+    feeds: Vec<Sender<FeedCommand>>,
+}
+
+impl<'a> ReferenceDataRefresher<'a> {
+    // The markets of `universe` are in chunks 0 to `universe.len() - 1`, as published by `create_shm_files`
+    pub(crate) fn new(
+        config: &'a EngineConfig,
+        rest_client: &'a RestClient,
+        universe: TradingUniverse,
+        shm_files: &'a [(String, File)],
+        markets_per_websocket: usize,
+        feeds: Vec<Sender<FeedCommand>>,
+    ) -> Result<Self, EngineError> {
+        let directories = shm_files.iter()
+            .map(|(path, shm_file)| DirectoryWriter::create(shm_file).map_err(crate::shm_error(path)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let slots = (0..feeds.len() * markets_per_websocket)
+            .map(|chunk_index| match universe.instruments().get(chunk_index) {
+                Some(instrument) => ChunkSlot::Used(instrument.symbol.clone()),
+                None => ChunkSlot::Free,
+            })
+            .collect();
+        Ok(ReferenceDataRefresher { config, rest_client, universe, slots, markets_per_websocket, directories, feeds })
+    }
+
+    pub(crate) fn run(mut self) {
+        let interval = Duration::from_secs(self.config.refresh.interval_secs);
+        tracing::info!("Refreshing reference data every {:?}, {} spare chunks", interval, self.free_chunks());
+        loop {
+            std::thread::sleep(interval);
+            self.refresh();
+        }
+    }
+
+    fn refresh(&mut self) {
+        // A failed or empty poll keeps the current markets, it must not unsubscribe everything
+        let mut universe = match crate::load_htx_universe(self.config, self.rest_client, LISTED_STATES) {
+            Ok(universe) => universe,
+            Err(e) => {
+                tracing::error!("Failed refreshing reference data, keeping the current markets: {}", e);
+                return;
+            }
+        };
+        let diff = self.universe.diff(&universe);
+        if diff.is_empty() {
+            tracing::debug!("Reference data unchanged, {} markets", universe.len());
+            return;
+        }
+        tracing::info!("Reference data changed: {} added, {} removed, {} changed markets",
+            diff.added.len(), diff.removed.len(), diff.changed.len());
+
+        for instrument in &diff.removed {
+            self.remove(instrument);
+        }
+        for (before, after) in &diff.changed {
+            self.update(before, after);
+        }
+        let mut unsubscribed = Vec::new();
+        for instrument in &diff.added {
+            // Like at startup, halted markets are subscribed once they trade
+            let halted = self.config.symbol_filters.online && instrument.status == InstrumentStatus::Halted;
+            if halted || !self.add(instrument) {
+                unsubscribed.push(instrument.symbol.as_str());
+            }
+        }
+        // Markets without a chunk show up as added again on the next poll
+        universe.retain(|instrument| !unsubscribed.contains(&instrument.symbol.as_str()));
+        self.universe = universe;
+    }
+
+    fn add(&mut self, instrument: &Instrument) -> bool {
+        // A market that was removed and listed again, e.g. after trade_enabled was off, returns to its chunk
+        let retired = self.slots.iter().position(|slot| matches!(slot, ChunkSlot::Retired(retired) if *retired == instrument.symbol));
+        let Some(chunk_index) = retired.or_else(|| self.slots.iter().position(|slot| *slot == ChunkSlot::Free)) else {
+            tracing::warn!("No spare chunk left for new market {}, raise refresh.spare_markets and restart to subscribe it",
+                instrument.symbol);
+            return false;
+        };
+        // Published before subscribing, so readers know the market before its first message
+        self.publish(chunk_index, &DirectoryEntry::from_instrument(instrument));
+        self.slots[chunk_index] = ChunkSlot::Used(instrument.symbol.clone());
+        tracing::info!("Adding market {} in chunk {}", instrument.symbol, chunk_index);
+        self.send(chunk_index, FeedCommand::Subscribe {
+            market: instrument.symbol.clone(),
+            index: chunk_index % self.markets_per_websocket,
+        });
+        true
+    }
+
+    fn remove(&mut self, instrument: &Instrument) {
+        let Some(chunk_index) = self.chunk_index(&instrument.symbol) else {
+            return;
+        };
+        tracing::info!("Removing market {} from chunk {}", instrument.symbol, chunk_index);
+        self.send(chunk_index, FeedCommand::Unsubscribe { market: instrument.symbol.clone() });
+        for directory in &mut self.directories {
+            directory.clear(chunk_index);
+        }
+        self.slots[chunk_index] = ChunkSlot::Retired(instrument.symbol.clone());
+    }
+
+    // The market stays subscribed, a halted market just stops sending updates. Readers see the status in the directory.
+    fn update(&mut self, before: &Instrument, after: &Instrument) {
+        let Some(chunk_index) = self.chunk_index(&after.symbol) else {
+            return;
+        };
+        if before.status != after.status {
+            tracing::info!("Market {} changed status from {:?} to {:?}", after.symbol, before.status, after.status);
+        } else {
+            tracing::info!("Market {} changed reference data", after.symbol);
+        }
+        self.publish(chunk_index, &DirectoryEntry::from_instrument(after));
+    }
+
+    fn publish(&mut self, chunk_index: usize, entry: &DirectoryEntry) {
+        for directory in &mut self.directories {
+            directory.publish(chunk_index, entry);
+        }
+    }
+
+    fn send(&self, chunk_index: usize, command: FeedCommand) {
+        let writer_id = chunk_index / self.markets_per_websocket;
+        if self.feeds[writer_id].send(command).is_err() {
+            tracing::error!("Feed with writer id {} stopped, can't update chunk {}", writer_id, chunk_index);
+        }
+    }
+
+    fn chunk_index(&self, market: &str) -> Option<usize> {
+        self.slots.iter().position(|slot| matches!(slot, ChunkSlot::Used(used) if used == market))
+    }
+
+    fn free_chunks(&self) -> usize {
+        self.slots.iter().filter(|slot| **slot == ChunkSlot::Free).count()
+    }
+}
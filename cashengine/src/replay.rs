@@ -0,0 +1,307 @@
+use crate::channel;
+use crate::channel::Channel;
+use crate::config::EngineConfig;
+use crate::error::EngineError;
+use crate::exchange::Exchange;
+use crate::feed_handler::FeedHandler;
+use crate::feed_source::{FeedHealth, FeedMessage, FeedSource, MessageTimes};
+use crate::htx_feed;
+use crate::metrics::FeedLatency;
+use crate::placement::Placement;
+use crate::recorder::{Record, RecordReader};
+use crate::shm_directory::DirectoryEntry;
+use crate::{recorder, shm_directory};
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+// Replays recorded feeds into the SHM files instead of live websockets, so the reader thread
+// runs against historical sessions without network access.
+// Markets and channels are taken from the recording, markets get chunk indexes in order of first appearance.
+// Only HTX feeds are recorded.
+
+// Waits shorter than this spin instead of sleeping, to keep the original timing precise
+const SPIN_THRESHOLD: Duration = Duration::from_micros(200);
+
+pub(crate) fn try_run(config: &EngineConfig) -> Result<(), EngineError> {
+    let replay = &config.replay;
+    let replay_error = |error| EngineError::Replay { path: replay.path.clone(), error };
+
+    let files = recorder::recording_files(&replay.path).map_err(replay_error)?;
+    if files.is_empty() {
+        return Err(replay_error(io::Error::new(io::ErrorKind::NotFound, "no recording files found")));
+    }
+    let (markets, channels) = scan_recording(&files).map_err(replay_error)?;
+    if markets.is_empty() {
+        return Err(replay_error(io::Error::new(io::ErrorKind::InvalidData, "recording contains no market data")));
+    }
+    tracing::info!("Replaying {} recording files with {} markets and channels {:?} at speed {}",
+                   files.len(), markets.len(), channels, replay.speed);
+
+    let entries: Vec<DirectoryEntry> = markets.iter().map(|market| DirectoryEntry::from_symbol(market)).collect();
+    let shm_files = crate::create_shm_files(&config.shm_file_path, config.chunk_size, &channels, 1, markets.len(), &entries)?;
+    let mut shm_readers = [crate::attach_readers(&channels, &shm_files)?];
+    let shm_writers = crate::create_writers(&shm_files, 0)?;
+    let market_names: Vec<&str> = markets.iter().map(String::as_str).collect();
+    let mut feed_handler = FeedHandler::new(0, Exchange::Htx, &channels, shm_writers, &market_names, 0, None);
+    let mut replay_feed = ReplayFeed::new(&replay.path, files, replay.speed);
+    replay_feed.connect()?;
+    replay_feed.subscribe(&market_names, &channels);
+    // The replay thread is placed like a feed thread
+    let placement = Placement::plan(&config.placement, 1)?;
+    // Set once the recording is replayed, the reader thread then reads the last messages and returns
+    let finished = AtomicBool::new(false);
+
+    std::thread::scope(|s| {
+        let placement = &placement;
+        let finished = &finished;
+        let replay_thread = s.spawn(move || {
+            // Also set if the replay panics, the reader thread would wait for it forever otherwise
+            let _finished = SetOnDrop(finished);
+            if placement.enter(&placement.feeds[0]) {
+                let started = Instant::now();
+                let mut latency = FeedLatency::new(0, &config.latency);
+                feed_handler.run(&mut replay_feed, &mut latency);
+                tracing::info!("Replay finished in {} ms", started.elapsed().as_millis());
+            }
+        });
+
+        let main_thread = s.spawn(move || {
+            if !placement.enter(&placement.reader) {
+                return;
+            }
+            crate::read_feeds(&mut shm_readers, &[vec![0]], &config.latency, None, Some(finished));
+        });
+        placement.check()?;
+        main_thread.join().map_err(|payload| EngineError::panic("reader", payload))?;
+        replay_thread.join().map_err(|payload| EngineError::panic("replay", payload))?;
+        Ok(())
+    })
+}
+
+struct SetOnDrop<'a>(&'a AtomicBool);
+
+impl Drop for SetOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+// Collects the markets and channels of the recording, bbo first so the reader thread follows it
+fn scan_recording(files: &[PathBuf]) -> io::Result<(Vec<String>, Vec<Channel>)> {
+    let mut markets: Vec<String> = Vec::new();
+    let mut channels: Vec<Channel> = Vec::new();
+    let mut record = Record { receive_nanos: 0, feed_id: 0, message: Vec::new() };
+    for file in files {
+        let mut reader = RecordReader::open(file)?;
+        while read_next(&mut reader, &mut record)? {
+            let Some((market, channel_name)) = channel::split_topic(&record.message) else {
+                continue;
+            };
+            let channel = match std::str::from_utf8(channel_name).map(str::parse::<Channel>) {
+                Ok(Ok(channel)) => channel,
+                _ => continue,
+            };
+            if market.len() >= shm_directory::SYMBOL_SIZE {
+                continue;
+            }
+            if !markets.iter().any(|known| known == market) {
+                markets.push(market.to_string());
+            }
+            if !channels.contains(&channel) {
+                channels.push(channel);
+            }
+        }
+    }
+    channels.sort_by_key(|channel| *channel != Channel::Bbo);
+    Ok((markets, channels))
+}
+
+// Recorded HTX messages in recording order, paced by their receive time
+pub struct ReplayFeed {
+    name: String,
+    files: Vec<PathBuf>,
+    speed: f64,
+    next_file: usize,
+    reader: Option<RecordReader>,
+    record: Record,
+    // Channels of the subscription, messages of other channels are skipped
+    channel_names: Vec<String>,
+    // Receive time of the first record and when it was replayed
+    first: Option<(u64, Instant)>,
+    messages: u64,
+}
+
+impl ReplayFeed {
+    // `speed` 1.0 keeps the original timing, 0.0 replays as fast as possible
+    pub fn new(name: &str, files: Vec<PathBuf>, speed: f64) -> ReplayFeed {
+        ReplayFeed {
+            name: name.to_string(),
+            files,
+            speed,
+            next_file: 0,
+            reader: None,
+            record: Record { receive_nanos: 0, feed_id: 0, message: Vec::new() },
+            channel_names: Vec::new(),
+            first: None,
+            messages: 0,
+        }
+    }
+
+    // Reads the next record of the subscribed channels, opening the following files as needed
+    fn read_record(&mut self) -> io::Result<bool> {
+        loop {
+            let reader = match self.reader.as_mut() {
+                Some(reader) => reader,
+                None => {
+                    let Some(file) = self.files.get(self.next_file) else {
+                        return Ok(false);
+                    };
+                    tracing::info!("Replaying {}", file.display());
+                    self.next_file += 1;
+                    self.reader.insert(RecordReader::open(file)?)
+                }
+            };
+            if !read_next(reader, &mut self.record)? {
+                self.reader = None;
+                continue;
+            }
+            // Skip messages of channels this version can't parse, the feed handler expects known channels
+            if let Some((_, channel_name)) = channel::split_topic(&self.record.message) {
+                if !self.channel_names.iter().any(|name| name.as_bytes() == channel_name) {
+                    continue;
+                }
+            }
+            return Ok(true);
+        }
+    }
+}
+
+impl FeedSource for ReplayFeed {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn connect(&mut self) -> Result<(), EngineError> {
+        if let Some(file) = self.files.iter().find(|file| !file.is_file()) {
+            let error = io::Error::new(io::ErrorKind::NotFound, format!("{} is not a file", file.display()));
+            return Err(EngineError::Replay { path: self.name.clone(), error });
+        }
+        Ok(())
+    }
+
+    fn subscribe(&mut self, _markets: &[&str], channels: &[Channel]) {
+        self.channel_names = channels.iter().map(|channel| channel.to_string()).collect();
+    }
+
+    fn next_message(&mut self) -> Option<FeedMessage<'_>> {
+        match self.read_record() {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(e) => {
+                tracing::error!("Replay of {} failed: {}", self.name, e);
+                return None;
+            }
+        }
+        if self.speed > 0.0 {
+            let (first_nanos, started) = *self.first.get_or_insert((self.record.receive_nanos, Instant::now()));
+            let offset = self.record.receive_nanos.saturating_sub(first_nanos) as f64 / self.speed;
+            wait_until(started + Duration::from_nanos(offset as u64));
+        }
+        self.messages += 1;
+        // Recorded receive times are from another session, only the stages after the replay are measured
+        Some(htx_feed::classify(self.record.feed_id as usize, &self.record.message, MessageTimes::default()))
+    }
+
+    // The recording decides which markets are replayed
+    fn add_markets(&mut self, _markets: &[&str], _channels: &[Channel]) {}
+
+    fn remove_markets(&mut self, _markets: &[&str], _channels: &[Channel]) {}
+
+    // Snapshot requests of order books are answered by the recorded replies
+    fn send(&mut self, _request: &str) {}
+
+    fn health(&self) -> FeedHealth {
+        FeedHealth {
+            connected: self.next_file <= self.files.len(),
+            reconnects: 0,
+            messages: self.messages,
+            max_message_size: 0,
+        }
+    }
+}
+
+// A file cut off by a crash of the recording process ends at its last complete record
+fn read_next(reader: &mut RecordReader, record: &mut Record) -> io::Result<bool> {
+    match reader.read_next(record) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            tracing::warn!("Recording file {} is truncated, replaying up to its last complete record", reader.path().display());
+            Ok(false)
+        }
+        result => result,
+    }
+}
+
+fn wait_until(deadline: Instant) {
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return;
+        }
+        let remaining = deadline - now;
+        if remaining > SPIN_THRESHOLD {
+            std::thread::sleep(remaining - SPIN_THRESHOLD);
+        } else {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ReplayConfig;
+    use crate::recorder::RecordWriter;
+    use crate::shm_reader::SharedMemoryReader;
+    use std::fs::File;
+    use std::sync::mpsc;
+
+    fn bbo(symbol: &str, seq_id: u64, bid: f64) -> Vec<u8> {
+        format!(r#"{{"ch":"market.{}.bbo","ts":1,"tick":{{"seqId":{},"ask":{},"askSize":1.0,"bid":{},"bidSize":2.0,"quoteTime":1,"symbol":"{}"}}}}"#,
+                symbol, seq_id, bid + 1.0, bid, symbol).into_bytes()
+    }
+
+    #[test]
+    fn replays_a_recording_into_shm_and_returns() {
+        let directory = std::env::temp_dir().join(format!("cashengine-replay-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let mut writer = RecordWriter::create(directory.join("recording"), u64::MAX).unwrap();
+        let messages = [bbo("btcusdt", 1, 100.0), b"{\"ping\":1}".to_vec(), bbo("ethusdt", 2, 10.0), bbo("btcusdt", 3, 101.0)];
+        for (i, message) in messages.into_iter().enumerate() {
+            writer.write(&Record { receive_nanos: 1_000 + i as u64, feed_id: 0, message }).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let shm_file_path = directory.join("ticks.mmap").to_string_lossy().into_owned();
+        let config = EngineConfig {
+            shm_file_path: shm_file_path.clone(),
+            replay: ReplayConfig { enabled: true, path: directory.join("recording").to_string_lossy().into_owned(), speed: 0.0 },
+            ..EngineConfig::default()
+        };
+        let (done, result) = mpsc::channel();
+        std::thread::spawn(move || done.send(try_run(&config).map_err(|e| e.to_string())).unwrap());
+        result.recv_timeout(Duration::from_secs(30)).expect("replay didn't return").unwrap();
+
+        let file = File::open(&shm_file_path).unwrap();
+        let mut reader = SharedMemoryReader::attach(&file).unwrap();
+        let btcusdt = reader.lookup_symbol("btcusdt").unwrap();
+        let ethusdt = reader.lookup_symbol("ethusdt").unwrap();
+        assert_eq!((btcusdt, ethusdt), (0, 1));
+        let bbo = *reader.read_chunk(btcusdt).unwrap().bbo().unwrap();
+        assert_eq!((bbo.symbol_index, bbo.seq_id, bbo.bid, bbo.ask), (0, 3, 101.0, 102.0));
+        let bbo = *reader.read_chunk(ethusdt).unwrap().bbo().unwrap();
+        assert_eq!((bbo.symbol_index, bbo.seq_id, bbo.bid_size), (1, 2, 2.0));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
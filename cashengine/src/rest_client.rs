@@ -1,15 +1,378 @@
-use std::io::Read;
+use crate::config::RestConfig;
+use crate::error::EngineError;
+use crate::exchange::Exchange;
+use reqwest::blocking::{Client, Response};
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-pub fn send_request(url: &str) -> Result<String, Box<dyn std::error::Error>> {
-    let mut response = reqwest::blocking::get(url)?;
-    let mut body = String::new();
-    tracing::debug!("Requesting url {} ...", url);
-    response.read_to_string(&mut body)?;
-    tracing::debug!("Requesting url {} done", url);
+// Longest part of an error response kept in the error message
+const MAX_ERROR_BODY: usize = 512;
 
-    tracing::trace!("Status: {}", response.status());
-    tracing::trace!("Headers:\n{:#?}", response.headers());
-    tracing::trace!("Body:\n{}", body);
+// Blocking client for the exchange REST APIs, shared by the startup and the reference data refresher.
+// Requests are rate limited per host, retried with backoff on timeouts, connection failures, 5xx and 429,
+// and the body is checked for the exchange's error format before it is deserialized.
+pub(crate) struct RestClient {
+    client: Client,
+    config: RestConfig,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
 
-    Ok(body)
-}
\ No newline at end of file
+// HTX {"status":"error","err-code":"invalid-parameter","err-msg":"..."}, Binance {"code":-1121,"msg":"Invalid symbol."}
+#[derive(Deserialize, Default)]
+struct ErrorEnvelope {
+    status: Option<String>,
+    #[serde(rename = "err-code")]
+    err_code: Option<String>,
+    #[serde(rename = "err-msg")]
+    err_msg: Option<String>,
+    // Any JSON value, so an unexpected type doesn't hide the other fields
+    code: Option<serde_json::Value>,
+    msg: Option<String>,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+enum Failure {
+    Status(StatusCode, Option<Duration>),
+    Transport(reqwest::Error),
+}
+
+impl RestClient {
+    pub(crate) fn new(config: &RestConfig) -> Result<RestClient, EngineError> {
+        let client = Client::builder()
+            .connect_timeout(Duration::from_millis(config.connect_timeout_ms))
+            .timeout(Duration::from_millis(config.read_timeout_ms))
+            .build()
+            .map_err(|e| EngineError::Rest { url: String::new(), message: format!("Failed creating the HTTP client: {}", e) })?;
+        Ok(RestClient { client, config: config.clone(), buckets: Mutex::new(HashMap::new()) })
+    }
+
+    // GETs `url` and deserializes the body into `T`, `what` names the data in errors, e.g. "symbols"
+    pub(crate) fn get<T: DeserializeOwned>(&self, exchange: Exchange, url: &str, what: &'static str) -> Result<T, EngineError> {
+        let body = self.get_body(exchange, url)?;
+        check_exchange_error(exchange, &body)?;
+        serde_json::from_str(&body).map_err(|error| EngineError::Parse { what, error })
+    }
+
+    fn get_body(&self, exchange: Exchange, url: &str) -> Result<String, EngineError> {
+        let rest_error = |message: String| EngineError::Rest { url: url.to_string(), message };
+        let mut backoff = Duration::from_millis(self.config.initial_backoff_ms);
+        let max_backoff = Duration::from_millis(self.config.max_backoff_ms);
+        let max_retry_after = Duration::from_millis(self.config.max_retry_after_ms);
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            self.acquire(url);
+            tracing::debug!("Requesting url {} (attempt {})", url, attempt);
+            let failure = match self.client.get(url).send() {
+                Ok(response) if response.status().is_success() => match response.text() {
+                    Ok(body) => {
+                        tracing::debug!("Requesting url {} done, {} bytes", url, body.len());
+                        tracing::trace!("Body:\n{}", body);
+                        return Ok(body);
+                    }
+                    Err(e) if e.is_timeout() => Failure::Transport(e),
+                    Err(e) => return Err(rest_error(format!("Failed reading the response: {}", e))),
+                },
+                Ok(response) => {
+                    let status = response.status();
+                    if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+                        Failure::Status(status, retry_after(&response))
+                    } else {
+                        // Other responses are final, client errors carry the exchange error in the body
+                        let body = response.text().unwrap_or_default();
+                        check_exchange_error(exchange, &body)?;
+                        return Err(rest_error(format!("HTTP {}: {}", status, truncated(&body))));
+                    }
+                }
+                Err(e) if e.is_timeout() || e.is_connect() => Failure::Transport(e),
+                Err(e) => return Err(rest_error(e.to_string())),
+            };
+
+            if attempt > self.config.max_retries {
+                return Err(rest_error(format!("{}, giving up after {} attempts", failure, attempt)));
+            }
+            // The exchange bans clients that retry before Retry-After, e.g. Binance answers 418 after 429
+            let delay = match &failure {
+                Failure::Status(_, Some(retry_after)) if *retry_after > max_retry_after => {
+                    return Err(rest_error(format!("{}, Retry-After {} s exceeds rest.max_retry_after_ms, giving up",
+                        failure, retry_after.as_secs())));
+                }
+                Failure::Status(_, Some(retry_after)) => (*retry_after).max(backoff),
+                _ => backoff,
+            };
+            tracing::warn!("Request to {} failed: {}, retrying in {} ms (attempt {} of {})",
+                url, failure, delay.as_millis(), attempt, self.config.max_retries + 1);
+            std::thread::sleep(delay);
+            backoff = (backoff * 2).min(max_backoff);
+        }
+    }
+
+    // Blocks until the bucket of the url's host has a token
+    fn acquire(&self, url: &str) {
+        let host = reqwest::Url::parse(url)
+            .map(|url| format!("{}:{}", url.host_str().unwrap_or_default(), url.port_or_known_default().unwrap_or_default()))
+            .unwrap_or_default();
+        let rate = self.config.requests_per_second;
+        let burst = self.config.burst as f64;
+        loop {
+            let wait = {
+                let mut buckets = self.buckets.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                let bucket = buckets.entry(host.clone()).or_insert_with(|| TokenBucket { tokens: burst, refilled_at: Instant::now() });
+                let now = Instant::now();
+                bucket.tokens = (bucket.tokens + now.duration_since(bucket.refilled_at).as_secs_f64() * rate).min(burst);
+                bucket.refilled_at = now;
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - bucket.tokens) / rate)
+            };
+            tracing::debug!("Rate limiting requests to {} for {} ms", host, wait.as_millis());
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+// The exchanges report some errors with HTTP 200 and an error body
+fn check_exchange_error(exchange: Exchange, body: &str) -> Result<(), EngineError> {
+    // Bodies that are not a JSON object fail later as parse errors
+    let envelope: ErrorEnvelope = serde_json::from_str(body).unwrap_or_default();
+    let failed = match exchange {
+        Exchange::Htx => envelope.status.as_deref() == Some("error") || envelope.err_code.is_some(),
+        Exchange::Binance => envelope.code.is_some(),
+    };
+    if !failed {
+        return Ok(());
+    }
+    let (code, msg) = match exchange {
+        Exchange::Htx => (envelope.err_code, envelope.err_msg),
+        Exchange::Binance => (envelope.code.map(|code| code.to_string()), envelope.msg),
+    };
+    tracing::error!("{} returned an error: {}: {}", exchange, code.as_deref().unwrap_or("N/A"), msg.as_deref().unwrap_or("N/A"));
+    Err(EngineError::exchange(code.as_ref(), msg.as_ref()))
+}
+
+// Retry-After in seconds, the HTTP date form is not used by the exchanges
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(reqwest::header::RETRY_AFTER)?;
+    value.to_str().ok()?.trim().parse().ok().map(Duration::from_secs)
+}
+
+fn truncated(body: &str) -> &str {
+    match body.char_indices().nth(MAX_ERROR_BODY) {
+        Some((end, _)) => &body[..end],
+        None => body,
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Status(status, _) => write!(f, "HTTP {}", status),
+            Failure::Transport(error) if error.is_timeout() => write!(f, "timed out: {}", error),
+            Failure::Transport(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Data {
+        data: Vec<u32>,
+    }
+
+    fn config() -> RestConfig {
+        RestConfig {
+            connect_timeout_ms: 1_000,
+            read_timeout_ms: 2_000,
+            max_retries: 3,
+            initial_backoff_ms: 1,
+            max_backoff_ms: 10,
+            max_retry_after_ms: 5_000,
+            requests_per_second: 1_000.0,
+            burst: 100,
+        }
+    }
+
+    fn response(status: &str, headers: &[&str], body: &str) -> String {
+        let mut response = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n", status, body.len());
+        for header in headers {
+            response.push_str(header);
+            response.push_str("\r\n");
+        }
+        response.push_str("\r\n");
+        response.push_str(body);
+        response
+    }
+
+    // Answers one request per connection with the next canned response, returns the url and the request count
+    fn serve(responses: Vec<String>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/v1/data", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&requests);
+        std::thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 1024];
+                while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    let read = stream.read(&mut buffer).unwrap();
+                    if read == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buffer[..read]);
+                }
+                counter.fetch_add(1, Ordering::SeqCst);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn get(config: &RestConfig, exchange: Exchange, url: &str) -> Result<Data, EngineError> {
+        RestClient::new(config).unwrap().get(exchange, url, "data")
+    }
+
+    #[test]
+    fn returns_the_deserialized_body() {
+        let (url, requests) = serve(vec![response("200 OK", &[], r#"{"status":"ok","data":[1,2]}"#)]);
+        assert_eq!(get(&config(), Exchange::Htx, &url).unwrap(), Data { data: vec![1, 2] });
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn retries_server_errors() {
+        let (url, requests) = serve(vec![
+            response("503 Service Unavailable", &[], "unavailable"),
+            response("502 Bad Gateway", &[], ""),
+            response("200 OK", &[], r#"{"data":[3]}"#),
+        ]);
+        assert_eq!(get(&config(), Exchange::Htx, &url).unwrap(), Data { data: vec![3] });
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn gives_up_after_max_retries() {
+        let config = RestConfig { max_retries: 2, ..config() };
+        let (url, requests) = serve(vec![response("500 Internal Server Error", &[], ""); 4]);
+        let error = get(&config, Exchange::Htx, &url).unwrap_err();
+        assert!(matches!(&error, EngineError::Rest { message, .. } if message.contains("giving up after 3 attempts")), "{}", error);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn waits_at_least_retry_after() {
+        let (url, requests) = serve(vec![
+            response("429 Too Many Requests", &["Retry-After: 1"], ""),
+            response("200 OK", &[], r#"{"data":[]}"#),
+        ]);
+        let started = Instant::now();
+        assert_eq!(get(&config(), Exchange::Binance, &url).unwrap(), Data { data: vec![] });
+        // Above max_backoff_ms, which must not cut it short
+        assert!(started.elapsed() >= Duration::from_secs(1), "{:?}", started.elapsed());
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn gives_up_on_retry_after_beyond_the_ceiling() {
+        let (url, requests) = serve(vec![
+            response("429 Too Many Requests", &["Retry-After: 120"], ""),
+            response("200 OK", &[], r#"{"data":[]}"#),
+        ]);
+        let started = Instant::now();
+        let error = get(&config(), Exchange::Binance, &url).unwrap_err();
+        assert!(matches!(&error, EngineError::Rest { message, .. } if message.contains("Retry-After 120 s")), "{}", error);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn reports_htx_errors_of_successful_responses() {
+        let body = r#"{"status":"error","err-code":"invalid-parameter","err-msg":"invalid symbol"}"#;
+        let (url, requests) = serve(vec![response("200 OK", &[], body)]);
+        match get(&config(), Exchange::Htx, &url).unwrap_err() {
+            EngineError::Exchange { code, msg } => {
+                assert_eq!(code, "invalid-parameter");
+                assert_eq!(msg, "invalid symbol");
+            }
+            error => panic!("unexpected error {}", error),
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn reports_binance_errors_of_client_errors_without_retrying() {
+        let (url, requests) = serve(vec![
+            response("400 Bad Request", &[], r#"{"code":-1121,"msg":"Invalid symbol."}"#),
+            response("200 OK", &[], r#"{"data":[]}"#),
+        ]);
+        match get(&config(), Exchange::Binance, &url).unwrap_err() {
+            EngineError::Exchange { code, msg } => {
+                assert_eq!(code, "-1121");
+                assert_eq!(msg, "Invalid symbol.");
+            }
+            error => panic!("unexpected error {}", error),
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn client_errors_without_exchange_error_are_final() {
+        let (url, requests) = serve(vec![
+            response("404 Not Found", &[], "not found"),
+            response("200 OK", &[], r#"{"data":[]}"#),
+        ]);
+        let error = get(&config(), Exchange::Htx, &url).unwrap_err();
+        assert!(matches!(&error, EngineError::Rest { message, .. } if message.starts_with("HTTP 404")), "{}", error);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn unexpected_bodies_are_parse_errors() {
+        let (url, _) = serve(vec![response("200 OK", &[], r#"{"data":"none"}"#)]);
+        assert!(matches!(get(&config(), Exchange::Htx, &url), Err(EngineError::Parse { what: "data", .. })));
+    }
+
+    #[test]
+    fn retries_connection_failures() {
+        // Nothing listens on the port once the listener is dropped
+        let url = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}/v1/data", listener.local_addr().unwrap())
+        };
+        let config = RestConfig { max_retries: 1, ..config() };
+        let error = get(&config, Exchange::Htx, &url).unwrap_err();
+        assert!(matches!(&error, EngineError::Rest { message, .. } if message.contains("giving up after 2 attempts")), "{}", error);
+    }
+
+    #[test]
+    fn rate_limits_requests_per_host() {
+        let config = RestConfig { requests_per_second: 20.0, burst: 1, ..config() };
+        let (url, requests) = serve(vec![response("200 OK", &[], r#"{"data":[]}"#); 3]);
+        let client = RestClient::new(&config).unwrap();
+        let started = Instant::now();
+        for _ in 0..3 {
+            client.get::<Data>(Exchange::Htx, &url, "data").unwrap();
+        }
+        // The first request takes the burst token, the others wait 50 ms each
+        assert!(started.elapsed() >= Duration::from_millis(90), "{:?}", started.elapsed());
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+}
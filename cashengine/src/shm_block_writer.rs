@@ -1,6 +1,9 @@
-use crate::util::MAX_USIZE_STRING_LENGTH;
+use crate::shm_chunk;
+use crate::shm_chunk::ChunkPayload;
+use crate::shm_file;
+use crate::shm_chunk::ChunkHeader;
+use crate::time_util::monotonic_nanos;
 use memmap2::{MmapMut, MmapOptions};
-use std::fmt::Write;
 use std::fs::File;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tracing::{enabled, Level};
@@ -17,51 +20,62 @@ unsafe impl Send for ShareablePtr {
 
 pub struct SharedMemoryWriter<'a> {
     sequence: usize,
+    #[allow(dead_code)] // Ties the writer to the lifetime of the SHM file
     mmap_file: &'a File,
+    #[allow(dead_code)] // Keeps the mapping alive
     mmap: MmapMut,
     writer_id: usize,
     chunk_size: usize,
+    chunks_per_writer: usize,
     shareable_ptr: ShareablePtr,
-    write_buffer: String,
+    // `ChunkHeader::monotonic_nanos` of the last write, 0 before the first
+    last_write_nanos: u64,
+    // Messages that were too large for a chunk or addressed a chunk outside the writer's block
+    dropped: u64,
 }
 
 impl<'a> SharedMemoryWriter<'a> {
+    // Maps the chunks of `writer_id` from an SHM file initialized with `shm_file::create`
     pub fn create(
         mmap_file: &'a File,
         writer_id: usize,
-        chunk_size: usize,
-        chunk_count: usize,
-    ) -> SharedMemoryWriter<'a> {
-        let block_size = chunk_size * chunk_count;
-        let mut mmap = SharedMemoryWriter::map_file_to_memory(mmap_file, writer_id, block_size);
+    ) -> std::io::Result<SharedMemoryWriter<'a>> {
+        let header = shm_file::read_header(mmap_file)?;
+        if writer_id >= header.writer_count as usize {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("writer_id {} exceeds writer count {} of SHM file", writer_id, header.writer_count),
+            ));
+        }
+        let chunk_size = header.chunk_size as usize;
+        let chunks_per_writer = header.chunks_per_writer as usize;
+        let block_size = chunk_size * chunks_per_writer;
+        let offset = header.writer_offset(writer_id);
+        let mut mmap = SharedMemoryWriter::map_file_to_memory(mmap_file, writer_id, offset, block_size)?;
         let start_ptr =
             SharedMemoryWriter::initialize_start_ptr_to_mapped_memory(&mut mmap, writer_id);
         let shareable_ptr = ShareablePtr(start_ptr);
-        let shm_writer = SharedMemoryWriter {
+        Ok(SharedMemoryWriter {
             sequence: 0,
             mmap_file,
             mmap,
             writer_id,
             chunk_size,
+            chunks_per_writer,
             shareable_ptr,
-            write_buffer: String::with_capacity(chunk_size),
-        };
-        shm_writer
+            last_write_nanos: 0,
+            dropped: 0,
+        })
     }
 
-    fn map_file_to_memory(file: &File, writer_id: usize, block_size: usize) -> MmapMut {
+    fn map_file_to_memory(file: &File, writer_id: usize, offset: usize, block_size: usize) -> std::io::Result<MmapMut> {
         tracing::info!("Mapping file to memory for writer_id {}", writer_id);
         unsafe {
-            match MmapOptions::new()
-                .offset((writer_id * block_size) as u64)
+            MmapOptions::new()
+                .offset(offset as u64)
                 .len(block_size)
                 .map_mut(file)
-            {
-                Ok(mmap) => mmap,
-                Err(e) => {
-                    panic!("Failed to map SHM file to memory for writer_id {}: {}", writer_id, e);
-                }
-            }
+                .inspect_err(|e| tracing::error!("Failed to map SHM file to memory for writer_id {}: {}", writer_id, e))
         }
     }
 
@@ -72,53 +86,75 @@ impl<'a> SharedMemoryWriter<'a> {
         start_ptr
     }
 
+    // Writes raw message bytes into the chunk
     pub fn write(&mut self, chunk_index: usize, message: &[u8]) {
+        self.write_payload(chunk_index, message, shm_chunk::KIND_RAW);
+    }
+
+    // Writes a parsed message such as a `Bbo` in its binary layout into the chunk
+    pub fn write_typed<T: ChunkPayload>(&mut self, chunk_index: usize, payload: &T) {
+        self.write_payload(chunk_index, payload.as_bytes(), T::KIND);
+    }
+
+    // Drops the message if it doesn't fit, see `dropped`
+    fn write_payload(&mut self, chunk_index: usize, message: &[u8], flags: u32) {
+        if chunk_index >= self.chunks_per_writer {
+            self.dropped += 1;
+            tracing::warn!("SharedMemoryWriter writer_id {} dropped a message for chunk {}, it has {} chunks, total dropped: {}",
+                           self.writer_id, chunk_index, self.chunks_per_writer, self.dropped);
+            return;
+        }
+        let max_message_size = shm_chunk::max_message_size(self.chunk_size);
+        if message.len() > max_message_size {
+            self.dropped += 1;
+            tracing::warn!("SharedMemoryWriter writer_id {} dropped a message of {} bytes, max message size is {}, total dropped: {}",
+                           self.writer_id, message.len(), max_message_size, self.dropped);
+            return;
+        }
         let start_ptr: *mut u8 = self.shareable_ptr.0;
-        self.write_buffer.clear();
 
-        let start_timestamp_micros = self.start_bench();
+        let start_timestamp_nanos = self.start_bench();
+        let monotonic_nanos = monotonic_nanos();
 
         let target_offset = chunk_index * self.chunk_size;
-        // TODO: Remove String allocation and this write!() here. Use copy_nonoverlapping() to directly copy the message into the shared memory.
-        let mut message = String::from_utf8(message.to_vec()).unwrap();
-        message.push('\0');
-
-        write!(
-            &mut self.write_buffer,
-            "{}:{}:{}:{:0width$}:{}",
-            self.writer_id,
-            self.sequence,
-            start_timestamp_micros,
-            self.writer_id + (target_offset),
-            message,
-            width = MAX_USIZE_STRING_LENGTH
-        )
-        .unwrap();
-        if self.write_buffer.len() > self.chunk_size {
-            panic!("SharedMemoryWriter writer_id {} write_buffer size {} is greater than chunk size: {}",
-                   self.writer_id, self.write_buffer.len(), self.chunk_size);
-        }
+
+        let header = ChunkHeader {
+            writer_id: self.writer_id as u32,
+            // Global like the directory and reader indexes, not the index within the writer's block
+            market_index: (self.writer_id * self.chunks_per_writer + chunk_index) as u32,
+            sequence: self.sequence as u64,
+            timestamp_nanos: start_timestamp_nanos,
+            monotonic_nanos,
+            payload_len: message.len() as u32,
+            flags,
+        };
 
         if enabled!(Level::TRACE) {
             tracing::debug!(
                 "SharedMemoryWriter writer_id {} writing to offset {} at time {}",
                 self.writer_id,
                 self.shareable_ptr.0.addr() + target_offset,
-                start_timestamp_micros
+                start_timestamp_nanos
             );
         }
 
         let write_start = SystemTime::now();
 
         unsafe {
-            // SAFETY: We never overlap on writes.
+            // SAFETY: We never overlap on writes and the chunk lies within the writer's block, checked above.
             // Pointer is living because we use scoped threads.
+            // Chunks are 8-byte aligned because the mapping is page aligned and chunk_size is a multiple of 8.
             let target_ptr = start_ptr.add(target_offset);
+            let version = shm_chunk::version(target_ptr);
+            let next_version = shm_chunk::begin_write(version);
+            let header_ptr = target_ptr.add(shm_chunk::VERSION_SIZE);
+            std::ptr::write(header_ptr.cast::<ChunkHeader>(), header);
             std::ptr::copy_nonoverlapping(
-                self.write_buffer.as_ptr(),
-                target_ptr,
-                self.chunk_size,
+                message.as_ptr(),
+                header_ptr.add(shm_chunk::HEADER_SIZE),
+                message.len(),
             );
+            shm_chunk::end_write(version, next_version);
         }
 
         if enabled!(Level::TRACE) {
@@ -127,36 +163,43 @@ impl<'a> SharedMemoryWriter<'a> {
                 "SharedMemoryWriter writer_id {} wrote at offset {} at time {}. Write took {} μs",
                 self.writer_id,
                 start_ptr.addr(),
-                start_timestamp_micros,
+                start_timestamp_nanos,
                 write_duration.as_micros()
             );
         }
 
-        // Make writes visible for main thread
-        // It is not necessary when using `std::thread::scope` but may be necessary in your case.
-        std::sync::atomic::fence(std::sync::atomic::Ordering::Release);
         self.sequence += 1;
+        self.last_write_nanos = monotonic_nanos;
     }
 
-    fn start_bench(&self) -> u128 {
+    // Changes with every write, so callers can tell whether a message was written
+    pub fn last_write_nanos(&self) -> u64 {
+        self.last_write_nanos
+    }
+
+    // Messages dropped instead of written, see `write_payload`
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    fn start_bench(&self) -> u64 {
         let current_system_time = SystemTime::now();
-        let mut timestamp_micros = 0;
+        let mut timestamp_nanos = 0;
         match current_system_time.duration_since(UNIX_EPOCH) {
             Ok(duration_since_epoch) => {
-                timestamp_micros = duration_since_epoch.as_micros();
+                timestamp_nanos = duration_since_epoch.as_nanos() as u64;
             }
             Err(err) => tracing::error!(
                 "SharedMemoryWriter writer_id {} failed getting duration for UNIX epoch: {}",
                 self.writer_id, err
             ),
         }
-        timestamp_micros
+        timestamp_nanos
     }
 
     fn end_bench(&self, write_start: SystemTime) -> Duration {
         let write_end = SystemTime::now();
-        let write_duration = write_end.duration_since(write_start).unwrap();
-        write_duration
+        write_end.duration_since(write_start).unwrap()
     }
 }
 
@@ -167,3 +210,51 @@ impl<'a> Drop for SharedMemoryWriter<'a> {
         std::sync::atomic::fence(std::sync::atomic::Ordering::Acquire);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shm_reader::SharedMemoryReader;
+
+    const CHUNK_SIZE: usize = 128;
+
+    fn create_file(name: &str) -> (std::path::PathBuf, File) {
+        let path = std::env::temp_dir().join(format!("cashengine-{}-{}.mmap", name, std::process::id()));
+        let file = shm_file::create(path.to_str().unwrap(), &shm_file::FileHeader::new(CHUNK_SIZE, 2, 2)).unwrap();
+        (path, file)
+    }
+
+    #[test]
+    fn drops_messages_for_chunks_outside_the_block() {
+        let (path, file) = create_file("writer-index");
+        let mut writer = SharedMemoryWriter::create(&file, 0).unwrap();
+        writer.write(2, b"next writer");
+        writer.write(usize::MAX / CHUNK_SIZE, b"far away");
+        assert_eq!(writer.dropped(), 2);
+        assert_eq!(writer.last_write_nanos(), 0);
+
+        writer.write(1, b"last chunk");
+        assert_eq!(writer.dropped(), 2);
+        let mut reader = SharedMemoryReader::attach(&file).unwrap();
+        assert!(reader.read_chunk(2).is_none());
+        assert_eq!(reader.read_chunk(1).unwrap().message(), b"last chunk");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn drops_messages_larger_than_a_chunk() {
+        let (path, file) = create_file("writer-size");
+        let mut writer = SharedMemoryWriter::create(&file, 1).unwrap();
+        let max_message_size = shm_chunk::max_message_size(CHUNK_SIZE);
+        writer.write(0, &vec![b'x'; max_message_size + 1]);
+        assert_eq!(writer.dropped(), 1);
+
+        writer.write(0, &vec![b'y'; max_message_size]);
+        assert_eq!(writer.dropped(), 1);
+        let mut reader = SharedMemoryReader::attach(&file).unwrap();
+        let chunk = reader.read_chunk(2).unwrap();
+        assert_eq!(chunk.message(), vec![b'y'; max_message_size].as_slice());
+        assert_eq!((chunk.header().writer_id, chunk.header().market_index, chunk.header().sequence), (1, 2, 0));
+        std::fs::remove_file(path).unwrap();
+    }
+}
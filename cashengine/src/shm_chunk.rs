@@ -0,0 +1,151 @@
+use crate::bbo::Bbo;
+use crate::market_data::Depth;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Every chunk starts with a seqlock version word followed by the payload.
+// The writer makes the version odd before and even after copying the payload.
+// A reader that sees an odd version, or a different version after copying, retries.
+pub const VERSION_SIZE: usize = size_of::<u64>();
+
+// The payload of a chunk starts with this header followed by `payload_len` raw message bytes.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct ChunkHeader {
+    pub writer_id: u32,
+    // Global chunk index, `writer_id * chunks_per_writer + chunk index of the writer`
+    pub market_index: u32,
+    pub sequence: u64,
+    // Nanoseconds since UNIX epoch when the writer received the message
+    pub timestamp_nanos: u64,
+    // CLOCK_MONOTONIC nanoseconds of the write, for latencies measured by readers on the same host
+    pub monotonic_nanos: u64,
+    pub payload_len: u32,
+    // Kind of payload, one of the KIND_* constants
+    pub flags: u32,
+}
+
+pub const HEADER_SIZE: usize = size_of::<ChunkHeader>();
+
+// Raw message bytes as received from the exchange
+pub const KIND_RAW: u32 = 0;
+// A `Bbo` in its binary layout
+pub const KIND_BBO: u32 = 1;
+// A `market_data::TradeBatch` in its binary layout
+pub const KIND_TRADES: u32 = 2;
+// A `market_data::Depth` in its binary layout, from depth.step0 or an mbp order book
+pub const KIND_DEPTH: u32 = 3;
+// A `market_data::Kline` in its binary layout
+pub const KIND_KLINE: u32 = 4;
+// A `market_data::MarketDetail` in its binary layout
+pub const KIND_DETAIL: u32 = 5;
+
+/// Fixed-layout message types that are written into chunks and viewed zero-copy by readers.
+///
+/// # Safety
+/// Implementors must be repr(C) plain old data without padding and at most 8-byte aligned.
+pub unsafe trait ChunkPayload: Copy {
+    const KIND: u32;
+
+    fn as_bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts((self as *const Self).cast::<u8>(), size_of::<Self>()) }
+    }
+}
+
+// Chunk size needed to hold one `T`, rounded up to keep chunks aligned
+pub const fn chunk_size_for<T: ChunkPayload>() -> usize {
+    (VERSION_SIZE + HEADER_SIZE + size_of::<T>()).next_multiple_of(CHUNK_ALIGNMENT)
+}
+
+// Chunks must keep the version word 8-byte aligned
+pub const CHUNK_ALIGNMENT: usize = align_of::<AtomicU64>();
+
+// Bytes available for the header and message after the version word
+pub(crate) fn payload_size(chunk_size: usize) -> usize {
+    chunk_size - VERSION_SIZE
+}
+
+// Largest message that fits into a chunk
+pub fn max_message_size(chunk_size: usize) -> usize {
+    chunk_size - VERSION_SIZE - HEADER_SIZE
+}
+
+// A consistent copy of a chunk's header and message, borrowed from the reader's buffer
+pub struct Chunk<'r> {
+    header: &'r ChunkHeader,
+    message: &'r [u8],
+}
+
+impl<'r> Chunk<'r> {
+    // SAFETY: `payload` must be 8-byte aligned and hold a header written by `SharedMemoryWriter`.
+    pub(crate) unsafe fn from_payload(payload: &'r [u8]) -> Chunk<'r> {
+        let header = &*payload.as_ptr().cast::<ChunkHeader>();
+        let message_end = HEADER_SIZE + (header.payload_len as usize).min(payload.len() - HEADER_SIZE);
+        Chunk {
+            header,
+            message: &payload[HEADER_SIZE..message_end],
+        }
+    }
+
+    pub fn header(&self) -> &'r ChunkHeader {
+        self.header
+    }
+
+    pub fn message(&self) -> &'r [u8] {
+        self.message
+    }
+
+    // Zero-copy view of the payload if the chunk holds a `T`
+    pub fn payload<T: ChunkPayload>(&self) -> Option<&'r T> {
+        if self.header.flags != T::KIND || self.message.len() != size_of::<T>() {
+            return None;
+        }
+        // SAFETY: The message starts 8-byte aligned after the header and was written from a `T`.
+        unsafe { Some(&*self.message.as_ptr().cast::<T>()) }
+    }
+
+    pub fn bbo(&self) -> Option<&'r Bbo> {
+        self.payload::<Bbo>()
+    }
+
+    pub fn depth(&self) -> Option<&'r Depth> {
+        self.payload::<Depth>()
+    }
+}
+
+// SAFETY: `chunk_ptr` must point to a mapped chunk that is 8-byte aligned and lives as long as `'a`.
+pub(crate) unsafe fn version<'a>(chunk_ptr: *mut u8) -> &'a AtomicU64 {
+    AtomicU64::from_ptr(chunk_ptr.cast::<u64>())
+}
+
+// Marks the chunk as being written and returns the version to publish with `end_write`
+pub(crate) fn begin_write(version: &AtomicU64) -> u64 {
+    let current = version.load(Ordering::Relaxed);
+    version.store(current + 1, Ordering::Relaxed);
+    // Payload stores must not become visible before the odd version
+    std::sync::atomic::fence(Ordering::Release);
+    current + 2
+}
+
+pub(crate) fn end_write(version: &AtomicU64, next: u64) {
+    version.store(next, Ordering::Release);
+}
+
+// Readers give up on a chunk or directory entry that stays inconsistent, e.g. because its writer died mid-write
+pub(crate) const MAX_READ_RETRIES: usize = 64;
+
+// Returns the version if no write is in progress
+pub(crate) fn begin_read(version: &AtomicU64) -> Option<u64> {
+    let current = version.load(Ordering::Acquire);
+    if current & 1 == 1 {
+        None
+    } else {
+        Some(current)
+    }
+}
+
+// Returns true if the payload copied since `begin_read` is consistent
+pub(crate) fn end_read(version: &AtomicU64, started: u64) -> bool {
+    // Payload loads must not be reordered after the version check
+    std::sync::atomic::fence(Ordering::Acquire);
+    version.load(Ordering::Relaxed) == started
+}
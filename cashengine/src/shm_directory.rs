@@ -0,0 +1,199 @@
+use crate::binance_symbol::BinanceSymbol;
+use crate::decimal::Decimal;
+use crate::instrument::{Instrument, InstrumentStatus};
+use crate::shm_chunk;
+use crate::shm_file;
+use memmap2::{MmapMut, MmapOptions};
+use std::fs::File;
+use std::sync::atomic::AtomicU64;
+
+// The market index directory holds one entry per chunk, describing the market written into that chunk.
+// Entries are guarded by the same seqlock protocol as the chunks, so they can be updated while consumers read.
+pub const SYMBOL_SIZE: usize = 32;
+pub const CURRENCY_SIZE: usize = 16;
+
+// Precision value for markets where the exchange didn't provide one
+pub const UNKNOWN_PRECISION: i32 = -1;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct DirectoryEntry {
+    // NUL padded, empty if the chunk is unused
+    pub symbol: [u8; SYMBOL_SIZE],
+    pub base_currency: [u8; CURRENCY_SIZE],
+    pub quote_currency: [u8; CURRENCY_SIZE],
+    pub price_precision: i32,
+    pub amount_precision: i32,
+    // `InstrumentStatus::code`, updated when a market is halted or resumes
+    pub status: u32,
+    pub reserved: u32,
+}
+
+// Seqlock version word plus the entry
+pub const ENTRY_SIZE: usize = shm_chunk::VERSION_SIZE + size_of::<DirectoryEntry>();
+
+impl DirectoryEntry {
+    pub const EMPTY: DirectoryEntry = DirectoryEntry {
+        symbol: [0; SYMBOL_SIZE],
+        base_currency: [0; CURRENCY_SIZE],
+        quote_currency: [0; CURRENCY_SIZE],
+        price_precision: UNKNOWN_PRECISION,
+        amount_precision: UNKNOWN_PRECISION,
+        status: 0,
+        reserved: 0,
+    };
+
+    pub(crate) fn from_instrument(instrument: &Instrument) -> DirectoryEntry {
+        DirectoryEntry {
+            symbol: to_fixed(&instrument.symbol),
+            base_currency: to_fixed(&instrument.base),
+            quote_currency: to_fixed(&instrument.quote),
+            price_precision: instrument.tick_size.map_or(UNKNOWN_PRECISION, precision),
+            amount_precision: instrument.lot_size.map_or(UNKNOWN_PRECISION, precision),
+            status: instrument.status.code(),
+            reserved: 0,
+        }
+    }
+
+    // Lowercase like the stream names, e.g. BTCUSDT -> btcusdt
+    pub(crate) fn from_binance_symbol(symbol: &BinanceSymbol) -> DirectoryEntry {
+        DirectoryEntry {
+            symbol: to_fixed(&symbol.symbol.as_deref().unwrap_or_default().to_ascii_lowercase()),
+            base_currency: to_fixed(&symbol.base_asset.as_deref().unwrap_or_default().to_ascii_lowercase()),
+            quote_currency: to_fixed(&symbol.quote_asset.as_deref().unwrap_or_default().to_ascii_lowercase()),
+            price_precision: symbol.price_precision().unwrap_or(UNKNOWN_PRECISION),
+            amount_precision: symbol.amount_precision().unwrap_or(UNKNOWN_PRECISION),
+            status: InstrumentStatus::from_binance(symbol.status.as_deref()).code(),
+            reserved: 0,
+        }
+    }
+
+    // Entry with only the symbol known, e.g. for markets of a replayed recording
+    pub(crate) fn from_symbol(symbol: &str) -> DirectoryEntry {
+        DirectoryEntry {
+            symbol: to_fixed(symbol),
+            ..DirectoryEntry::EMPTY
+        }
+    }
+
+    pub fn symbol(&self) -> &str {
+        from_fixed(&self.symbol)
+    }
+
+    pub fn base_currency(&self) -> &str {
+        from_fixed(&self.base_currency)
+    }
+
+    pub fn quote_currency(&self) -> &str {
+        from_fixed(&self.quote_currency)
+    }
+
+    pub fn status(&self) -> InstrumentStatus {
+        InstrumentStatus::from_code(self.status)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbol[0] == 0
+    }
+}
+
+// Decimals of an increment, e.g. 0.01 -> 2 and 10 -> -1
+fn precision(increment: Decimal) -> i32 {
+    let increment = increment.normalized();
+    if increment.scale() > 0 {
+        return increment.scale() as i32;
+    }
+    let mut mantissa = increment.mantissa();
+    let mut precision = 0;
+    while mantissa != 0 && mantissa % 10 == 0 {
+        mantissa /= 10;
+        precision -= 1;
+    }
+    precision
+}
+
+fn to_fixed<const N: usize>(value: &str) -> [u8; N] {
+    let mut fixed = [0u8; N];
+    // Keep one NUL terminator
+    let len = value.len().min(N - 1);
+    if len < value.len() {
+        tracing::warn!("Truncating '{}' to {} bytes for the SHM market directory", value, len);
+    }
+    fixed[..len].copy_from_slice(&value.as_bytes()[..len]);
+    fixed
+}
+
+fn from_fixed(value: &[u8]) -> &str {
+    let end = value.iter().position(|&c| c == b'\0').unwrap_or(value.len());
+    std::str::from_utf8(&value[..end]).unwrap_or_default()
+}
+
+// Size of the directory region for `chunk_count` entries, rounded up to whole pages
+pub fn region_size(chunk_count: usize) -> usize {
+    (chunk_count * ENTRY_SIZE).div_ceil(shm_file::HEADER_REGION_SIZE) * shm_file::HEADER_REGION_SIZE
+}
+
+pub struct DirectoryWriter<'a> {
+    #[allow(dead_code)] // Ties the writer to the lifetime of the SHM file
+    mmap_file: &'a File,
+    mmap: MmapMut,
+    entry_count: usize,
+}
+
+impl<'a> DirectoryWriter<'a> {
+    pub fn create(mmap_file: &'a File) -> std::io::Result<DirectoryWriter<'a>> {
+        let header = shm_file::read_header(mmap_file)?;
+        let entry_count = header.chunk_count as usize;
+        tracing::info!("Mapping SHM market directory with {} entries", entry_count);
+        let mmap = unsafe {
+            MmapOptions::new()
+                .offset(header.market_index_offset)
+                .len(entry_count * ENTRY_SIZE)
+                .map_mut(mmap_file)?
+        };
+        Ok(DirectoryWriter {
+            mmap_file,
+            mmap,
+            entry_count,
+        })
+    }
+
+    // Publishes the market written into the chunk with the global `chunk_index`
+    pub fn publish(&mut self, chunk_index: usize, entry: &DirectoryEntry) {
+        if chunk_index >= self.entry_count {
+            panic!("DirectoryWriter chunk_index {} exceeds entry count {}", chunk_index, self.entry_count);
+        }
+        unsafe {
+            // SAFETY: Entries are 8-byte aligned, ENTRY_SIZE is a multiple of 8 and the index is checked.
+            let entry_ptr = self.mmap.as_mut_ptr().add(chunk_index * ENTRY_SIZE);
+            let version = shm_chunk::version(entry_ptr);
+            let next_version = shm_chunk::begin_write(version);
+            std::ptr::write(entry_ptr.add(shm_chunk::VERSION_SIZE).cast::<DirectoryEntry>(), *entry);
+            shm_chunk::end_write(version, next_version);
+        }
+    }
+
+    pub fn clear(&mut self, chunk_index: usize) {
+        self.publish(chunk_index, &DirectoryEntry::EMPTY);
+    }
+}
+
+// Reads a consistent copy of an entry. `directory_ptr` must point to the mapped directory region.
+// Returns None if the entry stayed torn, e.g. because the engine died while publishing it.
+pub(crate) unsafe fn read_entry(directory_ptr: *const u8, chunk_index: usize) -> Option<DirectoryEntry> {
+    let entry_ptr = directory_ptr.add(chunk_index * ENTRY_SIZE);
+    let version = shm_chunk::version(entry_ptr.cast_mut());
+    for _ in 0..shm_chunk::MAX_READ_RETRIES {
+        if let Some(started) = shm_chunk::begin_read(version) {
+            let entry = std::ptr::read_volatile(entry_ptr.add(shm_chunk::VERSION_SIZE).cast::<DirectoryEntry>());
+            if shm_chunk::end_read(version, started) {
+                return Some(entry);
+            }
+        }
+        std::hint::spin_loop();
+    }
+    tracing::warn!("Gave up reading SHM market directory entry {} after {} retries", chunk_index, shm_chunk::MAX_READ_RETRIES);
+    None
+}
+
+const _: () = assert!(ENTRY_SIZE.is_multiple_of(align_of::<AtomicU64>()));
@@ -0,0 +1,132 @@
+use crate::shm_directory;
+use memmap2::{MmapMut, MmapOptions};
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{fence, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The SHM file starts with a `FileHeader` region, followed by the market index directory
+// and the chunks of all writers.
+// Consumers in other processes read the header to discover the layout instead of sharing constants.
+pub const MAGIC: u64 = u64::from_le_bytes(*b"CESHMEM\0");
+pub const LAYOUT_VERSION: u32 = 4;
+
+// Regions start on page boundaries so they stay aligned independent of the header size
+pub const HEADER_REGION_SIZE: usize = 4096;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct FileHeader {
+    pub magic: u64,
+    pub layout_version: u32,
+    pub header_size: u32,
+    pub chunk_size: u64,
+    pub chunk_count: u64,
+    pub writer_count: u64,
+    pub chunks_per_writer: u64,
+    pub chunks_offset: u64,
+    // Offset of the market index directory with one `DirectoryEntry` per chunk
+    pub market_index_offset: u64,
+    pub created_at_nanos: u64,
+}
+
+impl FileHeader {
+    pub fn new(chunk_size: usize, writer_count: usize, chunks_per_writer: usize) -> FileHeader {
+        let created_at_nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        let chunk_count = writer_count * chunks_per_writer;
+        let market_index_offset = HEADER_REGION_SIZE;
+        FileHeader {
+            magic: MAGIC,
+            layout_version: LAYOUT_VERSION,
+            header_size: size_of::<FileHeader>() as u32,
+            chunk_size: chunk_size as u64,
+            chunk_count: chunk_count as u64,
+            writer_count: writer_count as u64,
+            chunks_per_writer: chunks_per_writer as u64,
+            chunks_offset: (market_index_offset + shm_directory::region_size(chunk_count)) as u64,
+            market_index_offset: market_index_offset as u64,
+            created_at_nanos,
+        }
+    }
+
+    pub fn file_size(&self) -> usize {
+        self.chunks_offset as usize + self.chunk_size as usize * self.chunk_count as usize
+    }
+
+    // Offset of the first chunk of a writer
+    pub fn writer_offset(&self, writer_id: usize) -> usize {
+        self.chunks_offset as usize + writer_id * self.chunks_per_writer as usize * self.chunk_size as usize
+    }
+
+    fn validate(&self) -> io::Result<()> {
+        if self.magic != MAGIC {
+            return Err(invalid_data(format!("SHM file has invalid magic {:#x}, not initialized yet?", self.magic)));
+        }
+        if self.layout_version != LAYOUT_VERSION {
+            return Err(invalid_data(format!(
+                "SHM file has layout version {}, expected {}", self.layout_version, LAYOUT_VERSION)));
+        }
+        if self.writer_count * self.chunks_per_writer != self.chunk_count {
+            return Err(invalid_data(format!(
+                "SHM file has inconsistent chunk count {} for {} writers with {} chunks each",
+                self.chunk_count, self.writer_count, self.chunks_per_writer)));
+        }
+        Ok(())
+    }
+}
+
+// Creates or truncates the SHM file, sizes it for the layout and publishes the header.
+// The chunks are zero-filled by the truncation.
+pub fn create(file_path: &str, header: &FileHeader) -> io::Result<File> {
+    tracing::info!("Creating SHM file: {}", file_path);
+    let file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(PathBuf::from(file_path))?;
+
+    tracing::info!("Resizing SHM file to {} bytes", header.file_size());
+    file.set_len(header.file_size() as u64)?;
+
+    let mut mmap = unsafe { MmapOptions::new().len(HEADER_REGION_SIZE).map_mut(&file)? };
+    write_header(&mut mmap, header);
+    mmap.flush()?;
+    tracing::info!("Initialized SHM file header: {:?}", header);
+    Ok(file)
+}
+
+fn write_header(mmap: &mut MmapMut, header: &FileHeader) {
+    let header_ptr = mmap.as_mut_ptr().cast::<FileHeader>();
+    unsafe {
+        // Publish the magic last, so attaching consumers never see a half written header
+        std::ptr::write(header_ptr, FileHeader { magic: 0, ..*header });
+        fence(Ordering::Release);
+        std::ptr::write_volatile(std::ptr::addr_of_mut!((*header_ptr).magic), header.magic);
+    }
+}
+
+// Reads and validates the header of an existing SHM file without modifying it
+pub fn read_header(file: &File) -> io::Result<FileHeader> {
+    let file_size = file.metadata()?.len() as usize;
+    if file_size < HEADER_REGION_SIZE {
+        return Err(invalid_data(format!("SHM file of {} bytes is too small for a header", file_size)));
+    }
+    let mmap = unsafe { MmapOptions::new().len(HEADER_REGION_SIZE).map(file)? };
+    let header = unsafe { std::ptr::read_volatile(mmap.as_ptr().cast::<FileHeader>()) };
+    fence(Ordering::Acquire);
+    header.validate()?;
+    if file_size < header.file_size() {
+        return Err(invalid_data(format!(
+            "SHM file of {} bytes is smaller than its layout of {} bytes", file_size, header.file_size())));
+    }
+    Ok(header)
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
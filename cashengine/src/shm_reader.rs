@@ -1,6 +1,11 @@
-use memmap2::{MmapMut, MmapOptions};
+use crate::shm_chunk;
+use crate::shm_chunk::Chunk;
+use crate::shm_directory;
+use crate::shm_directory::DirectoryEntry;
+use crate::shm_file;
+use crate::shm_file::FileHeader;
+use memmap2::{Mmap, MmapOptions};
 use std::fs::File;
-use std::ptr::write_bytes;
 use std::time::{Duration, SystemTime};
 use std::vec;
 use tracing::{enabled, Level};
@@ -18,95 +23,167 @@ unsafe impl Send for ShareablePtr {
 }
 
 pub struct SharedMemoryReader<'a> {
+    #[allow(dead_code)] // Ties the reader to the lifetime of the SHM file
     mmap_file: &'a File,
-    mmap: MmapMut,
+    mmap: Mmap,
+    header: FileHeader,
     chunk_size: usize,
     chunk_count: usize,
-    file_size: usize,
     shareable_ptr: ShareablePtr,
-    read_buffer: Vec<u8>,
+    // u64 elements keep the copied chunk header aligned
+    read_buffer: Vec<u64>,
     current_chunk_id: usize,
+    last_versions: Vec<u64>,
+    torn_reads: u64,
 }
 
 impl<'a> SharedMemoryReader<'a> {
-    pub fn create(
-        mmap_file: &'a File,
-        chunk_size: usize,
-        chunk_count: usize,
-    ) -> SharedMemoryReader<'a> {
-        let file_size = chunk_size * chunk_count;
-        let mut mmap = SharedMemoryReader::map_file_to_memory(&mmap_file, file_size);
-        let start_ptr =
-            SharedMemoryReader::initialize_start_ptr_to_mapped_memory(&mut mmap, file_size);
+    // Attaches read-only to an SHM file initialized with `shm_file::create`, also from another process.
+    // The layout is taken from the file header and live data is left untouched.
+    pub fn attach(mmap_file: &'a File) -> std::io::Result<SharedMemoryReader<'a>> {
+        let header = shm_file::read_header(mmap_file)?;
+        let chunk_size = header.chunk_size as usize;
+        let chunk_count = header.chunk_count as usize;
+        let mmap = SharedMemoryReader::map_file_to_memory(mmap_file, header.file_size())?;
+        let start_ptr = SharedMemoryReader::initialize_start_ptr_to_mapped_memory(&mmap, &header);
         let shareable_ptr = ShareablePtr(start_ptr);
-        let shm_reader = SharedMemoryReader {
+        Ok(SharedMemoryReader {
             mmap_file,
             mmap,
+            header,
             chunk_size,
             chunk_count,
-            file_size,
             shareable_ptr,
-            read_buffer: vec![0u8; chunk_size],
+            read_buffer: vec![0u64; shm_chunk::payload_size(chunk_size) / size_of::<u64>()],
             current_chunk_id: 0,
-        };
-        shm_reader
+            last_versions: vec![0u64; chunk_count],
+            torn_reads: 0,
+        })
     }
 
-    fn map_file_to_memory(file: &File, file_size: usize) -> MmapMut {
+    fn map_file_to_memory(file: &File, file_size: usize) -> std::io::Result<Mmap> {
         tracing::info!("Mapping SHM file to memory for reading");
         unsafe {
-            match MmapOptions::new().offset(0).len(file_size).map_mut(file) {
-                Ok(mmap) => mmap,
-                Err(e) => {
-                    panic!("Failed to map SHM file to memory for reading: {}", e);
-                }
-            }
+            MmapOptions::new()
+                .offset(0)
+                .len(file_size)
+                .map(file)
+                .inspect_err(|e| tracing::error!("Failed to map SHM file to memory for reading: {}", e))
         }
     }
 
-    fn initialize_start_ptr_to_mapped_memory(mmap: &mut MmapMut, file_size: usize) -> *mut u8 {
-        tracing::info!("Initializing SHM file with zeros for reading");
-        let start_ptr = mmap.as_mut_ptr();
-        unsafe {
-            write_bytes(start_ptr.offset(0), 0u8, file_size);
-        }
-        tracing::info!("Got for reading the start_ptr: {:p}", start_ptr);
+    fn initialize_start_ptr_to_mapped_memory(mmap: &Mmap, header: &FileHeader) -> *mut u8 {
+        // The mapping is read-only, the pointer is only used for loads
+        let start_ptr = unsafe { mmap.as_ptr().add(header.chunks_offset as usize).cast_mut() };
+        tracing::info!("Got for reading the start_ptr: {:p}, layout: {:?}", start_ptr, header);
         start_ptr
     }
 
-    pub fn read_next_message(&mut self) -> &[u8] {
-        let start_ptr: *mut u8 = self.shareable_ptr.0;
+    pub fn header(&self) -> &FileHeader {
+        &self.header
+    }
+
+    // Reads the next chunk in round-robin order.
+    // Returns None if the chunk has not been written since the last read or stayed torn.
+    pub fn read_next_message(&mut self) -> Option<Chunk<'_>> {
+        let chunk_id = self.current_chunk_id;
+        self.next_chunk();
+        self.read_chunk_at(chunk_id, true)
+    }
 
-        let target_offset = self.current_chunk_id * self.chunk_size;
+    // Reads the latest message of the chunk with the global `chunk_index`, e.g. from `lookup_symbol`.
+    // Returns None if the chunk was never written or stayed torn.
+    pub fn read_chunk(&mut self, chunk_index: usize) -> Option<Chunk<'_>> {
+        if chunk_index >= self.chunk_count {
+            return None;
+        }
+        self.read_chunk_at(chunk_index, false)
+    }
 
-        // Make writes visible for main thread
-        // It is not necessary when using `std::thread::scope` but may be necessary in your case.
-        std::sync::atomic::fence(std::sync::atomic::Ordering::Acquire);
+    fn read_chunk_at(&mut self, chunk_id: usize, skip_unchanged: bool) -> Option<Chunk<'_>> {
+        let start_ptr: *mut u8 = self.shareable_ptr.0;
+        let target_offset = chunk_id * self.chunk_size;
 
         let read_start = self.start_bench();
 
-        unsafe {
-            // SAFETY: We never overlap on writes.
-            // Pointer is living because we use scoped threads.
-            std::ptr::copy_nonoverlapping(
-                start_ptr.wrapping_offset(target_offset as isize),
-                self.read_buffer.as_ptr().cast_mut(),
-                self.chunk_size,
-            );
-        }
+        // SAFETY: Chunks are 8-byte aligned and the pointer is living because we use scoped threads.
+        let chunk_ptr = unsafe { start_ptr.add(target_offset) };
+        let version = unsafe { shm_chunk::version(chunk_ptr) };
+
+        let mut retries = 0;
+        let read_version = loop {
+            if let Some(started) = shm_chunk::begin_read(version) {
+                if started == 0 || (skip_unchanged && started == self.last_versions[chunk_id]) {
+                    return None;
+                }
+                unsafe {
+                    std::ptr::copy_nonoverlapping(
+                        chunk_ptr.add(shm_chunk::VERSION_SIZE),
+                        self.read_buffer.as_mut_ptr().cast::<u8>(),
+                        shm_chunk::payload_size(self.chunk_size),
+                    );
+                }
+                if shm_chunk::end_read(version, started) {
+                    break started;
+                }
+            }
+            self.torn_reads += 1;
+            retries += 1;
+            if retries >= shm_chunk::MAX_READ_RETRIES {
+                tracing::warn!(
+                    "SharedMemoryReader gave up reading chunk_id {} after {} retries, total torn reads: {}",
+                    chunk_id, retries, self.torn_reads
+                );
+                return None;
+            }
+            std::hint::spin_loop();
+        };
+        self.last_versions[chunk_id] = read_version;
 
         if enabled!(Level::TRACE) {
             let read_duration = self.end_bench(read_start);
             tracing::trace!(
-                "SharedMemoryReader read chunk_id {} at offset {} in {} μs",
-                self.current_chunk_id,
+                "SharedMemoryReader read chunk_id {} version {} at offset {} in {} μs",
+                chunk_id,
+                read_version,
                 target_offset,
                 read_duration.as_micros()
             );
         }
 
-        self.next_chunk();
-        &self.read_buffer[..self.chunk_size]
+        // SAFETY: The buffer is 8-byte aligned and holds a consistent copy of a written chunk.
+        unsafe {
+            let payload = std::slice::from_raw_parts(
+                self.read_buffer.as_ptr().cast::<u8>(),
+                shm_chunk::payload_size(self.chunk_size),
+            );
+            Some(Chunk::from_payload(payload))
+        }
+    }
+
+    // Returns the global chunk index of a market from the SHM market directory.
+    // Scans the directory, so resolve once and keep the index for `read_chunk`.
+    pub fn lookup_symbol(&self, symbol: &str) -> Option<usize> {
+        (0..self.chunk_count).find(|&chunk_index| {
+            self.market(chunk_index).is_some_and(|entry| !entry.is_empty() && entry.symbol() == symbol)
+        })
+    }
+
+    // Returns the directory entry of the market written into the chunk with the global `chunk_index`.
+    // Returns None if the index is out of range or the entry stayed torn.
+    pub fn market(&self, chunk_index: usize) -> Option<DirectoryEntry> {
+        if chunk_index >= self.chunk_count {
+            return None;
+        }
+        // SAFETY: The directory region is mapped for `chunk_count` entries.
+        unsafe {
+            let directory_ptr = self.mmap.as_ptr().add(self.header.market_index_offset as usize);
+            shm_directory::read_entry(directory_ptr, chunk_index)
+        }
+    }
+
+    pub fn torn_reads(&self) -> u64 {
+        self.torn_reads
     }
 
     fn next_chunk(&mut self) {
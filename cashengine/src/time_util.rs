@@ -9,4 +9,18 @@ pub fn print_systemtime() {
         },
         Err(err) => tracing::error!("Error getting duration for UNIX epoch: {}", err),
     }
-}
\ No newline at end of file
+}
+
+// Nanoseconds since UNIX epoch, 0 if the clock is set before it
+pub fn epoch_nanos() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_nanos() as u64)
+}
+
+// CLOCK_MONOTONIC in nanoseconds, comparable between threads and processes of the host but not with epoch times.
+// Read through the vDSO, which scales the TSC on x86, so it costs about as much as reading the TSC directly.
+pub fn monotonic_nanos() -> u64 {
+    let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // Only fails for invalid clock ids
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time) };
+    time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64
+}
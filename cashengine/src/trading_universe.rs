@@ -0,0 +1,199 @@
+use crate::htx_currency::{HtxCurrencies, HtxCurrency};
+use crate::htx_market::{HtxMarket, HtxMarkets};
+use crate::htx_symbol::HtxSymbols;
+use crate::instrument::Instrument;
+use std::collections::HashMap;
+use std::fmt;
+
+// The HTX instruments to subscribe, built by cross-referencing the filtered symbols with the currencies
+// and markets endpoints. Symbols whose currencies or market are not available are dropped,
+// the remaining ones get the order limits of their market.
+pub struct TradingUniverse {
+    instruments: Vec<Instrument>,
+    issues: Vec<UniverseIssue>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum UniverseIssue {
+    // Symbol without name, dropped
+    MissingSymbolName,
+    // Base or quote currency is not in the filtered currencies, the symbol is dropped
+    CurrencyUnavailable { symbol: String, currency: String },
+    // No filtered market for the symbol, the symbol is dropped
+    MarketUnavailable { symbol: String },
+    // Symbol and market disagree, the symbol's value is kept
+    Mismatch { symbol: String, field: &'static str, symbol_value: String, market_value: String },
+}
+
+// Changes between two universes, by symbol
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UniverseDiff {
+    pub added: Vec<Instrument>,
+    pub removed: Vec<Instrument>,
+    // Previous and current instrument, e.g. after a state change or new order limits
+    pub changed: Vec<(Instrument, Instrument)>,
+}
+
+pub(crate) struct TradingUniverseBuilder<'a> {
+    symbols: &'a HtxSymbols,
+    currencies: Option<&'a HtxCurrencies>,
+    markets: Option<&'a HtxMarkets>,
+}
+
+impl TradingUniverse {
+    pub(crate) fn builder(symbols: &HtxSymbols) -> TradingUniverseBuilder<'_> {
+        TradingUniverseBuilder {
+            symbols,
+            currencies: None,
+            markets: None,
+        }
+    }
+
+    // In the order of the symbols endpoint
+    pub fn instruments(&self) -> &[Instrument] {
+        &self.instruments
+    }
+
+    pub fn issues(&self) -> &[UniverseIssue] {
+        &self.issues
+    }
+
+    pub fn len(&self) -> usize {
+        self.instruments.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instruments.is_empty()
+    }
+
+    // What changed from `self` to the `current` universe
+    pub fn diff(&self, current: &TradingUniverse) -> UniverseDiff {
+        let previous: HashMap<&str, &Instrument> = self.instruments.iter().map(|i| (i.symbol.as_str(), i)).collect();
+        let now: HashMap<&str, &Instrument> = current.instruments.iter().map(|i| (i.symbol.as_str(), i)).collect();
+        let mut diff = UniverseDiff::default();
+        for instrument in &current.instruments {
+            match previous.get(instrument.symbol.as_str()) {
+                None => diff.added.push(instrument.clone()),
+                Some(&before) if before != instrument => diff.changed.push((before.clone(), instrument.clone())),
+                Some(_) => {}
+            }
+        }
+        diff.removed = self.instruments.iter().filter(|i| !now.contains_key(i.symbol.as_str())).cloned().collect();
+        diff
+    }
+
+    // Drops the instruments not matching `keep`, e.g. markets that couldn't be subscribed
+    pub fn retain<F: FnMut(&Instrument) -> bool>(&mut self, keep: F) {
+        self.instruments.retain(keep);
+    }
+
+    pub fn log_issues(&self) {
+        for issue in &self.issues {
+            tracing::warn!("Trading universe: {}", issue);
+        }
+        tracing::info!("Trading universe contains {} instruments, {} issues", self.instruments.len(), self.issues.len());
+    }
+}
+
+impl<'a> TradingUniverseBuilder<'a> {
+    // Only symbols with both currencies in `currencies` are kept
+    pub(crate) fn currencies(mut self, currencies: &'a HtxCurrencies) -> Self {
+        self.currencies = Some(currencies);
+        self
+    }
+
+    // Only symbols with a market in `markets` are kept, enriched with its order limits
+    pub(crate) fn markets(mut self, markets: &'a HtxMarkets) -> Self {
+        self.markets = Some(markets);
+        self
+    }
+
+    pub(crate) fn build(self) -> TradingUniverse {
+        let currencies: Option<HashMap<&str, &HtxCurrency>> = self.currencies.map(|currencies| {
+            currencies.data.iter()
+                .filter_map(|currency| currency.currency_code.as_deref().map(|code| (code, currency)))
+                .collect()
+        });
+        let markets: Option<HashMap<&str, &HtxMarket>> = self.markets.map(|markets| {
+            markets.data.iter()
+                .filter_map(|market| market.symbol.as_deref().map(|symbol| (symbol, market)))
+                .collect()
+        });
+
+        let mut instruments = Vec::with_capacity(self.symbols.len());
+        let mut issues = Vec::new();
+        'symbols: for symbol in self.symbols.get_symbols() {
+            let Some(name) = symbol.symbol.as_deref() else {
+                issues.push(UniverseIssue::MissingSymbolName);
+                continue;
+            };
+            let mut instrument = Instrument::from(symbol);
+
+            if let Some(currencies) = &currencies {
+                for currency in [&instrument.base, &instrument.quote] {
+                    if !currencies.contains_key(currency.as_str()) {
+                        issues.push(UniverseIssue::CurrencyUnavailable { symbol: name.to_string(), currency: currency.clone() });
+                        continue 'symbols;
+                    }
+                }
+            }
+
+            if let Some(markets) = &markets {
+                let Some(market) = markets.get(name) else {
+                    issues.push(UniverseIssue::MarketUnavailable { symbol: name.to_string() });
+                    continue;
+                };
+                let market = Instrument::from(*market);
+                check(&mut issues, name, "base currency", non_empty(&instrument.base), non_empty(&market.base));
+                check(&mut issues, name, "quote currency", non_empty(&instrument.quote), non_empty(&market.quote));
+                check(&mut issues, name, "tick size", instrument.tick_size, market.tick_size);
+                check(&mut issues, name, "lot size", instrument.lot_size, market.lot_size);
+                instrument.tick_size = instrument.tick_size.or(market.tick_size);
+                instrument.lot_size = instrument.lot_size.or(market.lot_size);
+                instrument.min_notional = market.min_notional;
+                instrument.min_order_amount = market.min_order_amount;
+                instrument.max_order_amount = market.max_order_amount;
+                instrument.max_order_value = market.max_order_value;
+            }
+            instruments.push(instrument);
+        }
+        TradingUniverse { instruments, issues }
+    }
+}
+
+// Values missing on either side are not reported
+fn check<T: PartialEq + fmt::Display>(issues: &mut Vec<UniverseIssue>, symbol: &str, field: &'static str, symbol_value: Option<T>, market_value: Option<T>) {
+    if let (Some(symbol_value), Some(market_value)) = (symbol_value, market_value) {
+        if symbol_value != market_value {
+            issues.push(UniverseIssue::Mismatch {
+                symbol: symbol.to_string(),
+                field,
+                symbol_value: symbol_value.to_string(),
+                market_value: market_value.to_string(),
+            });
+        }
+    }
+}
+
+fn non_empty(value: &str) -> Option<&str> {
+    Some(value).filter(|value| !value.is_empty())
+}
+
+impl UniverseDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl fmt::Display for UniverseIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UniverseIssue::MissingSymbolName => write!(f, "dropped symbol without name"),
+            UniverseIssue::CurrencyUnavailable { symbol, currency } =>
+                write!(f, "dropped {}, currency {} is not available", symbol, currency),
+            UniverseIssue::MarketUnavailable { symbol } => write!(f, "dropped {}, market is not available", symbol),
+            UniverseIssue::Mismatch { symbol, field, symbol_value, market_value } =>
+                write!(f, "{} of {} is {} in symbols but {} in markets", field, symbol, symbol_value, market_value),
+        }
+    }
+}
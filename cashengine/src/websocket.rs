@@ -1,22 +1,153 @@
 use crate::compression;
+use crate::config::ReconnectConfig;
+use crate::feed_source::MessageTimes;
+use crate::metrics::ConnectionMetrics;
+use crate::time_util::{epoch_nanos, monotonic_nanos};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
 use std::net::TcpStream;
+use std::os::fd::{AsRawFd, RawFd};
 use std::str;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tungstenite::stream::MaybeTlsStream;
 use tungstenite::{Message, WebSocket};
 // non-blocking: https://github.com/haxpor/bybit-shiprekt/blob/6c3c5693d675fc997ce5e76df27e571f2aaaf291/src/main.rs
 
-pub const CHUNK_SIZE: usize = 320;
+#[derive(Clone, Copy, Debug)]
+pub struct ReconnectPolicy {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub multiplier: u32,
+    // Jitter in percent of the current backoff, applied in both directions
+    pub jitter_percent: u32,
+    // A session that stayed connected at least this long resets the backoff
+    pub stable_after: Duration,
+}
+
+impl ReconnectPolicy {
+    pub fn new(config: &ReconnectConfig) -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_millis(config.initial_backoff_ms),
+            max_backoff: Duration::from_millis(config.max_backoff_ms),
+            multiplier: config.multiplier,
+            jitter_percent: config.jitter_percent,
+            stable_after: Duration::from_secs(config.stable_after_secs),
+        }
+    }
+
+    fn next_backoff(&self, backoff: Duration) -> Duration {
+        let next = backoff.saturating_mul(self.multiplier.max(1));
+        if next > self.max_backoff {
+            self.max_backoff
+        } else {
+            next
+        }
+    }
+
+    fn with_jitter(&self, backoff: Duration, seed: &mut u64) -> Duration {
+        let range_millis = backoff.as_millis() as u64 * self.jitter_percent as u64 / 100;
+        if range_millis == 0 {
+            return backoff;
+        }
+        // xorshift64, good enough to spread reconnects of the feed threads
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 7;
+        *seed ^= *seed << 17;
+        let offset_millis = *seed % (2 * range_millis + 1);
+        (backoff + Duration::from_millis(offset_millis)).saturating_sub(Duration::from_millis(range_millis))
+    }
+}
+
+#[derive(Debug)]
+pub enum DisconnectReason {
+    ClosedByServer(Option<String>),
+    ReadError(Box<tungstenite::Error>),
+    UnexpectedMessage,
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisconnectReason::ClosedByServer(Some(reason)) => write!(f, "closed by server with reason: {}", reason),
+            DisconnectReason::ClosedByServer(None) => write!(f, "closed by server without reason"),
+            DisconnectReason::ReadError(e) => write!(f, "read error: {}", e),
+            DisconnectReason::UnexpectedMessage => write!(f, "unexpected message from server"),
+        }
+    }
+}
+
+// Result of reading one frame
+enum Frame {
+    // Size of the inflated message in the buffer
+    Message(usize, MessageTimes),
+    // Frames without a message, such as ping frames
+    Skipped,
+    // Non-blocking mode only: no complete frame is buffered
+    WouldBlock,
+}
+
+// Reconnect of a non-blocking websocket, attempted by `try_next_message` once due
+struct PendingReconnect {
+    reason: String,
+    disconnected_at: Instant,
+    attempt: u64,
+    due: Instant,
+}
 
 pub struct CeWebSocket {
-    buffer: [u8; CHUNK_SIZE],
+    buffer: Vec<u8>,
     socket: WebSocket<MaybeTlsStream<TcpStream>>,
-    max_size: usize,
+    // Set by `set_nonblocking`, kept across reconnects
+    nonblocking: bool,
+    pending_reconnect: Option<PendingReconnect>,
+    url: String,
+    // Replayed after every reconnect
+    subscribe_requests: Vec<String>,
+    // Requests are sent `request_interval` apart, the next pending one at `next_request_due`
+    pending_requests: VecDeque<String>,
+    next_request_due: Instant,
+    request_interval: Duration,
+    policy: ReconnectPolicy,
+    backoff: Duration,
+    // Jitter state
+    seed: u64,
+    session_start: Instant,
+    // Shared with the metrics exporter
+    metrics: Arc<ConnectionMetrics>,
 }
 
 impl CeWebSocket {
-    pub fn connect(url: &str) -> Result<CeWebSocket, tungstenite::Error> {
-        let result = tungstenite::connect(url);
-        match result {
+    pub fn connect(url: &str, buffer_size: usize, policy: ReconnectPolicy) -> Result<CeWebSocket, Box<tungstenite::Error>> {
+        let socket = CeWebSocket::open_socket(url)?;
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0x2545_F491_4F6C_DD1D)
+            | 1;
+        let metrics = Arc::new(ConnectionMetrics::default());
+        metrics.connected.set(1);
+        Ok(CeWebSocket {
+            buffer: vec![0; buffer_size],
+            socket,
+            nonblocking: false,
+            pending_reconnect: None,
+            url: url.to_string(),
+            subscribe_requests: Vec::new(),
+            pending_requests: VecDeque::new(),
+            next_request_due: Instant::now(),
+            request_interval: Duration::ZERO,
+            backoff: policy.initial_backoff,
+            policy,
+            seed,
+            session_start: Instant::now(),
+            metrics,
+        })
+    }
+
+    fn open_socket(url: &str) -> Result<WebSocket<MaybeTlsStream<TcpStream>>, Box<tungstenite::Error>> {
+        match tungstenite::connect(url) {
             Ok((sock, response)) => {
                 tracing::trace!("Connected to the server");
                 tracing::trace!("Response HTTP code: {}", response.status());
@@ -24,99 +155,330 @@ impl CeWebSocket {
                 for (header, _value) in response.headers() {
                     tracing::trace!("* {header}");
                 }
-
-                Ok(CeWebSocket {
-                    buffer: [0; CHUNK_SIZE],
-                    socket: sock,
-                    max_size: 0,
-                })
+                Ok(sock)
             },
-            Err(e) => Err(e)
+            Err(e) => Err(Box::new(e))
         }
     }
 
+    // Sent now and replayed after every reconnect
     pub fn subscribe(&mut self, request: &str) {
-        self.send_message(request);
+        self.subscribe_all(vec![request.to_string()], Duration::ZERO);
     }
 
-    pub fn run<F>(&mut self, mut on_message: F)
-    where
-        F: FnMut(&[u8]),
-    {
+    // Sends the requests `interval` apart, for exchanges limiting the messages per second of a connection.
+    // Only the first is sent now, the others by the next reads once due.
+    pub fn subscribe_all(&mut self, requests: Vec<String>, interval: Duration) {
+        self.subscribe_requests = requests;
+        self.request_interval = interval;
+        self.send_subscribe_requests();
+    }
+
+    // Queues the requests behind the pending ones, paced like the subscribe requests
+    pub fn send_paced(&mut self, requests: Vec<String>) {
+        // A reconnect replays the subscribe requests on a new connection instead
+        if self.pending_reconnect.is_some() {
+            return;
+        }
+        self.pending_requests.extend(requests);
+        self.send_due_requests();
+    }
+
+    // Replaces the requests replayed after reconnects without sending them now
+    pub fn set_subscribe_requests(&mut self, requests: Vec<String>) {
+        self.subscribe_requests = requests;
+    }
+
+    fn send_subscribe_requests(&mut self) {
+        self.pending_requests = self.subscribe_requests.iter().cloned().collect();
+        self.send_due_requests();
+    }
+
+    fn send_due_requests(&mut self) {
+        while self.next_request_due <= Instant::now() {
+            let Some(request) = self.pending_requests.pop_front() else {
+                return;
+            };
+            self.send_message(&request);
+            self.next_request_due = Instant::now() + self.request_interval;
+        }
+    }
+
+    // When the next pending request is due, None if there is none
+    pub fn requests_due(&self) -> Option<Instant> {
+        if self.pending_requests.is_empty() {
+            None
+        } else {
+            Some(self.next_request_due)
+        }
+    }
+
+    // Switches to non-blocking reads with `try_next_message`, for an event loop waiting on `raw_fd`.
+    // Reconnect handshakes still block the calling thread.
+    pub fn set_nonblocking(&mut self) -> io::Result<()> {
+        self.nonblocking = true;
+        tcp_stream(&self.socket).set_nonblocking(true)
+    }
+
+    // Changes after reconnects
+    pub fn raw_fd(&self) -> RawFd {
+        tcp_stream(&self.socket).as_raw_fd()
+    }
+
+    // Blocks until the next inflated message, answering pings on the way. On disconnect the connection is
+    // re-established with exponential backoff and jitter and the subscribe request is replayed.
+    pub fn next_message(&mut self) -> (&[u8], MessageTimes) {
         loop {
-            let msg = match self.socket.read() {
-                Ok(msg) => msg,
-                Err(e) => {
-                    panic!("Error reading message from websocket server: {}", e)
+            // The thread owns the connection, it can sleep until the pending requests are sent
+            while let Some(due) = self.requests_due() {
+                std::thread::sleep(due.saturating_duration_since(Instant::now()));
+                self.send_due_requests();
+            }
+            match self.read_message() {
+                Ok(Frame::Message(size, times)) => {
+                    self.metrics.messages.increment();
+                    return (&self.buffer[..size], times);
                 }
-            };
-            match msg {
-                Message::Text(message) => {
-                    tracing::trace!("Received text message from websocket server: {}", message);
-                },
-                Message::Binary(bytes) => {
-                    let vec = bytes.as_ref().to_vec();
-                    unsafe {
-                        match compression::gz_inflate_to_buffer(&vec, &mut self.buffer) {
-                            Ok(size) => {
-                                if size >= 6 && self.buffer.get_unchecked(..6) == b"{\"ping" {
-                                    let message = str::from_utf8_unchecked(&self.buffer[..size]);
-                                    let message = message.to_string();
-                                    //tracing::trace!("Received ping from websocket server: {}", message);
-                                    self.send_pong(&message);
-                                } else {
-                                    on_message( &self.buffer[..size]);
-                                }
-                                if size > self.max_size {
-                                    self.max_size = size;
-                                }
-                                //tracing::trace!("Max size in bytes: {}", self.max_size);
-                            }
-                            Err(e) => tracing::error!("Failed to inflate message from websocket server: {:?}: {:?}", e, String::from_utf8_lossy(&vec)),
+                Ok(Frame::Skipped | Frame::WouldBlock) => {}
+                Err(reason) => self.reconnect(reason),
+            }
+        }
+    }
+
+    // Non-blocking mode: the next buffered message, None once the socket would block. After a disconnect it
+    // returns None until the reconnect is due, see `reconnect_due`, then makes one attempt per call.
+    // Pending requests are sent once due, see `requests_due`.
+    pub fn try_next_message(&mut self) -> Option<(&[u8], MessageTimes)> {
+        if let Some(pending) = self.pending_reconnect.take() {
+            if Instant::now() < pending.due {
+                self.pending_reconnect = Some(pending);
+                return None;
+            }
+            self.attempt_reconnect(pending);
+            return None;
+        }
+        self.send_due_requests();
+        loop {
+            match self.read_message() {
+                Ok(Frame::Message(size, times)) => {
+                    self.metrics.messages.increment();
+                    return Some((&self.buffer[..size], times));
+                }
+                Ok(Frame::Skipped) => {}
+                Ok(Frame::WouldBlock) => return None,
+                Err(reason) => {
+                    let disconnected_at = self.on_disconnect(&reason);
+                    let due = Instant::now() + self.next_delay();
+                    self.pending_reconnect = Some(PendingReconnect { reason: reason.to_string(), disconnected_at, attempt: 1, due });
+                    return None;
+                }
+            }
+        }
+    }
+
+    // When the next reconnect attempt is due, None while connected
+    pub fn reconnect_due(&self) -> Option<Instant> {
+        self.pending_reconnect.as_ref().map(|pending| pending.due)
+    }
+
+    pub fn reconnects(&self) -> u64 {
+        self.metrics.reconnects.get()
+    }
+
+    pub fn messages(&self) -> u64 {
+        self.metrics.messages.get()
+    }
+
+    // Largest inflated message so far
+    pub fn max_size(&self) -> usize {
+        self.metrics.max_message_size.get() as usize
+    }
+
+    pub fn metrics(&self) -> &Arc<ConnectionMetrics> {
+        &self.metrics
+    }
+
+    fn reconnect(&mut self, reason: DisconnectReason) {
+        let disconnected_at = self.on_disconnect(&reason);
+        let mut attempt: u64 = 0;
+        loop {
+            attempt += 1;
+            let delay = self.next_delay();
+            tracing::info!("Reconnecting websocket {} in {} ms (attempt {})", self.url, delay.as_millis(), attempt);
+            std::thread::sleep(delay);
+            if self.open(attempt) {
+                break;
+            }
+        }
+        self.on_reconnect(attempt, disconnected_at, &reason.to_string());
+    }
+
+    fn attempt_reconnect(&mut self, pending: PendingReconnect) {
+        tracing::info!("Reconnecting websocket {} (attempt {})", self.url, pending.attempt);
+        if self.open(pending.attempt) {
+            self.on_reconnect(pending.attempt, pending.disconnected_at, &pending.reason);
+        } else {
+            let due = Instant::now() + self.next_delay();
+            self.pending_reconnect = Some(PendingReconnect { attempt: pending.attempt + 1, due, ..pending });
+        }
+    }
+
+    fn on_disconnect(&mut self, reason: &DisconnectReason) -> Instant {
+        let disconnected_at = Instant::now();
+        if disconnected_at.duration_since(self.session_start) >= self.policy.stable_after {
+            self.backoff = self.policy.initial_backoff;
+        }
+        self.metrics.connected.set(0);
+        // Replaced by the subscribe requests after the reconnect
+        self.pending_requests.clear();
+        tracing::warn!("Websocket {} disconnected: {}", self.url, reason);
+        disconnected_at
+    }
+
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.policy.with_jitter(self.backoff, &mut self.seed);
+        self.backoff = self.policy.next_backoff(self.backoff);
+        delay
+    }
+
+    // Replaces the socket, false if the attempt failed
+    fn open(&mut self, attempt: u64) -> bool {
+        let socket = match CeWebSocket::open_socket(&self.url) {
+            Ok(socket) => socket,
+            Err(e) => {
+                tracing::error!("Failed reconnecting websocket {} (attempt {}): {}", self.url, attempt, e);
+                return false;
+            }
+        };
+        self.socket = socket;
+        true
+    }
+
+    fn on_reconnect(&mut self, attempt: u64, disconnected_at: Instant, reason: &str) {
+        // The first request is sent while blocking, so it is not split by a full send buffer,
+        // the others once due
+        self.send_subscribe_requests();
+        if self.nonblocking {
+            if let Err(e) = tcp_stream(&self.socket).set_nonblocking(true) {
+                tracing::error!("Failed making websocket {} non-blocking: {}", self.url, e);
+            }
+        }
+        self.session_start = Instant::now();
+        self.metrics.reconnects.increment();
+        self.metrics.connected.set(1);
+        tracing::warn!(
+            "Reconnected websocket {} after {} attempts, downtime {} ms, reason: {}, total reconnects: {}",
+            self.url,
+            attempt,
+            disconnected_at.elapsed().as_millis(),
+            reason,
+            self.metrics.reconnects.get()
+        );
+    }
+
+    // Reads one frame into the buffer.
+    // Binary frames are gzip compressed (HTX), text frames are copied as they are (Binance).
+    // The receive time is taken when tungstenite returns the frame, for frames that arrived in one
+    // TCP read that is after parsing the frames before it.
+    fn read_message(&mut self) -> Result<Frame, DisconnectReason> {
+        let msg = match self.socket.read() {
+            Ok(msg) => msg,
+            Err(tungstenite::Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Frame::WouldBlock),
+            Err(e) => {
+                tracing::error!("Error reading message from websocket server: {}", e);
+                return Err(DisconnectReason::ReadError(Box::new(e)));
+            }
+        };
+        let mut times = MessageTimes { received_epoch_nanos: epoch_nanos(), received_nanos: monotonic_nanos(), ..MessageTimes::default() };
+        match msg {
+            Message::Text(message) => {
+                let size = message.len();
+                if size > self.buffer.len() {
+                    tracing::error!("Text message of {} bytes exceeds the buffer of {} bytes: {}", size, self.buffer.len(), message.as_str());
+                    return Ok(Frame::Skipped);
+                }
+                self.buffer[..size].copy_from_slice(message.as_bytes());
+                self.update_max_size(size);
+                times.inflated_nanos = monotonic_nanos();
+                Ok(Frame::Message(size, times))
+            },
+            // Pongs to ping frames are queued by tungstenite and sent with the next read or write
+            Message::Ping(_) => {
+                self.metrics.pings.increment();
+                self.metrics.pongs.increment();
+                Ok(Frame::Skipped)
+            }
+            Message::Pong(_) => Ok(Frame::Skipped),
+            Message::Binary(bytes) => {
+                match compression::gz_inflate_to_buffer(bytes.as_ref(), &mut self.buffer) {
+                    Ok(size) => {
+                        times.inflated_nanos = monotonic_nanos();
+                        self.update_max_size(size);
+                        if size >= 6 && &self.buffer[..6] == b"{\"ping" {
+                            self.metrics.pings.increment();
+                            // SAFETY: HTX pings are ASCII JSON
+                            let message = unsafe { str::from_utf8_unchecked(&self.buffer[..size]) }.to_string();
+                            self.send_pong(&message);
                         }
+                        // Answered pings are returned too, they wake up feeds without subscribed markets
+                        Ok(Frame::Message(size, times))
                     }
-                },
-                Message::Close(close_frame) => {
-                    match close_frame {
-                        Some(reason) => {
-                            tracing::info!("Connection closed by server with reason: {}", reason);
-                            match self.socket.close(None) {
-                                Ok(()) => tracing::info!("Closed connection to server"),
-                                Err(e) => tracing::error!("Failed to close connection to sever: {}", e),
-                            }
-                        },
-                        None => {
-                            tracing::info!("Connection closed by server without reason");
-                            match self.socket.close(None) {
-                                Ok(()) => tracing::info!("Closed connection to server"),
-                                Err(e) => tracing::error!("Failed to close connection to server: {}", e),
-                            }
-                        },
+                    Err(e) => {
+                        self.metrics.inflate_errors.increment();
+                        tracing::error!("Failed to inflate message from websocket server: {:?}: {:?}", e, String::from_utf8_lossy(bytes.as_ref()));
+                        Ok(Frame::Skipped)
                     }
-                    break;
-                },
-                _ => {
-                    tracing::error!("Received unknown message from server");
-                    break;
                 }
+            },
+            Message::Close(close_frame) => {
+                let reason = match close_frame {
+                    Some(reason) => {
+                        tracing::info!("Connection closed by server with reason: {}", reason);
+                        Some(reason.to_string())
+                    },
+                    None => {
+                        tracing::info!("Connection closed by server without reason");
+                        None
+                    },
+                };
+                match self.socket.close(None) {
+                    Ok(()) => tracing::info!("Closed connection to server"),
+                    Err(e) => tracing::error!("Failed to close connection to server: {}", e),
+                }
+                Err(DisconnectReason::ClosedByServer(reason))
+            },
+            _ => {
+                tracing::error!("Received unknown message from server");
+                Err(DisconnectReason::UnexpectedMessage)
             }
         }
     }
 
+    fn update_max_size(&self, size: usize) {
+        if size > self.max_size() {
+            self.metrics.max_message_size.set(size as u64);
+        }
+    }
+
     fn send_pong(&mut self, s: &str) {
         let mut pong = String::with_capacity(s.len());
         pong.push_str(&s[..3]);
         pong.push('o');
         pong.push_str(&s[4..]);
         self.send_message(pong.as_str());
+        self.metrics.pongs.increment();
     }
 
-    fn send_message(&mut self, s: &str) {
+    pub fn send_message(&mut self, s: &str) {
         let msg = Message::text(s);
         match self.socket.send(msg) {
             Ok(()) => {
                 tracing::trace!("Sent message to websocket server: {}", String::from(s));
             },
+            // Non-blocking mode: the frame stays queued and is flushed by the next read or write
+            Err(tungstenite::Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => {
+                tracing::trace!("Queued message to websocket server: {}", String::from(s));
+            },
             Err(e) => {
                 tracing::error!("Error sending message to websocket server: {}", e);
             }
@@ -124,10 +486,19 @@ impl CeWebSocket {
     }
 }
 
+fn tcp_stream(socket: &WebSocket<MaybeTlsStream<TcpStream>>) -> &TcpStream {
+    match socket.get_ref() {
+        MaybeTlsStream::Plain(stream) => stream,
+        MaybeTlsStream::NativeTls(stream) => stream.get_ref(),
+        // Only the enabled TLS backend exists
+        _ => unreachable!("unsupported websocket stream"),
+    }
+}
+
 impl Drop for CeWebSocket {
     fn drop(&mut self) {
         if let Err(e) = self.socket.close(None) {
             tracing::error!("Failed to close connection to websocket server: {}", e);
         }
     }
-}
\ No newline at end of file
+}
@@ -1,5 +1,53 @@
-use cashengine;
+use cashengine::config::EngineConfig;
+use cashengine::error::EngineError;
+
+// Exit codes for the process supervisor, so it can tell exchange problems from local ones
+const EXIT_CONFIG: i32 = 2;
+const EXIT_REST: i32 = 10;
+const EXIT_PARSE: i32 = 11;
+const EXIT_EXCHANGE: i32 = 12;
+const EXIT_SHM: i32 = 13;
+const EXIT_AFFINITY: i32 = 14;
+const EXIT_WEBSOCKET: i32 = 15;
+const EXIT_RECORDING: i32 = 16;
+const EXIT_REPLAY: i32 = 17;
+const EXIT_EVENT_LOOP: i32 = 18;
+const EXIT_METRICS: i32 = 19;
+const EXIT_PANIC: i32 = 20;
 
 fn main() {
-    cashengine::run();
+    // Config file from the first argument or CASHENGINE_CONFIG, otherwise built-in defaults.
+    // Any value can be overridden with a CASHENGINE_* environment variable.
+    let config_path = std::env::args()
+        .nth(1)
+        .or_else(|| std::env::var("CASHENGINE_CONFIG").ok());
+    let config = match config_path {
+        Some(path) => EngineConfig::load(path),
+        None => EngineConfig::from_env(),
+    };
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(EXIT_CONFIG);
+        }
+    };
+
+    if let Err(e) = cashengine::try_run(&config) {
+        eprintln!("{e}");
+        let exit_code = match e {
+            EngineError::Rest { .. } => EXIT_REST,
+            EngineError::Parse { .. } => EXIT_PARSE,
+            EngineError::Exchange { .. } => EXIT_EXCHANGE,
+            EngineError::Shm { .. } => EXIT_SHM,
+            EngineError::Affinity(_) => EXIT_AFFINITY,
+            EngineError::WebSocket { .. } => EXIT_WEBSOCKET,
+            EngineError::Recording { .. } => EXIT_RECORDING,
+            EngineError::Replay { .. } => EXIT_REPLAY,
+            EngineError::EventLoop(_) => EXIT_EVENT_LOOP,
+            EngineError::Metrics { .. } => EXIT_METRICS,
+            EngineError::Panic { .. } => EXIT_PANIC,
+        };
+        std::process::exit(exit_code);
+    }
 }